@@ -19,6 +19,7 @@ const N_ELEMENTS_TO_TEST: &[u32] = &[1_000, 10_000, 100_000, 1_000_000];
 
 const WORLD_SIZE: f32 = 10.0;
 const LOOKUP_RADIUS: f32 = 1.0;
+const K_NEAREST: usize = 10;
 
 //## Helper functions
 
@@ -69,7 +70,22 @@ fn world_with_bvh(n: u32) -> (World, Schedule, Schedule) {
         algorithms::Bvh::default(),
     ));
 
-    prepare_schedule.add_systems(prepare_spatial_lookup);
+    prepare_schedule.add_systems(prepare_spatial_lookup::<(), ()>);
+    query_schedule.add_systems(system_with_spatial_query);
+
+    (world, prepare_schedule, query_schedule)
+}
+
+fn world_with_lbvh(n: u32) -> (World, Schedule, Schedule) {
+    let mut world = world_with_n_entities(n);
+    let mut prepare_schedule = Schedule::default();
+    let mut query_schedule = Schedule::default();
+
+    let mut bvh = algorithms::Bvh::default();
+    bvh.build_strategy = algorithms::BvhBuildStrategy::Lbvh;
+    world.insert_resource(SpatialLookupState::with_algorithm(bvh));
+
+    prepare_schedule.add_systems(prepare_spatial_lookup::<(), ()>);
     query_schedule.add_systems(system_with_spatial_query);
 
     (world, prepare_schedule, query_schedule)
@@ -84,7 +100,22 @@ fn world_with_naive(n: u32) -> (World, Schedule, Schedule) {
         algorithms::Naive::default(),
     ));
 
-    prepare_schedule.add_systems(prepare_spatial_lookup);
+    prepare_schedule.add_systems(prepare_spatial_lookup::<(), ()>);
+    query_schedule.add_systems(system_with_spatial_query);
+
+    (world, prepare_schedule, query_schedule)
+}
+
+fn world_with_hash_grid(n: u32) -> (World, Schedule, Schedule) {
+    let mut world = world_with_n_entities(n);
+    let mut prepare_schedule = Schedule::default();
+    let mut query_schedule = Schedule::default();
+
+    world.insert_resource(SpatialLookupState::with_algorithm(
+        algorithms::HashGrid::with_cell_size(LOOKUP_RADIUS),
+    ));
+
+    prepare_schedule.add_systems(prepare_spatial_lookup::<(), ()>);
     query_schedule.add_systems(system_with_spatial_query);
 
     (world, prepare_schedule, query_schedule)
@@ -110,6 +141,19 @@ fn test_in_range_without_bevy<A: SpatialLookupAlgorithm + Send + Sync + 'static>
     found.len()
 }
 
+fn k_nearest_100_times_without_bevy(lookup_state: &mut SpatialLookupState) -> usize {
+    lookup_state.prepare_algorithm();
+
+    (0..100)
+        .map(|i| {
+            let sample_point = Vec3::splat((i as f32 / 50. - 1.) * WORLD_SIZE);
+            lookup_state
+                .k_nearest(sample_point, K_NEAREST, f32::INFINITY)
+                .len()
+        })
+        .sum()
+}
+
 //## Benchmarks
 
 fn benchmark_prepare_with_bvh(c: &mut Criterion) {
@@ -131,6 +175,25 @@ fn benchmark_prepare_with_bvh(c: &mut Criterion) {
     }
 }
 
+fn benchmark_prepare_with_lbvh(c: &mut Criterion) {
+    let plot_config = PlotConfiguration::default().summary_scale(AxisScale::Logarithmic);
+    let mut group = c.benchmark_group("LBVH Prepare");
+    group.sample_size(100);
+    group.plot_config(plot_config);
+    group.sampling_mode(SamplingMode::Flat);
+
+    for n in N_ELEMENTS_TO_TEST {
+        group.throughput(Throughput::Elements(*n as u64));
+        group.bench_function(BenchmarkId::from_parameter(*n), |b| {
+            b.iter_batched_ref(
+                || world_with_lbvh(*n),
+                |(world, prepare_schedule, _)| prepare_schedule.run(world),
+                BatchSize::LargeInput,
+            );
+        });
+    }
+}
+
 fn benchmark_query_with_bvh(c: &mut Criterion) {
     let plot_config = PlotConfiguration::default().summary_scale(AxisScale::Logarithmic);
     let mut group = c.benchmark_group("BVH Query");
@@ -196,6 +259,49 @@ fn benchmark_query_with_naive(c: &mut Criterion) {
     }
 }
 
+fn benchmark_prepare_with_hash_grid(c: &mut Criterion) {
+    let plot_config = PlotConfiguration::default().summary_scale(AxisScale::Logarithmic);
+    let mut group = c.benchmark_group("HashGrid Prepare");
+    group.sample_size(100);
+    group.plot_config(plot_config);
+    group.sampling_mode(SamplingMode::Flat);
+
+    for n in N_ELEMENTS_TO_TEST {
+        group.throughput(Throughput::Elements(*n as u64));
+        group.bench_function(BenchmarkId::from_parameter(*n), |b| {
+            b.iter_batched_ref(
+                || world_with_hash_grid(*n),
+                |(world, prepare_schedule, _)| prepare_schedule.run(world),
+                BatchSize::LargeInput,
+            );
+        });
+    }
+}
+
+fn benchmark_query_with_hash_grid(c: &mut Criterion) {
+    let plot_config = PlotConfiguration::default().summary_scale(AxisScale::Logarithmic);
+    let mut group = c.benchmark_group("HashGrid Query");
+    group.sample_size(100);
+    group.plot_config(plot_config);
+
+    for n in N_ELEMENTS_TO_TEST {
+        group.throughput(Throughput::Elements(*n as u64));
+        group.bench_function(BenchmarkId::from_parameter(*n), |b| {
+            b.iter_batched_ref(
+                || {
+                    let (mut world, mut prepare_schedule, query_schedule) =
+                        world_with_hash_grid(*n);
+                    prepare_schedule.run(&mut world);
+
+                    (world, prepare_schedule, query_schedule)
+                },
+                |(world, _, query_schedule)| query_schedule.run(world),
+                BatchSize::LargeInput,
+            );
+        });
+    }
+}
+
 fn compare_bvh_to_naive(c: &mut Criterion) {
     let plot_config = PlotConfiguration::default().summary_scale(AxisScale::Logarithmic);
 
@@ -231,6 +337,14 @@ fn compare_bvh_to_naive(c: &mut Criterion) {
                 BatchSize::LargeInput,
             );
         });
+
+        group.bench_function(BenchmarkId::new("HashGrid", *n), |b| {
+            b.iter_batched_ref(
+                || world_with_hash_grid(*n),
+                prepare_and_call_100_times,
+                BatchSize::LargeInput,
+            );
+        });
     }
 }
 
@@ -272,14 +386,81 @@ fn benchmark_naive_without_bevy(c: &mut Criterion) {
     }
 }
 
+fn benchmark_hash_grid_without_bevy(c: &mut Criterion) {
+    let plot_config = PlotConfiguration::default().summary_scale(AxisScale::Logarithmic);
+    let mut group = c.benchmark_group("HashGrid Plain");
+    group.sample_size(100);
+    group.plot_config(plot_config);
+    group.sampling_mode(SamplingMode::Flat);
+
+    for n in N_ELEMENTS_TO_TEST {
+        group.throughput(Throughput::Elements(*n as u64));
+        group.bench_function(BenchmarkId::from_parameter(*n), |b| {
+            b.iter_batched(
+                || entities_and_positions(*n),
+                |entities| {
+                    test_in_range_without_bevy(
+                        algorithms::HashGrid::with_cell_size(LOOKUP_RADIUS),
+                        entities,
+                    )
+                },
+                BatchSize::LargeInput,
+            );
+        });
+    }
+}
+
+fn compare_k_nearest(c: &mut Criterion) {
+    let plot_config = PlotConfiguration::default().summary_scale(AxisScale::Logarithmic);
+    let mut group = c.benchmark_group("compare_k_nearest");
+    group.sample_size(100);
+    group.plot_config(plot_config);
+    group.sampling_mode(SamplingMode::Flat);
+
+    for n in N_ELEMENTS_TO_TEST {
+        group.throughput(Throughput::Elements(*n as u64));
+
+        group.bench_function(BenchmarkId::new("BVH", *n), |b| {
+            b.iter_batched_ref(
+                || {
+                    let mut lookup_state =
+                        SpatialLookupState::with_algorithm(algorithms::Bvh::default());
+                    lookup_state.entities = entities_and_positions(*n);
+                    lookup_state
+                },
+                k_nearest_100_times_without_bevy,
+                BatchSize::LargeInput,
+            );
+        });
+
+        group.bench_function(BenchmarkId::new("KdTree", *n), |b| {
+            b.iter_batched_ref(
+                || {
+                    let mut lookup_state =
+                        SpatialLookupState::with_algorithm(algorithms::KdTree::default());
+                    lookup_state.entities = entities_and_positions(*n);
+                    lookup_state
+                },
+                k_nearest_100_times_without_bevy,
+                BatchSize::LargeInput,
+            );
+        });
+    }
+}
+
 criterion_group!(
     benches,
     benchmark_prepare_with_bvh,
     benchmark_query_with_bvh,
+    benchmark_prepare_with_lbvh,
     benchmark_prepare_with_naive,
     benchmark_query_with_naive,
+    benchmark_prepare_with_hash_grid,
+    benchmark_query_with_hash_grid,
     compare_bvh_to_naive,
     benchmark_naive_without_bevy,
     benchmark_bvh_without_bevy,
+    benchmark_hash_grid_without_bevy,
+    compare_k_nearest,
 );
 criterion_main!(benches);
@@ -48,8 +48,10 @@ fn setup(
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<ColorMaterial>>,
 ) {
-    let default_material = materials.add(Color::linear_rgb(0.1, 0.1, 0.1));
-    let hovered_material = materials.add(Color::linear_rgb(0.8, 0.8, 0.8));
+    let example_materials = ExampleMaterials {
+        default_material: materials.add(Color::linear_rgb(0.1, 0.1, 0.1)),
+        hovered_material: materials.add(Color::linear_rgb(0.8, 0.8, 0.8)),
+    };
 
     let mesh = meshes.add(Circle::new(CIRCLE_RADIUS));
 
@@ -63,7 +65,7 @@ fn setup(
         for col in 0..COLUMNS {
             commands.spawn((
                 Mesh2d(mesh.clone()),
-                MeshMaterial2d(default_material.clone()),
+                MeshMaterial2d(example_materials.default_material.clone()),
                 Transform::from_translation(
                     Vec3::new(
                         col as f32 * CIRCLE_RADIUS * 2.,
@@ -77,10 +79,7 @@ fn setup(
     }
 
     commands.spawn(Camera2d);
-    commands.insert_resource(ExampleMaterials {
-        default_material,
-        hovered_material,
-    });
+    commands.insert_resource(example_materials);
 }
 
 /// System which changes the material of entities that are near the cursor using spatial queries.
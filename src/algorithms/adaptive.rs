@@ -0,0 +1,371 @@
+//! Spatial lookup which picks the fastest of several algorithms for the observed workload
+
+use crate::SpatialLookupAlgorithm;
+use crate::algorithms::{Bvh, Naive};
+use crate::spatial_extent::SpatialExtent;
+use crate::spatial_shape::SpatialShape;
+use bevy::prelude::*;
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Rough cost estimates of a lookup algorithm, used by `Adaptive` to pick the fastest algorithm.
+///
+/// The costs are relative to checking a single entity against a query, which is what the
+/// `Naive` algorithm does for every entity in every query.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AdaptiveCost {
+    /// Cost of preparing the algorithm, per entity.
+    pub prepare_per_entity: f32,
+    /// Cost of a single query, per entity in the lookup.
+    pub query_per_entity: f32,
+    /// Cost of a single query which doesn't depend on the number of entities, e.g. for
+    /// traversing a tree.
+    pub query_base: f32,
+}
+
+impl AdaptiveCost {
+    /// Cost estimate of the `Naive` algorithm, which is the unit of all other estimates.
+    ///
+    /// Preparing only copies the entities, which measures at 0.1 - 1.3 entity checks per entity,
+    /// so it's rounded up to one.
+    pub const NAIVE: AdaptiveCost = AdaptiveCost {
+        prepare_per_entity: 1.,
+        query_per_entity: 1.,
+        query_base: 0.,
+    };
+
+    /// Cost estimate of the `Bvh` algorithm with its default settings.
+    ///
+    /// Measured by timing `prepare_algorithm` and `entities_in_radius` of both algorithms on the
+    /// uniformly spread entities of the `Naive Plain` and `BVH Plain` benchmarks, and dividing by
+    /// the time `Naive` takes to check one entity:
+    ///
+    /// - building the tree costs 90 entity checks per entity with 100 000 entities and 150 with
+    ///   1 000 000 entities,
+    /// - a query scans the few leaves of up to 10 000 entities it overlaps, which costs about
+    ///   23 000 entity checks with 100 000 entities, and only 1 000 more with 1 000 000.
+    ///
+    /// With fewer than 10 000 entities the whole tree is a single leaf and queries cost as much
+    /// as with `Naive`. The `query_base` overestimates those queries, so `Naive` is picked there.
+    pub const BVH: AdaptiveCost = AdaptiveCost {
+        prepare_per_entity: 100.,
+        query_per_entity: 0.001,
+        query_base: 23_000.,
+    };
+
+    /// Returns the estimated cost of a frame with the given number of entities, rebuilds and
+    /// queries.
+    pub fn frame_cost(&self, entities: f32, rebuilds: f32, queries: f32) -> f32 {
+        rebuilds * entities * self.prepare_per_entity
+            + queries * (self.query_base + entities * self.query_per_entity)
+    }
+}
+
+/// Algorithm contained in `Adaptive`, along with its cost estimates.
+struct Candidate {
+    algorithm: Box<dyn SpatialLookupAlgorithm + Send + Sync>,
+    cost: AdaptiveCost,
+}
+
+/// Spatial lookup which switches between several algorithms depending on the workload.
+///
+/// Whether a more advanced algorithm is faster than `Naive` depends on the number of entities,
+/// how often the lookup is rebuilt and how many queries are made per frame. `Adaptive` records
+/// these every frame, estimates the cost of each contained algorithm with its `AdaptiveCost`,
+/// and switches to the cheapest one at the start of a frame. The lookup is then rebuilt with the
+/// new algorithm.
+///
+/// To avoid switching back and forth when the costs are close, a different algorithm is only
+/// selected once it is estimated to be cheaper by more than the `hysteresis` fraction, and at
+/// most once every `min_frames_between_switches` frames.
+///
+/// Only the active algorithm is prepared and updated, so the inactive algorithms don't cost
+/// anything but memory. Use `active_index` or `SpatialLookupAlgorithm::name` to see which
+/// algorithm is in use:
+/// ```
+/// # use bevy::prelude::*;
+/// # use bevy_mod_spatial_query::prelude::*;
+/// # use bevy_mod_spatial_query::algorithms::{Adaptive, AdaptiveCost, HashGrid, Naive};
+/// #
+/// # let mut app = App::new();
+/// #
+/// let adaptive = Adaptive::new(Naive::default(), AdaptiveCost::NAIVE).with_algorithm(
+///     HashGrid::with_cell_size(10.),
+///     AdaptiveCost {
+///         prepare_per_entity: 5.,
+///         query_per_entity: 0.01,
+///         query_base: 50.,
+///     },
+/// );
+/// app.insert_resource(SpatialLookupState::with_algorithm(adaptive));
+///
+/// fn log_algorithm(lookup_state: Res<SpatialLookupState>) {
+///     info!("spatial lookup is using {}", lookup_state.algorithm.name());
+/// }
+///
+/// app.add_systems(Update, log_algorithm);
+/// ```
+pub struct Adaptive {
+    /// Fraction by which another algorithm has to be estimated cheaper than the active one before
+    /// switching to it.
+    pub hysteresis: f32,
+    /// Minimum number of frames between switching algorithms.
+    pub min_frames_between_switches: u32,
+    /// Weight of the latest frame in the running averages of the workload, between 0 and 1.
+    /// Larger values react faster to changes in the workload.
+    pub smoothing: f32,
+    candidates: Vec<Candidate>,
+    active: usize,
+    /// Number of entities in the active algorithm.
+    len: usize,
+    queries_this_frame: AtomicUsize,
+    rebuilds_this_frame: usize,
+    /// Running average of the number of queries per frame.
+    queries_per_frame: f32,
+    /// Running average of the number of rebuilds per frame.
+    rebuilds_per_frame: f32,
+    frames_since_switch: u32,
+}
+
+impl std::fmt::Debug for Adaptive {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Adaptive")
+            .field("hysteresis", &self.hysteresis)
+            .field(
+                "min_frames_between_switches",
+                &self.min_frames_between_switches,
+            )
+            .field("smoothing", &self.smoothing)
+            .field("active", &self.name())
+            .field("queries_per_frame", &self.queries_per_frame)
+            .field("rebuilds_per_frame", &self.rebuilds_per_frame)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for Adaptive {
+    /// Switches between `Naive` and `Bvh`.
+    fn default() -> Self {
+        Adaptive::new(Naive::default(), AdaptiveCost::NAIVE)
+            .with_algorithm(Bvh::default(), AdaptiveCost::BVH)
+    }
+}
+
+impl Adaptive {
+    /// Creates an adaptive lookup which starts with the given algorithm.
+    pub fn new<T: SpatialLookupAlgorithm + Send + Sync + 'static>(
+        algorithm: T,
+        cost: AdaptiveCost,
+    ) -> Self {
+        Adaptive {
+            hysteresis: 0.25,
+            min_frames_between_switches: 60,
+            smoothing: 0.1,
+            candidates: vec![Candidate {
+                algorithm: Box::new(algorithm),
+                cost,
+            }],
+            active: 0,
+            len: 0,
+            queries_this_frame: AtomicUsize::new(0),
+            rebuilds_this_frame: 0,
+            queries_per_frame: 0.,
+            rebuilds_per_frame: 0.,
+            frames_since_switch: 0,
+        }
+    }
+
+    /// Adds an algorithm to switch to when it's estimated to be the fastest.
+    pub fn with_algorithm<T: SpatialLookupAlgorithm + Send + Sync + 'static>(
+        mut self,
+        algorithm: T,
+        cost: AdaptiveCost,
+    ) -> Self {
+        self.candidates.push(Candidate {
+            algorithm: Box::new(algorithm),
+            cost,
+        });
+        self
+    }
+
+    /// Returns the index of the active algorithm, in the order the algorithms were added.
+    pub fn active_index(&self) -> usize {
+        self.active
+    }
+
+    /// Returns the running average of the number of queries per frame.
+    pub fn queries_per_frame(&self) -> f32 {
+        self.queries_per_frame
+    }
+
+    /// Returns the running average of the number of times the lookup is rebuilt per frame.
+    pub fn rebuilds_per_frame(&self) -> f32 {
+        self.rebuilds_per_frame
+    }
+
+    /// Returns the estimated cost of a frame with each algorithm, in the order the algorithms
+    /// were added.
+    pub fn estimated_costs(&self) -> impl Iterator<Item = f32> + '_ {
+        self.candidates.iter().map(|candidate| {
+            candidate.cost.frame_cost(
+                self.len as f32,
+                self.rebuilds_per_frame,
+                self.queries_per_frame,
+            )
+        })
+    }
+
+    #[inline]
+    fn active(&self) -> &(dyn SpatialLookupAlgorithm + Send + Sync) {
+        self.candidates[self.active].algorithm.as_ref()
+    }
+
+    /// Counts a query answered by the active algorithm for this frame.
+    #[inline]
+    fn count_query(&self) {
+        self.queries_this_frame.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts the query if the active algorithm answered it. Otherwise `SpatialLookupState` falls
+    /// back to `entities_in_radius`, which counts the query itself, or to a linear scan.
+    #[inline]
+    fn answered<T>(&self, result: Option<T>) -> Option<T> {
+        if result.is_some() {
+            self.count_query();
+        }
+
+        result
+    }
+
+    #[inline]
+    fn active_mut(&mut self) -> &mut (dyn SpatialLookupAlgorithm + Send + Sync) {
+        self.candidates[self.active].algorithm.as_mut()
+    }
+}
+
+impl SpatialLookupAlgorithm for Adaptive {
+    fn prepare(&mut self, entities: &[(Entity, Vec3)]) {
+        self.prepare_with_extents(entities, &[]);
+    }
+
+    fn prepare_with_extents(&mut self, entities: &[(Entity, Vec3)], extents: &[SpatialExtent]) {
+        self.len = entities.len();
+        self.rebuilds_this_frame += 1;
+        self.active_mut().prepare_with_extents(entities, extents);
+    }
+
+    fn insert(&mut self, entity: Entity, position: Vec3, extent: SpatialExtent) -> bool {
+        let inserted = self.active_mut().insert(entity, position, extent);
+        if inserted {
+            self.len += 1;
+        }
+
+        inserted
+    }
+
+    fn update(&mut self, entity: Entity, position: Vec3, extent: SpatialExtent) -> bool {
+        self.active_mut().update(entity, position, extent)
+    }
+
+    fn remove(&mut self, entity: Entity) -> bool {
+        let removed = self.active_mut().remove(entity);
+        if removed {
+            self.len = self.len.saturating_sub(1);
+        }
+
+        removed
+    }
+
+    fn begin_frame(&mut self) -> bool {
+        let queries = self.queries_this_frame.swap(0, Ordering::Relaxed);
+        self.queries_per_frame += (queries as f32 - self.queries_per_frame) * self.smoothing;
+        self.rebuilds_per_frame +=
+            (self.rebuilds_this_frame as f32 - self.rebuilds_per_frame) * self.smoothing;
+        self.rebuilds_this_frame = 0;
+        self.frames_since_switch = self.frames_since_switch.saturating_add(1);
+
+        let active_ready = self.active_mut().begin_frame();
+        if self.frames_since_switch < self.min_frames_between_switches {
+            return active_ready;
+        }
+
+        let costs: Vec<f32> = self.estimated_costs().collect();
+        let Some((cheapest, cheapest_cost)) = costs
+            .iter()
+            .copied()
+            .enumerate()
+            .min_by(|(_, cost), (_, other_cost)| cost.total_cmp(other_cost))
+        else {
+            return active_ready;
+        };
+
+        if cheapest == self.active || cheapest_cost >= costs[self.active] * (1. - self.hysteresis) {
+            return active_ready;
+        }
+
+        // free the memory used by the previous algorithm, the new one is prepared by the rebuild
+        self.active_mut().prepare(&[]);
+        self.active = cheapest;
+        self.frames_since_switch = 0;
+
+        false
+    }
+
+    fn name(&self) -> &'static str {
+        self.candidates[self.active].algorithm.name()
+    }
+
+    fn entities_in_radius(&self, sample_point: Vec3, radius: f32) -> Vec<Entity> {
+        self.count_query();
+        self.active().entities_in_radius(sample_point, radius)
+    }
+
+    fn visit_in_radius(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+        visit: &mut dyn FnMut(Entity, Vec3) -> ControlFlow<()>,
+    ) -> Option<ControlFlow<()>> {
+        self.answered(self.active().visit_in_radius(sample_point, radius, visit))
+    }
+
+    fn entities_in_aabb(&self, min: Vec3, max: Vec3) -> Option<Vec<Entity>> {
+        self.answered(self.active().entities_in_aabb(min, max))
+    }
+
+    fn entities_in_shape(
+        &self,
+        shape: &dyn SpatialShape,
+        isometry: Isometry3d,
+    ) -> Option<Vec<Entity>> {
+        self.answered(self.active().entities_in_shape(shape, isometry))
+    }
+
+    fn entities_along_ray(
+        &self,
+        ray: Ray3d,
+        max_distance: f32,
+        thickness: f32,
+    ) -> Option<Vec<(Entity, f32)>> {
+        self.answered(
+            self.active()
+                .entities_along_ray(ray, max_distance, thickness),
+        )
+    }
+
+    fn k_nearest(&self, sample_point: Vec3, k: usize, max_distance: f32) -> Option<Vec<Entity>> {
+        self.answered(self.active().k_nearest(sample_point, k, max_distance))
+    }
+
+    fn nearest_iter(&self, sample_point: Vec3) -> Option<Box<dyn Iterator<Item = Entity> + '_>> {
+        self.answered(self.active().nearest_iter(sample_point))
+    }
+
+    fn overlapping_pairs(&self) -> Option<Vec<(Entity, Entity)>> {
+        self.answered(self.active().overlapping_pairs())
+    }
+
+    fn debug_gizmos(&self, gizmos: &mut Gizmos) {
+        self.candidates[self.active].algorithm.debug_gizmos(gizmos);
+    }
+}
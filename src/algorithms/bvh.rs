@@ -1,21 +1,75 @@
 //! Bounding Volume Hierarchy -accelerated spatial lookup
 
 use crate::SpatialLookupAlgorithm;
+use crate::algorithms::SpatialPlane;
+use crate::algorithms::nearest::{
+    ByDistance, KNearest, NearestCandidates, NearestIter, NearestTree,
+};
+use crate::algorithms::traversal_stack::TraversalStack;
+use crate::spatial_extent::{SpatialExtent, with_extents};
+use crate::spatial_shape::SpatialShape;
+use bevy::ecs::entity::EntityHashMap;
+use bevy::math::bounding::Aabb3d;
 use bevy::math::{FloatOrd, FloatPow};
 use bevy::prelude::*;
-use bevy::tasks::TaskPool;
+use bevy::tasks::{ComputeTaskPool, TaskPool};
+use std::collections::BinaryHeap;
+use std::ops::ControlFlow;
 
-type EntityPositionPair = (Entity, Vec3);
+type EntityPositionExtent = (Entity, Vec3, SpatialExtent);
+
+/// Subtrees with fewer entities than this are built on the calling thread, because spawning tasks
+/// for them costs more than building them.
+const PARALLEL_BUILD_THRESHOLD: usize = 4096;
+
+/// Algorithm used to build the tree of a [`Bvh`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum BvhBuildStrategy {
+    /// Splits the nodes using the Surface Area Heuristic, which results in the best tree for
+    /// queries but is slow to build.
+    #[default]
+    Sah,
+    /// Builds a Linear BVH: the positions of the entities are sorted along a Morton curve with a
+    /// radix sort, and the nodes are split where the Morton codes start to differ. This is much
+    /// faster to build than the SAH tree, but the nodes are worse fitted to the entities, which
+    /// makes queries slower. Useful when the tree is rebuilt every frame, and only a few queries
+    /// are made per frame.
+    Lbvh,
+}
 
 /// Bounding Volume Hierarchy -based spatial acceleration algorithm.
 ///
-/// This implementation uses Surface Area Heuristic for splitting the space. Maximum number of
-/// splits to sample can be configured with the `max_split_samples_per_axis` field.
+/// By default this implementation uses binned Surface Area Heuristic for splitting the space: the
+/// entities of each node are sorted into bins along each axis, and the cost of splitting between
+/// each pair of bins is evaluated with a single sweep over the bins from both sides. The number of
+/// bins can be configured with the `max_split_samples_per_axis` field. A faster to build but lower
+/// quality Linear BVH can be selected with `build_strategy`.
 ///
 /// Number of entities per leaf node is controlled by the `entities_per_leaf` field. Storing higher
 /// number of entities per field results in smaller tree structure, faster tree building and
 /// traversal, but slower final entity filtering.
 ///
+/// The nodes are stored in a single flat array in depth-first order, with children referred to by
+/// their index, and the entities of each leaf node are stored in a contiguous range of a second
+/// flat array. This avoids allocating every node separately and keeps the traversal cache
+/// friendly.
+///
+/// By default the tree is rebuilt from scratch by every `prepare`. Setting
+/// `refit_max_surface_area_growth` enables refitting instead, which keeps the tree structure from
+/// the previous `prepare`, moves the entities within their leaf nodes and recalculates the node
+/// AABBs bottom-up. This is much faster when entities only move a little between frames, but the
+/// tree gets worse as the entities move further from where they were when it was built. The tree
+/// is therefore rebuilt when the total surface area of its nodes has grown by more than the
+/// configured fraction since the last rebuild, or when a leaf node becomes empty or grows to
+/// twice `entities_per_leaf`.
+///
+/// Setting `plane` builds the tree for a 2D world: splits are only searched along the two axes of
+/// the plane, the Surface Area Heuristic uses the perimeters of the node rectangles on the plane,
+/// and the Morton codes of `BvhBuildStrategy::Lbvh` only interleave the two axes. This only
+/// changes how the tree is built. The nodes still store and are tested as 3D AABBs, so queries
+/// stay exact for entities at different depths, but a 2D tree uses as much memory and is
+/// traversed as fast as a 3D tree of the same shape.
+///
 /// Spatial lookups with the BVH structure can be split into two phases: tree traversal and final
 /// filtering.
 ///
@@ -27,44 +81,96 @@ type EntityPositionPair = (Entity, Vec3);
 /// actually intersect the query.
 #[derive(Debug)]
 pub struct Bvh {
+    /// Algorithm used to build the tree.
+    pub build_strategy: BvhBuildStrategy,
     /// Maximum number of entities per leaf node.
     pub entities_per_leaf: usize,
-    /// Maximum number of test splits performed per axis. Larger number results in better (=faster)
-    /// tree structure but makes tree generation slower.
+    /// Maximum number of test splits performed per axis, i.e. the number of bins per axis minus
+    /// one. Larger number results in better (=faster) tree structure but makes tree generation
+    /// slower.
     pub max_split_samples_per_axis: usize,
-    root: Option<BvhNode>,
+    /// Enables refitting the tree instead of rebuilding it, with the maximum allowed growth of the
+    /// total surface area of the nodes before the tree is rebuilt. For example, `Some(0.5)`
+    /// rebuilds the tree once the surface area has grown by 50% since the last rebuild.
+    pub refit_max_surface_area_growth: Option<f32>,
+    /// Only splits the tree along the axes of the given plane, for 2D worlds. The nodes keep their
+    /// 3D AABBs. Changing this takes effect on the next rebuild.
+    pub plane: Option<SpatialPlane>,
+    tree: Option<BvhTree>,
     tree_depth: usize,
-    task_pool: TaskPool,
+    /// Total surface area of the nodes when the tree was last rebuilt.
+    built_surface_area: f32,
+    /// Plane used when the tree was last rebuilt.
+    built_plane: Option<SpatialPlane>,
 }
 
 impl Default for Bvh {
     fn default() -> Self {
         Bvh {
+            build_strategy: BvhBuildStrategy::default(),
             entities_per_leaf: 10_000,
             max_split_samples_per_axis: 10,
-            root: None,
+            refit_max_surface_area_growth: None,
+            plane: None,
+            tree: None,
             tree_depth: 0,
-            task_pool: TaskPool::new(),
+            built_surface_area: 0.,
+            built_plane: None,
         }
     }
 }
 
 impl SpatialLookupAlgorithm for Bvh {
-    fn prepare(&mut self, entities: &[EntityPositionPair]) {
-        let root = split_node(
-            entities,
-            self.entities_per_leaf,
-            self.max_split_samples_per_axis,
-            &self.task_pool,
-        );
+    fn prepare(&mut self, entities: &[(Entity, Vec3)]) {
+        self.prepare_with_extents(entities, &[]);
+    }
+
+    fn prepare_with_extents(&mut self, entities: &[(Entity, Vec3)], extents: &[SpatialExtent]) {
+        if self.refit(entities, extents) {
+            return;
+        }
 
-        self.tree_depth = root.count_depth();
-        self.root = Some(root);
+        let mut tree = BvhTree {
+            nodes: Vec::new(),
+            entities: with_extents(entities, extents).collect(),
+        };
+
+        if !tree.entities.is_empty() {
+            let settings = BuildSettings {
+                entities_per_leaf: self.entities_per_leaf.max(1),
+                bins: self.max_split_samples_per_axis.max(1) + 1,
+                plane: self.plane,
+            };
+
+            match self.build_strategy {
+                BvhBuildStrategy::Sah => {
+                    build_node(
+                        &mut tree.entities,
+                        0,
+                        &mut tree.nodes,
+                        &settings,
+                        ComputeTaskPool::get_or_init(TaskPool::default),
+                    );
+                }
+                BvhBuildStrategy::Lbvh => build_lbvh(&mut tree, &settings),
+            }
+        }
+
+        self.tree_depth = tree.count_depth();
+        self.built_surface_area = tree.total_surface_area(self.plane);
+        self.built_plane = self.plane;
+        self.tree = Some(tree);
     }
 
     fn entities_in_radius(&self, sample_point: Vec3, radius: f32) -> Vec<Entity> {
-        if let Some(root) = &self.root {
-            root.entities_in_radius(sample_point, radius)
+        if let Some(tree) = &self.tree {
+            let mut found = Vec::new();
+            let _ = tree.visit_in_radius(sample_point, radius, &mut |entity, _position| {
+                found.push(entity);
+                ControlFlow::Continue(())
+            });
+
+            found
         } else {
             warn!(
                 "called Bvh::entities_in_radius before initializing the lookup with Bvh::prepare,\
@@ -74,137 +180,529 @@ impl SpatialLookupAlgorithm for Bvh {
         }
     }
 
+    fn visit_in_radius(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+        visit: &mut dyn FnMut(Entity, Vec3) -> ControlFlow<()>,
+    ) -> Option<ControlFlow<()>> {
+        if let Some(tree) = &self.tree {
+            Some(tree.visit_in_radius(sample_point, radius, visit))
+        } else {
+            warn!(
+                "called Bvh::visit_in_radius before initializing the lookup with Bvh::prepare,\
+                no entities will be visited"
+            );
+            Some(ControlFlow::Continue(()))
+        }
+    }
+
+    fn entities_in_aabb(&self, min: Vec3, max: Vec3) -> Option<Vec<Entity>> {
+        if let Some(tree) = &self.tree {
+            Some(tree.entities_in_aabb(&Aabb { min, max }))
+        } else {
+            warn!(
+                "called Bvh::entities_in_aabb before initializing the lookup with Bvh::prepare,\
+                no entities will be returned"
+            );
+            Some(Vec::new())
+        }
+    }
+
+    fn entities_in_shape(
+        &self,
+        shape: &dyn SpatialShape,
+        isometry: Isometry3d,
+    ) -> Option<Vec<Entity>> {
+        if let Some(tree) = &self.tree {
+            Some(tree.entities_in_shape(shape, isometry))
+        } else {
+            warn!(
+                "called Bvh::entities_in_shape before initializing the lookup with Bvh::prepare,\
+                no entities will be returned"
+            );
+            Some(Vec::new())
+        }
+    }
+
+    fn entities_along_ray(
+        &self,
+        ray: Ray3d,
+        max_distance: f32,
+        thickness: f32,
+    ) -> Option<Vec<(Entity, f32)>> {
+        if let Some(tree) = &self.tree {
+            let mut found = tree.entities_along_ray(ray, max_distance, thickness);
+            found.sort_unstable_by_key(|(_entity, distance)| FloatOrd(*distance));
+
+            Some(found)
+        } else {
+            warn!(
+                "called Bvh::entities_along_ray before initializing the lookup with Bvh::prepare,\
+                no entities will be returned"
+            );
+            Some(Vec::new())
+        }
+    }
+
+    fn k_nearest(&self, sample_point: Vec3, k: usize, max_distance: f32) -> Option<Vec<Entity>> {
+        if let Some(tree) = &self.tree {
+            Some(tree.k_nearest(sample_point, k, max_distance))
+        } else {
+            warn!(
+                "called Bvh::k_nearest before initializing the lookup with Bvh::prepare,\
+                no entities will be returned"
+            );
+            Some(Vec::new())
+        }
+    }
+
+    fn nearest_iter(&self, sample_point: Vec3) -> Option<Box<dyn Iterator<Item = Entity> + '_>> {
+        if let Some(tree) = &self.tree {
+            Some(Box::new(NearestIter::new(
+                tree,
+                sample_point,
+                (!tree.nodes.is_empty()).then_some(0),
+            )))
+        } else {
+            warn!(
+                "called Bvh::nearest_iter before initializing the lookup with Bvh::prepare,\
+                no entities will be returned"
+            );
+            Some(Box::new(std::iter::empty()))
+        }
+    }
+
     fn debug_gizmos(&self, gizmos: &mut Gizmos) {
-        if let Some(root) = &self.root {
-            root.draw_gizmos(gizmos, 0, self.tree_depth);
+        if let Some(tree) = &self.tree {
+            tree.draw_gizmos(gizmos, self.built_plane, self.tree_depth);
         }
     }
 }
 
-/// Recursively splits a slice of Entity, Position pairs into BVH nodes.
-///
-/// This implementation uses the Surface Area Heuristic with a user-controllable amount of
-/// split samples.
-fn split_node(
-    entities: &[EntityPositionPair],
+impl Bvh {
+    /// Refits the existing tree to the given entities, if refitting is enabled.
+    ///
+    /// Returns false if the tree has to be rebuilt instead.
+    fn refit(&mut self, entities: &[(Entity, Vec3)], extents: &[SpatialExtent]) -> bool {
+        let (Some(max_growth), Some(tree)) = (self.refit_max_surface_area_growth, &mut self.tree)
+        else {
+            return false;
+        };
+        if self.plane != self.built_plane || tree.nodes.is_empty() {
+            return false;
+        }
+
+        let new_entities: EntityHashMap<EntityPositionExtent> = with_extents(entities, extents)
+            .map(|entity_position_extent| (entity_position_extent.0, entity_position_extent))
+            .collect();
+
+        tree.update_entities(new_entities, self.plane);
+
+        match tree.refit_aabbs(self.entities_per_leaf * 2, self.plane) {
+            Some(surface_area) => surface_area <= self.built_surface_area * (1. + max_growth),
+            None => false,
+        }
+    }
+}
+
+/// Settings used while building the tree.
+#[derive(Debug, Clone, Copy)]
+struct BuildSettings {
     entities_per_leaf: usize,
-    max_split_samples_per_axis: usize,
+    /// Number of bins per axis for the binned Surface Area Heuristic.
+    bins: usize,
+    plane: Option<SpatialPlane>,
+}
+
+/// Recursively builds the node for the given entities and its children, and pushes them to
+/// `nodes` in depth-first order.
+///
+/// The entities are reordered so that the entities of each leaf node are contiguous.
+/// `entity_offset` is the index of the first of the entities in the entity array of the tree.
+fn build_node(
+    entities: &mut [EntityPositionExtent],
+    entity_offset: usize,
+    nodes: &mut Vec<BvhNode>,
+    settings: &BuildSettings,
     task_pool: &TaskPool,
-) -> BvhNode {
+) {
     assert!(!entities.is_empty());
 
-    // we make a copy of the slice, because we need to sort it to find the axis of best split
-    let mut entities = entities.to_vec();
-    let aabb = calculate_aabb(&entities);
+    let aabb = calculate_aabb(entities);
 
-    if entities.len() <= entities_per_leaf {
-        return BvhNode {
+    if entities.len() <= settings.entities_per_leaf {
+        nodes.push(BvhNode {
             aabb,
-            kind: BvhNodeKind::Leaf(entities),
+            kind: BvhNodeKind::Leaf {
+                first: entity_offset as u32,
+                count: entities.len() as u32,
+            },
+        });
+        return;
+    }
+
+    let split_at = partition_binned_sah(entities, settings);
+
+    // the children are filled in once their indices are known
+    let index = nodes.len();
+    nodes.push(BvhNode {
+        aabb,
+        kind: BvhNodeKind::Branch { left: 0, right: 0 },
+    });
+
+    let parallel = entities.len() >= PARALLEL_BUILD_THRESHOLD;
+    let (left, right) = entities.split_at_mut(split_at);
+    let left_index = nodes.len() as u32;
+    let right_index;
+
+    if parallel {
+        let build_subtree = |entities: &mut [EntityPositionExtent]| {
+            let mut nodes = Vec::new();
+            build_node(entities, 0, &mut nodes, settings, task_pool);
+            nodes
         };
+
+        let mut subtrees = task_pool.scope(|scope| {
+            scope.spawn(async { build_subtree(left) });
+            scope.spawn(async { build_subtree(right) });
+        });
+        assert_eq!(subtrees.len(), 2);
+        // Unwrap is fine because of the assert above
+        let right_nodes = subtrees.pop().unwrap();
+        let left_nodes = subtrees.pop().unwrap();
+
+        append_subtree(nodes, left_nodes, entity_offset);
+        right_index = nodes.len() as u32;
+        append_subtree(nodes, right_nodes, entity_offset + split_at);
+    } else {
+        build_node(left, entity_offset, nodes, settings, task_pool);
+        right_index = nodes.len() as u32;
+        build_node(right, entity_offset + split_at, nodes, settings, task_pool);
     }
 
-    let sort_by_axis = |axis: usize, entities: &mut [EntityPositionPair]| {
-        entities.sort_unstable_by_key(|(_entity, position)| FloatOrd(position[axis]));
+    nodes[index].kind = BvhNodeKind::Branch {
+        left: left_index,
+        right: right_index,
     };
+}
 
-    // find the axis of best split
-    // TODO: to support 2D BVHs, all we have to do is use the first 2 axis instead of all 3.
-    let costs: Vec<(usize, f32)> = (0..3)
-        .map(|axis| {
-            sort_by_axis(axis, &mut entities);
-            find_split_index_and_cost(&entities, max_split_samples_per_axis)
-        })
-        .collect();
+/// Appends the nodes of a subtree built separately to `nodes`, offsetting the child indices by the
+/// position of the subtree and the entity ranges by `entity_offset`.
+fn append_subtree(nodes: &mut Vec<BvhNode>, subtree: Vec<BvhNode>, entity_offset: usize) {
+    let node_offset = nodes.len() as u32;
 
-    let (axis, (split_at, _cost)) = costs
+    nodes.extend(subtree.into_iter().map(|mut node| {
+        match &mut node.kind {
+            BvhNodeKind::Leaf { first, .. } => *first += entity_offset as u32,
+            BvhNodeKind::Branch { left, right } => {
+                *left += node_offset;
+                *right += node_offset;
+            }
+        }
+        node
+    }));
+}
+
+/// Finds the split of the entities with the lowest binned Surface Area Heuristic cost, and
+/// partitions the entities at it.
+///
+/// The positions of the entities are sorted into `bins` equally sized bins along each axis. A
+/// sweep from the right accumulates the AABBs and entity counts of everything right of each bin
+/// boundary, and a sweep from the left then finds the cheapest boundary in a single pass.
+///
+/// Returns the number of entities left of the split.
+fn partition_binned_sah(entities: &mut [EntityPositionExtent], settings: &BuildSettings) -> usize {
+    let mut centroid_min = Vec3::INFINITY;
+    let mut centroid_max = Vec3::NEG_INFINITY;
+    for (_entity, position, _extent) in entities.iter() {
+        centroid_min = centroid_min.min(*position);
+        centroid_max = centroid_max.max(*position);
+    }
+
+    let bins = settings.bins;
+    let bin_of = |position: Vec3, axis: usize| {
+        let relative =
+            (position[axis] - centroid_min[axis]) / (centroid_max[axis] - centroid_min[axis]);
+        ((relative * bins as f32) as usize).min(bins - 1)
+    };
+
+    // 2D trees only split along the axes of their plane
+    let axes = match settings.plane {
+        Some(plane) => plane.axes().to_vec(),
+        None => vec![0, 1, 2],
+    };
+
+    let mut bin_aabbs = vec![Aabb::EMPTY; bins];
+    let mut bin_counts = vec![0_usize; bins];
+    let mut right_aabbs = vec![Aabb::EMPTY; bins];
+    let mut right_counts = vec![0_usize; bins];
+
+    // axis, last bin left of the split, cost
+    let mut best_split: Option<(usize, usize, f32)> = None;
+
+    for axis in axes {
+        if centroid_max[axis] <= centroid_min[axis] {
+            continue;
+        }
+
+        bin_aabbs.fill(Aabb::EMPTY);
+        bin_counts.fill(0);
+        for entity_position_extent in entities.iter() {
+            let bin = bin_of(entity_position_extent.1, axis);
+            bin_aabbs[bin] = bin_aabbs[bin].union(&calculate_aabb(&[*entity_position_extent]));
+            bin_counts[bin] += 1;
+        }
+
+        // right_aabbs[i] and right_counts[i] cover the bins from i to the last bin
+        let mut aabb = Aabb::EMPTY;
+        let mut count = 0;
+        for bin in (1..bins).rev() {
+            aabb = aabb.union(&bin_aabbs[bin]);
+            count += bin_counts[bin];
+            right_aabbs[bin] = aabb.clone();
+            right_counts[bin] = count;
+        }
+
+        let mut aabb = Aabb::EMPTY;
+        let mut count = 0;
+        for bin in 0..bins - 1 {
+            aabb = aabb.union(&bin_aabbs[bin]);
+            count += bin_counts[bin];
+
+            let right_count = right_counts[bin + 1];
+            if count == 0 || right_count == 0 {
+                continue;
+            }
+
+            let cost = aabb.surface_area(settings.plane) * count as f32
+                + right_aabbs[bin + 1].surface_area(settings.plane) * right_count as f32;
+            if best_split.is_none_or(|(_axis, _bin, best_cost)| cost < best_cost) {
+                best_split = Some((axis, bin, cost));
+            }
+        }
+    }
+
+    let Some((axis, split_bin, _cost)) = best_split else {
+        // all entities are at the same position, so any split is as good as any other
+        return entities.len() / 2;
+    };
+
+    let mut split_at = 0;
+    for index in 0..entities.len() {
+        if bin_of(entities[index].1, axis) <= split_bin {
+            entities.swap(index, split_at);
+            split_at += 1;
+        }
+    }
+
+    split_at
+}
+
+/// Builds a Linear BVH from the entities of the tree.
+///
+/// The positions are quantized within their bounding box and encoded as Morton codes, which are
+/// sorted with a radix sort. Entities close to each other along the Morton curve are close to each
+/// other in space, so the hierarchy is emitted by splitting the sorted entities at the highest bit
+/// where the Morton codes differ.
+fn build_lbvh(tree: &mut BvhTree, settings: &BuildSettings) {
+    assert!(!tree.entities.is_empty());
+
+    let mut min = Vec3::INFINITY;
+    let mut max = Vec3::NEG_INFINITY;
+    for (_entity, position, _extent) in &tree.entities {
+        min = min.min(*position);
+        max = max.max(*position);
+    }
+    let scale = (max - min).max(Vec3::splat(f32::EPSILON)).recip();
+
+    let mut codes: Vec<(u64, u32)> = tree
+        .entities
         .iter()
         .enumerate()
-        .min_by_key(|(_axis, (_split_at, cost))| FloatOrd(*cost))
-        .unwrap();
-
-    // split entities at the index of best split
-    sort_by_axis(axis, &mut entities);
-    let (left, right) = entities.split_at(*split_at);
-
-    let mut nodes = task_pool.scope(|scope| {
-        scope.spawn(async move {
-            split_node(
-                left,
-                entities_per_leaf,
-                max_split_samples_per_axis,
-                task_pool,
-            )
-        });
-        scope.spawn(async move {
-            split_node(
-                right,
-                entities_per_leaf,
-                max_split_samples_per_axis,
-                task_pool,
+        .map(|(index, (_entity, position, _extent))| {
+            (
+                morton_code((*position - min) * scale, settings.plane),
+                index as u32,
             )
+        })
+        .collect();
+    radix_sort(&mut codes);
+
+    tree.entities = codes
+        .iter()
+        .map(|(_code, index)| tree.entities[*index as usize])
+        .collect();
+    let codes: Vec<u64> = codes.into_iter().map(|(code, _index)| code).collect();
+
+    emit_lbvh_node(
+        &tree.entities,
+        &codes,
+        0,
+        &mut tree.nodes,
+        settings.entities_per_leaf,
+    );
+}
+
+/// Recursively emits the nodes of a Linear BVH from entities sorted by their Morton codes, and
+/// pushes them to `nodes` in depth-first order.
+///
+/// `entity_offset` is the index of the first of the entities in the entity array of the tree.
+fn emit_lbvh_node(
+    entities: &[EntityPositionExtent],
+    codes: &[u64],
+    entity_offset: usize,
+    nodes: &mut Vec<BvhNode>,
+    entities_per_leaf: usize,
+) {
+    if entities.len() <= entities_per_leaf {
+        nodes.push(BvhNode {
+            aabb: calculate_aabb(entities),
+            kind: BvhNodeKind::Leaf {
+                first: entity_offset as u32,
+                count: entities.len() as u32,
+            },
         });
+        return;
+    }
+
+    let split_at = morton_split_index(codes);
+    let (left, right) = entities.split_at(split_at);
+    let (left_codes, right_codes) = codes.split_at(split_at);
+
+    // the AABB and children are filled in once the children are emitted
+    let index = nodes.len();
+    nodes.push(BvhNode {
+        aabb: Aabb::EMPTY,
+        kind: BvhNodeKind::Branch { left: 0, right: 0 },
     });
-    assert_eq!(nodes.len(), 2);
-    // Unwrap is fine because of the assert above
-    let right_node = nodes.pop().unwrap();
-    let left_node = nodes.pop().unwrap();
 
-    BvhNode {
-        aabb,
-        kind: BvhNodeKind::Branch(Box::new(left_node), Box::new(right_node)),
-    }
+    let left_index = nodes.len();
+    emit_lbvh_node(left, left_codes, entity_offset, nodes, entities_per_leaf);
+    let right_index = nodes.len();
+    emit_lbvh_node(
+        right,
+        right_codes,
+        entity_offset + split_at,
+        nodes,
+        entities_per_leaf,
+    );
+
+    nodes[index] = BvhNode {
+        aabb: nodes[left_index].aabb.union(&nodes[right_index].aabb),
+        kind: BvhNodeKind::Branch {
+            left: left_index as u32,
+            right: right_index as u32,
+        },
+    };
 }
 
-/// Find the best split index and the resulting cost of the sorted `entities` slice.
-fn find_split_index_and_cost(
-    entities: &[EntityPositionPair],
-    max_split_samples_per_axis: usize,
-) -> (usize, f32) {
-    assert!(entities.len() > 1);
+/// Returns the index of the first code which differs from the first code at the highest bit
+/// where the codes differ, or the middle index if all codes are equal.
+fn morton_split_index(codes: &[u64]) -> usize {
+    assert!(codes.len() > 1);
 
-    let samples = entities.len().min(max_split_samples_per_axis);
-    let step = entities.len() / samples;
+    let first = codes[0];
+    let last = codes[codes.len() - 1];
+    if first == last {
+        return codes.len() / 2;
+    }
 
-    let mut min = (1, f32::INFINITY);
-    for i in (1..entities.len() - 1).step_by(step) {
-        let current_cost = cost(entities, i);
-        if current_cost < min.1 {
-            min = (i, current_cost);
+    // the codes are sorted, so all codes share the bits above the highest differing bit of the
+    // first and the last code, and the ones with that bit set are at the end
+    let highest_differing_bit = 1 << (63 - (first ^ last).leading_zeros());
+    codes.partition_point(|code| code & highest_differing_bit == 0)
+}
+
+/// Returns the Morton code of a point normalized to the unit cube, or the unit square on the
+/// plane for 2D trees.
+fn morton_code(normalized: Vec3, plane: Option<SpatialPlane>) -> u64 {
+    let quantize = |value: f32, bits: u32| {
+        let max = (1_u64 << bits) - 1;
+        ((value.clamp(0., 1.) as f64 * max as f64) as u64).min(max)
+    };
+
+    match plane {
+        Some(plane) => {
+            let [x_axis, y_axis] = plane.axes();
+            spread_bits_2(quantize(normalized[x_axis], 32))
+                | spread_bits_2(quantize(normalized[y_axis], 32)) << 1
+        }
+        None => {
+            spread_bits_3(quantize(normalized.x, 21))
+                | spread_bits_3(quantize(normalized.y, 21)) << 1
+                | spread_bits_3(quantize(normalized.z, 21)) << 2
         }
     }
+}
 
-    min
+/// Spreads the lowest 32 bits of `value` so that there is a zero bit between each of them.
+#[inline]
+fn spread_bits_2(value: u64) -> u64 {
+    let mut value = value & 0xffff_ffff;
+    value = (value | value << 16) & 0x0000_ffff_0000_ffff;
+    value = (value | value << 8) & 0x00ff_00ff_00ff_00ff;
+    value = (value | value << 4) & 0x0f0f_0f0f_0f0f_0f0f;
+    value = (value | value << 2) & 0x3333_3333_3333_3333;
+    (value | value << 1) & 0x5555_5555_5555_5555
 }
 
-/// Surface Area Heuristic.
-///
-/// The cost is based on the surface areas of the two resulting AABB shapes.
-fn cost(entities: &[EntityPositionPair], index: usize) -> f32 {
-    let (left, right) = entities.split_at(index);
+/// Spreads the lowest 21 bits of `value` so that there are two zero bits between each of them.
+#[inline]
+fn spread_bits_3(value: u64) -> u64 {
+    let mut value = value & 0x1f_ffff;
+    value = (value | value << 32) & 0x001f_0000_0000_ffff;
+    value = (value | value << 16) & 0x001f_0000_ff00_00ff;
+    value = (value | value << 8) & 0x100f_00f0_0f00_f00f;
+    value = (value | value << 4) & 0x10c3_0c30_c30c_30c3;
+    (value | value << 2) & 0x1249_2492_4924_9249
+}
 
-    let left_aabb = calculate_aabb(left);
-    let right_aabb = calculate_aabb(right);
+/// Sorts Morton code, index pairs by their code with a least significant digit radix sort.
+fn radix_sort(codes: &mut Vec<(u64, u32)>) {
+    let mut sorted = vec![(0, 0); codes.len()];
 
-    let left_surface_area = left_aabb.total_surface_area();
-    let right_surface_area = right_aabb.total_surface_area();
+    for shift in (0..64).step_by(8) {
+        let digit = |code: u64| ((code >> shift) & 0xff) as usize;
 
-    let left_cost = left_surface_area * (left.len() as f32);
-    let right_cost = right_surface_area * (right.len() as f32);
+        let mut offsets = [0_usize; 256];
+        for (code, _index) in codes.iter() {
+            offsets[digit(*code)] += 1;
+        }
+        // all codes have the same digit, so this pass wouldn't change the order
+        if offsets.contains(&codes.len()) {
+            continue;
+        }
 
-    left_cost + right_cost
+        let mut offset = 0;
+        for digit_offset in offsets.iter_mut() {
+            let count = *digit_offset;
+            *digit_offset = offset;
+            offset += count;
+        }
+
+        for (code, index) in codes.iter() {
+            let digit_offset = &mut offsets[digit(*code)];
+            sorted[*digit_offset] = (*code, *index);
+            *digit_offset += 1;
+        }
+
+        std::mem::swap(codes, &mut sorted);
+    }
 }
 
-/// Calculates the Axis-Aligned Bounding Box for a set of points.
-fn calculate_aabb(entities: &[EntityPositionPair]) -> Aabb {
+/// Calculates the Axis-Aligned Bounding Box for a set of entity volumes.
+fn calculate_aabb(entities: &[EntityPositionExtent]) -> Aabb {
     assert!(!entities.is_empty());
 
-    let mut min_point = entities[0].1;
-    let mut max_point = entities[0].1;
+    let mut min_point = Vec3::INFINITY;
+    let mut max_point = Vec3::NEG_INFINITY;
+
+    for (_, position, extent) in entities {
+        let half_size = extent.half_size();
 
-    for (_, position) in entities {
-        min_point = min_point.min(*position);
-        max_point = max_point.max(*position);
+        min_point = min_point.min(*position - half_size);
+        max_point = max_point.max(*position + half_size);
     }
 
     Aabb {
@@ -223,100 +721,538 @@ struct Aabb {
 }
 
 impl Aabb {
+    /// AABB containing nothing, which is the identity of `union`.
+    const EMPTY: Aabb = Aabb {
+        min: Vec3::INFINITY,
+        max: Vec3::NEG_INFINITY,
+    };
+
+    /// Returns the smallest AABB containing both this AABB and the other AABB.
+    #[inline]
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
     pub fn total_surface_area(&self) -> f32 {
         let extents = self.max - self.min;
 
         extents.x * extents.y * 2. + extents.x * extents.z * 2. + extents.y * extents.z * 2.
     }
+
+    /// Returns the perimeter of the rectangle this AABB projects to on the given plane.
+    pub fn perimeter(&self, plane: SpatialPlane) -> f32 {
+        let extents: Vec2 = plane.project(self.max - self.min);
+
+        (extents.x + extents.y) * 2.
+    }
+
+    /// Returns the measure used by the Surface Area Heuristic: the total surface area for 3D
+    /// trees, and the perimeter on the plane for 2D trees.
+    #[inline]
+    pub fn surface_area(&self, plane: Option<SpatialPlane>) -> f32 {
+        match plane {
+            Some(plane) => self.perimeter(plane),
+            None => self.total_surface_area(),
+        }
+    }
+
+    /// Returns the squared distance from the given point to the closest point of this AABB.
+    ///
+    /// Points inside the AABB have a distance of zero.
+    #[inline]
+    pub fn distance_squared_to_point(&self, point: Vec3) -> f32 {
+        // implementation is based on Jim Arvo's algorithm from "Graphics Gems".
+        // http://web.archive.org/web/20100323053111/http://www.ics.uci.edu/~arvo/code/BoxSphereIntersect.c
+        let mut dmin = 0.;
+
+        for axis in 0..3 {
+            if point[axis] < self.min[axis] {
+                dmin += (point[axis] - self.min[axis]).squared();
+            } else if point[axis] > self.max[axis] {
+                dmin += (point[axis] - self.max[axis]).squared();
+            }
+        }
+
+        dmin
+    }
+
+    /// Returns true if the ray hits this AABB somewhere between the origin of the ray and
+    /// `max_distance` along it.
+    ///
+    /// Uses the slab method: the ray is clipped against the pair of planes bounding each axis.
+    #[inline]
+    pub fn intersects_ray(&self, ray: Ray3d, max_distance: f32) -> bool {
+        let mut t_min = 0.0_f32;
+        let mut t_max = max_distance;
+
+        for axis in 0..3 {
+            let origin = ray.origin[axis];
+            let direction = ray.direction[axis];
+
+            if direction == 0.0 {
+                // parallel to the slab, so the ray either misses it or stays inside it
+                if origin < self.min[axis] || origin > self.max[axis] {
+                    return false;
+                }
+                continue;
+            }
+
+            let t_near = (self.min[axis] - origin) / direction;
+            let t_far = (self.max[axis] - origin) / direction;
+
+            t_min = t_min.max(t_near.min(t_far));
+            t_max = t_max.min(t_near.max(t_far));
+
+            if t_min > t_max {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Returns true if this AABB overlaps with the other AABB.
+    #[inline]
+    pub fn intersects_aabb(&self, other: &Aabb) -> bool {
+        self.min.cmple(other.max).all() && self.max.cmpge(other.min).all()
+    }
 }
 
 #[derive(Debug, Clone)]
 enum BvhNodeKind {
-    Leaf(Vec<EntityPositionPair>),
-    Branch(Box<BvhNode>, Box<BvhNode>),
+    /// Range of the entity array of the tree.
+    Leaf { first: u32, count: u32 },
+    /// Indices of the child nodes in the node array of the tree.
+    Branch { left: u32, right: u32 },
 }
 
 /// Node of the BVH tree.
 ///
 /// Each node contains an AABB (the chosen bounding volume),
-/// and either a list of entities or 2 child nodes.
+/// and either a range of entities or the indices of 2 child nodes.
 #[derive(Debug, Clone)]
 struct BvhNode {
     aabb: Aabb,
     kind: BvhNodeKind,
 }
 
-impl BvhNode {
-    /// Returns a list of entities that are in radius of the given sample point.
-    fn entities_in_radius(&self, sample_point: Vec3, radius: f32) -> Vec<Entity> {
-        if !self.intersects_sphere(sample_point, radius) {
-            return Vec::new();
+/// Flat storage of the BVH tree.
+///
+/// The root is the first node, and the nodes are stored in depth-first order, so children always
+/// come after their parent. The entities of each leaf node are contiguous, and the leaf nodes
+/// cover the entity array in the order of the nodes.
+#[derive(Debug)]
+struct BvhTree {
+    nodes: Vec<BvhNode>,
+    entities: Vec<EntityPositionExtent>,
+}
+
+impl BvhTree {
+    /// Returns the entities of a leaf node.
+    #[inline]
+    fn leaf_entities(&self, first: u32, count: u32) -> &[EntityPositionExtent] {
+        &self.entities[first as usize..(first + count) as usize]
+    }
+
+    /// Updates the positions and extents of the entities in the leaf nodes, removes entities
+    /// which are no longer present, and adds the new entities to the leaf nodes whose AABBs grow
+    /// the least by adding them.
+    ///
+    /// Node AABBs are not updated, they have to be recalculated with `refit_aabbs`.
+    fn update_entities(
+        &mut self,
+        mut new_entities: EntityHashMap<EntityPositionExtent>,
+        plane: Option<SpatialPlane>,
+    ) {
+        let mut entities = Vec::with_capacity(self.entities.len());
+        for node in &mut self.nodes {
+            if let BvhNodeKind::Leaf { first, count } = &mut node.kind {
+                let updated_first = entities.len();
+                entities.extend(
+                    self.entities[*first as usize..(*first + *count) as usize]
+                        .iter()
+                        .filter_map(|(entity, _position, _extent)| new_entities.remove(entity)),
+                );
+
+                *first = updated_first as u32;
+                *count = (entities.len() - updated_first) as u32;
+            }
+        }
+        self.entities = entities;
+
+        if new_entities.is_empty() {
+            return;
+        }
+
+        // entities which weren't in the tree yet are added to the leaf they grow the least
+        let mut inserted: Vec<(usize, EntityPositionExtent)> = new_entities
+            .into_values()
+            .map(|entity_position_extent| {
+                (
+                    self.leaf_for(&entity_position_extent, plane),
+                    entity_position_extent,
+                )
+            })
+            .collect();
+        inserted.sort_unstable_by_key(|(leaf, (entity, _position, _extent))| (*leaf, *entity));
+        let mut inserted = inserted.into_iter().peekable();
+
+        let mut entities = Vec::with_capacity(self.entities.len() + inserted.len());
+        for (index, node) in self.nodes.iter_mut().enumerate() {
+            if let BvhNodeKind::Leaf { first, count } = &mut node.kind {
+                let updated_first = entities.len();
+                entities
+                    .extend_from_slice(&self.entities[*first as usize..(*first + *count) as usize]);
+                while let Some((_leaf, entity_position_extent)) =
+                    inserted.next_if(|(leaf, _entity_position_extent)| *leaf == index)
+                {
+                    entities.push(entity_position_extent);
+                }
+
+                *first = updated_first as u32;
+                *count = (entities.len() - updated_first) as u32;
+            }
+        }
+        self.entities = entities;
+    }
+
+    /// Returns the index of the leaf node whose AABB grows the least by adding the entity.
+    fn leaf_for(
+        &self,
+        entity_position_extent: &EntityPositionExtent,
+        plane: Option<SpatialPlane>,
+    ) -> usize {
+        let entity_aabb = calculate_aabb(std::slice::from_ref(entity_position_extent));
+        let growth = |node: &BvhNode| {
+            node.aabb.union(&entity_aabb).surface_area(plane) - node.aabb.surface_area(plane)
+        };
+
+        let mut index = 0;
+        while let BvhNodeKind::Branch { left, right } = self.nodes[index].kind {
+            index = if growth(&self.nodes[left as usize]) <= growth(&self.nodes[right as usize]) {
+                left as usize
+            } else {
+                right as usize
+            };
         }
 
-        match &self.kind {
-            BvhNodeKind::Leaf(entity_position_pairs) => entity_position_pairs
-                .iter()
-                .filter_map(|(entity, position)| {
-                    if position.distance(sample_point) <= radius {
-                        Some(*entity)
-                    } else {
-                        None
+        index
+    }
+
+    /// Recalculates the AABBs of the nodes bottom-up, and returns the total surface area of the
+    /// nodes.
+    ///
+    /// Returns `None` if a leaf node is empty or has more than `max_entities_per_leaf` entities,
+    /// in which case the tree should be rebuilt.
+    fn refit_aabbs(
+        &mut self,
+        max_entities_per_leaf: usize,
+        plane: Option<SpatialPlane>,
+    ) -> Option<f32> {
+        let mut total_surface_area = 0.;
+
+        // children come after their parent, so iterating backwards visits the children first
+        for index in (0..self.nodes.len()).rev() {
+            let aabb = match self.nodes[index].kind {
+                BvhNodeKind::Leaf { first, count } => {
+                    if count == 0 || count as usize > max_entities_per_leaf {
+                        return None;
                     }
-                })
-                .collect(),
-            BvhNodeKind::Branch(left, right) => {
-                let mut total = left.entities_in_radius(sample_point, radius);
 
-                total.extend(right.entities_in_radius(sample_point, radius));
+                    calculate_aabb(self.leaf_entities(first, count))
+                }
+                BvhNodeKind::Branch { left, right } => self.nodes[left as usize]
+                    .aabb
+                    .union(&self.nodes[right as usize].aabb),
+            };
 
-                total
+            total_surface_area += aabb.surface_area(plane);
+            self.nodes[index].aabb = aabb;
+        }
+
+        Some(total_surface_area)
+    }
+
+    /// Returns the total surface area of the nodes.
+    fn total_surface_area(&self, plane: Option<SpatialPlane>) -> f32 {
+        self.nodes
+            .iter()
+            .map(|node| node.aabb.surface_area(plane))
+            .sum()
+    }
+
+    /// Calls `visit` for every entity in the leaf nodes reached by entering the nodes whose AABBs
+    /// pass `enter`.
+    fn for_each_entity_in(
+        &self,
+        enter: impl FnMut(&Aabb) -> bool,
+        mut visit: impl FnMut(&EntityPositionExtent),
+    ) {
+        let _ = self.try_for_each_entity_in(enter, |entity_position_extent| {
+            visit(entity_position_extent);
+            ControlFlow::Continue(())
+        });
+    }
+
+    /// Calls `visit` for every entity in the leaf nodes reached by entering the nodes whose AABBs
+    /// pass `enter`, until `visit` returns `ControlFlow::Break`.
+    fn try_for_each_entity_in(
+        &self,
+        mut enter: impl FnMut(&Aabb) -> bool,
+        mut visit: impl FnMut(&EntityPositionExtent) -> ControlFlow<()>,
+    ) -> ControlFlow<()> {
+        if self.nodes.is_empty() {
+            return ControlFlow::Continue(());
+        }
+
+        let mut stack = TraversalStack::with_root(0);
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index as usize];
+            if !enter(&node.aabb) {
+                continue;
+            }
+
+            match node.kind {
+                BvhNodeKind::Leaf { first, count } => {
+                    self.leaf_entities(first, count)
+                        .iter()
+                        .try_for_each(&mut visit)?;
+                }
+                BvhNodeKind::Branch { left, right } => {
+                    stack.push(right);
+                    stack.push(left);
+                }
             }
         }
+
+        ControlFlow::Continue(())
     }
 
-    /// Returns true if this node intersects given sphere.
-    #[inline]
-    fn intersects_sphere(&self, sample_point: Vec3, radius: f32) -> bool {
-        // implementation is based on Jim Arvo's algorithm from "Graphics Gems".
-        // http://web.archive.org/web/20100323053111/http://www.ics.uci.edu/~arvo/code/BoxSphereIntersect.c
-        let mut dmin = 0.;
+    /// Calls `visit` with each entity that is in radius of the given sample point, until `visit`
+    /// returns `ControlFlow::Break`.
+    fn visit_in_radius(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+        visit: &mut dyn FnMut(Entity, Vec3) -> ControlFlow<()>,
+    ) -> ControlFlow<()> {
+        self.try_for_each_entity_in(
+            |aabb| aabb.distance_squared_to_point(sample_point) <= radius.squared(),
+            |(entity, position, extent)| {
+                if extent.distance_squared_to_point(*position, sample_point) <= radius.squared() {
+                    visit(*entity, *position)?;
+                }
 
-        for axis in 0..3 {
-            if sample_point[axis] < self.aabb.min[axis] {
-                dmin += (sample_point[axis] - self.aabb.min[axis]).squared();
-            } else if sample_point[axis] > self.aabb.max[axis] {
-                dmin += (sample_point[axis] - self.aabb.max[axis]).squared();
+                ControlFlow::Continue(())
+            },
+        )
+    }
+
+    /// Returns a list of entities that are inside the given AABB.
+    fn entities_in_aabb(&self, aabb: &Aabb) -> Vec<Entity> {
+        let mut found = Vec::new();
+
+        self.for_each_entity_in(
+            |node_aabb| node_aabb.intersects_aabb(aabb),
+            |(entity, position, extent)| {
+                if extent.intersects_aabb(*position, aabb.min, aabb.max) {
+                    found.push(*entity);
+                }
+            },
+        );
+
+        found
+    }
+
+    /// Returns a list of entities that are inside the given shape.
+    ///
+    /// Nodes are culled using `SpatialShape::intersects_aabb`, and the remaining entities are
+    /// filtered with the exact containment test of the shape.
+    fn entities_in_shape(&self, shape: &dyn SpatialShape, isometry: Isometry3d) -> Vec<Entity> {
+        let mut found = Vec::new();
+
+        self.for_each_entity_in(
+            |node_aabb| {
+                let node_aabb = Aabb3d {
+                    min: node_aabb.min.into(),
+                    max: node_aabb.max.into(),
+                };
+                shape.intersects_aabb(isometry, &node_aabb)
+            },
+            |(entity, position, extent)| {
+                if extent.intersects_shape(*position, shape, isometry) {
+                    found.push(*entity);
+                }
+            },
+        );
+
+        found
+    }
+
+    /// Returns a list of entities within `thickness` of the ray, along with their distance along
+    /// the ray.
+    ///
+    /// Nodes are culled with a slab test against the node AABB grown by `thickness`.
+    fn entities_along_ray(
+        &self,
+        ray: Ray3d,
+        max_distance: f32,
+        thickness: f32,
+    ) -> Vec<(Entity, f32)> {
+        let mut found = Vec::new();
+
+        self.for_each_entity_in(
+            |node_aabb| {
+                let grown_aabb = Aabb {
+                    min: node_aabb.min - thickness,
+                    max: node_aabb.max + thickness,
+                };
+                grown_aabb.intersects_ray(ray, max_distance)
+            },
+            |(entity, position, extent)| {
+                if let Some(distance) =
+                    extent.distance_along_ray(*position, ray, max_distance, thickness)
+                {
+                    found.push((*entity, distance));
+                }
+            },
+        );
+
+        found
+    }
+
+    /// Returns up to `k` entities nearest to the sample point, ordered by ascending distance.
+    ///
+    /// The tree is traversed best-first: nodes are visited in order of their distance to the
+    /// sample point, and traversal stops once the closest unvisited node is further away than
+    /// the current `k`th nearest entity.
+    fn k_nearest(&self, sample_point: Vec3, k: usize, max_distance: f32) -> Vec<Entity> {
+        if k == 0 || self.nodes.is_empty() {
+            return Vec::new();
+        }
+
+        let mut nearest = KNearest::new(k, max_distance);
+        let mut nodes = BinaryHeap::new();
+        nodes.push(ByDistance {
+            distance_squared: self.distance_squared_to_node(&0, sample_point),
+            item: 0,
+        });
+
+        while let Some(ByDistance {
+            distance_squared,
+            item: index,
+        }) = nodes.pop()
+        {
+            if distance_squared > nearest.max_distance_squared() {
+                break;
+            }
+
+            match self.nodes[index as usize].kind {
+                BvhNodeKind::Leaf { first, count } => {
+                    for (entity, position, extent) in self.leaf_entities(first, count) {
+                        nearest.consider(
+                            *entity,
+                            extent.distance_squared_to_point(*position, sample_point),
+                        );
+                    }
+                }
+                BvhNodeKind::Branch { left, right } => {
+                    for child in [left, right] {
+                        let distance_squared = self.distance_squared_to_node(&child, sample_point);
+                        if distance_squared <= nearest.max_distance_squared() {
+                            nodes.push(ByDistance {
+                                distance_squared,
+                                item: child,
+                            });
+                        }
+                    }
+                }
             }
         }
 
-        dmin <= radius.squared()
+        nearest.into_sorted_vec()
     }
 
     fn count_depth(&self) -> usize {
-        match &self.kind {
-            BvhNodeKind::Leaf(_) => 1,
-            BvhNodeKind::Branch(left, right) => 1 + left.count_depth().max(right.count_depth()),
+        let mut max_depth = 0;
+
+        let mut stack = Vec::new();
+        if !self.nodes.is_empty() {
+            stack.push((0, 1));
         }
+        while let Some((index, depth)) = stack.pop() {
+            max_depth = max_depth.max(depth);
+
+            if let BvhNodeKind::Branch { left, right } = self.nodes[index as usize].kind {
+                stack.push((left, depth + 1));
+                stack.push((right, depth + 1));
+            }
+        }
+
+        max_depth
     }
 
-    fn draw_gizmos(&self, gizmos: &mut Gizmos, level: usize, max_depth: usize) {
-        let cuboid_centroid = self.aabb.min.midpoint(self.aabb.max);
-        let cuboid_scale = Vec3::new(
-            self.aabb.max.x - self.aabb.min.x,
-            self.aabb.max.y - self.aabb.min.y,
-            self.aabb.max.z - self.aabb.min.z,
-        );
+    fn draw_gizmos(&self, gizmos: &mut Gizmos, plane: Option<SpatialPlane>, max_depth: usize) {
+        let mut stack = Vec::new();
+        if !self.nodes.is_empty() {
+            stack.push((0, 0));
+        }
 
-        match &self.kind {
-            BvhNodeKind::Leaf(_) => {
-                gizmos.cuboid(
-                    Transform::from_translation(cuboid_centroid).with_scale(cuboid_scale),
-                    Color::hsv((level as f32) / (max_depth as f32) * 360., 0.8, 1.0),
-                );
+        while let Some((index, level)) = stack.pop() {
+            let node = &self.nodes[index as usize];
+
+            match node.kind {
+                BvhNodeKind::Leaf { .. } => {
+                    let cuboid_centroid = node.aabb.min.midpoint(node.aabb.max);
+                    let cuboid_scale = node.aabb.max - node.aabb.min;
+                    let color = Color::hsv((level as f32) / (max_depth as f32) * 360., 0.8, 1.0);
+
+                    match plane {
+                        Some(plane) => gizmos.rect(
+                            plane.gizmo_isometry(
+                                plane.project(cuboid_centroid),
+                                cuboid_centroid[plane.depth_axis()],
+                            ),
+                            plane.project(cuboid_scale),
+                            color,
+                        ),
+                        None => gizmos.cuboid(
+                            Transform::from_translation(cuboid_centroid).with_scale(cuboid_scale),
+                            color,
+                        ),
+                    }
+                }
+                BvhNodeKind::Branch { left, right } => {
+                    stack.push((left, level + 1));
+                    stack.push((right, level + 1));
+                }
+            }
+        }
+    }
+}
+
+impl NearestTree for BvhTree {
+    type Node = u32;
+
+    fn distance_squared_to_node(&self, index: &u32, sample_point: Vec3) -> f32 {
+        self.nodes[*index as usize]
+            .aabb
+            .distance_squared_to_point(sample_point)
+    }
+
+    fn open_node(&self, index: u32, candidates: &mut NearestCandidates<'_, Self>) {
+        match self.nodes[index as usize].kind {
+            BvhNodeKind::Leaf { first, count } => {
+                for (entity, position, extent) in self.leaf_entities(first, count) {
+                    candidates.push_entity(*entity, *position, *extent);
+                }
             }
-            BvhNodeKind::Branch(left, right) => {
-                left.draw_gizmos(gizmos, level + 1, max_depth);
-                right.draw_gizmos(gizmos, level + 1, max_depth);
+            BvhNodeKind::Branch { left, right } => {
+                candidates.push_node(left);
+                candidates.push_node(right);
             }
         }
     }
@@ -0,0 +1,365 @@
+//! Uniform spatial hash grid -accelerated spatial lookup
+
+use crate::SpatialLookupAlgorithm;
+use crate::spatial_extent::{SpatialExtent, with_extents};
+use crate::spatial_shape::SpatialShape;
+use bevy::ecs::entity::EntityHashMap;
+use bevy::math::FloatOrd;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use std::ops::ControlFlow;
+
+type EntityPositionExtent = (Entity, Vec3, SpatialExtent);
+
+/// Uniform spatial hash grid -based spatial acceleration algorithm.
+///
+/// The world is divided into cubic cells of `cell_size`, and each entity is stored in the cell
+/// containing its position. Only occupied cells are stored, so the grid is unbounded and its
+/// memory usage only depends on the number of entities.
+///
+/// Preparing the grid is O(n), and it also supports incremental updates, which makes it a good fit
+/// for large numbers of moving entities. Queries only visit the cells overlapping the query, so
+/// the grid works best when the cell size is close to the typical query radius. Much smaller
+/// cells result in many cells being visited per query, and much larger cells result in many
+/// entities being filtered out in every visited cell.
+///
+/// Entities with a `SpatialExtent` are stored in the cell of their position, and queries are
+/// grown by the largest extent in the grid to find them. A few very large entities therefore make
+/// all queries slower. When the largest entities are removed, queries shrink again from the next
+/// `begin_frame`.
+#[derive(Debug)]
+pub struct HashGrid {
+    /// Length of the edges of each cell. Changing this takes effect on the next `prepare`.
+    pub cell_size: f32,
+    cells: HashMap<IVec3, Vec<EntityPositionExtent>>,
+    /// Number of entities in `cells`.
+    len: usize,
+    /// Cell of each entity, only kept up to date by incremental updates.
+    entity_cells: EntityHashMap<IVec3>,
+    /// Largest half-size of the extents of the entities in the grid.
+    max_half_size: Vec3,
+    /// Number of entities whose half-size is `max_half_size` along each axis. When this drops to
+    /// zero, `max_half_size` is recalculated by the next `begin_frame`.
+    max_half_size_counts: UVec3,
+    /// Cell size used when the grid was last prepared.
+    prepared_cell_size: f32,
+}
+
+impl Default for HashGrid {
+    fn default() -> Self {
+        HashGrid::with_cell_size(1.0)
+    }
+}
+
+impl HashGrid {
+    /// Creates an empty grid with the given cell size.
+    pub fn with_cell_size(cell_size: f32) -> Self {
+        assert!(cell_size > 0.0, "cell size must be positive");
+
+        HashGrid {
+            cell_size,
+            cells: HashMap::default(),
+            len: 0,
+            entity_cells: EntityHashMap::default(),
+            max_half_size: Vec3::ZERO,
+            max_half_size_counts: UVec3::ZERO,
+            prepared_cell_size: cell_size,
+        }
+    }
+
+    /// Returns the coordinates of the cell containing the given point.
+    #[inline]
+    fn cell_of(&self, point: Vec3) -> IVec3 {
+        (point / self.prepared_cell_size).floor().as_ivec3()
+    }
+
+    /// Makes sure `entity_cells` matches `cells` before an incremental update.
+    fn prepare_incremental_update(&mut self) {
+        if self.entity_cells.len() != self.len {
+            self.entity_cells = self
+                .cells
+                .iter()
+                .flat_map(|(cell, entities)| {
+                    entities
+                        .iter()
+                        .map(|(entity, _position, _extent)| (*entity, *cell))
+                })
+                .collect();
+        }
+    }
+
+    /// Counts an entity with the given half-size towards `max_half_size`.
+    fn add_half_size(&mut self, half_size: Vec3) {
+        for axis in 0..3 {
+            if half_size[axis] > self.max_half_size[axis] {
+                self.max_half_size[axis] = half_size[axis];
+                self.max_half_size_counts[axis] = 1;
+            } else if half_size[axis] == self.max_half_size[axis] {
+                self.max_half_size_counts[axis] += 1;
+            }
+        }
+    }
+
+    /// Stops counting an entity with the given half-size towards `max_half_size`.
+    fn remove_half_size(&mut self, half_size: Vec3) {
+        for axis in 0..3 {
+            if half_size[axis] == self.max_half_size[axis] {
+                self.max_half_size_counts[axis] = self.max_half_size_counts[axis].saturating_sub(1);
+            }
+        }
+    }
+
+    /// Recalculates `max_half_size` from the entities in the grid.
+    fn recalculate_max_half_size(&mut self) {
+        self.max_half_size = Vec3::ZERO;
+        self.max_half_size_counts = UVec3::ZERO;
+
+        let cells = std::mem::take(&mut self.cells);
+        for (_entity, _position, extent) in cells.values().flatten() {
+            self.add_half_size(extent.half_size());
+        }
+        self.cells = cells;
+    }
+
+    /// Calls `visit` for each occupied cell which may contain entities overlapping the
+    /// axis-aligned box spanned by `min` and `max`.
+    fn for_each_cell_in(
+        &self,
+        min: Vec3,
+        max: Vec3,
+        mut visit: impl FnMut(&[EntityPositionExtent]),
+    ) {
+        let _ = self.try_for_each_cell_in(min, max, |entities| {
+            visit(entities);
+            ControlFlow::Continue(())
+        });
+    }
+
+    /// Calls `visit` for each occupied cell which may contain entities overlapping the
+    /// axis-aligned box spanned by `min` and `max`, until `visit` returns `ControlFlow::Break`.
+    ///
+    /// If the box covers more cells than there are occupied cells, the occupied cells are
+    /// iterated instead, which keeps huge or unbounded queries from visiting empty cells.
+    fn try_for_each_cell_in(
+        &self,
+        min: Vec3,
+        max: Vec3,
+        mut visit: impl FnMut(&[EntityPositionExtent]) -> ControlFlow<()>,
+    ) -> ControlFlow<()> {
+        // entities are stored in the cell of their position, but their volume may reach further
+        let min_cell = self.cell_of(min - self.max_half_size);
+        let max_cell = self.cell_of(max + self.max_half_size);
+
+        let covered_cells = (max_cell.as_dvec3() - min_cell.as_dvec3() + 1.0).element_product();
+        if covered_cells > self.cells.len() as f64 {
+            for (cell, entities) in &self.cells {
+                if cell.cmpge(min_cell).all() && cell.cmple(max_cell).all() {
+                    visit(entities)?;
+                }
+            }
+            return ControlFlow::Continue(());
+        }
+
+        for x in min_cell.x..=max_cell.x {
+            for y in min_cell.y..=max_cell.y {
+                for z in min_cell.z..=max_cell.z {
+                    if let Some(entities) = self.cells.get(&IVec3::new(x, y, z)) {
+                        visit(entities)?;
+                    }
+                }
+            }
+        }
+
+        ControlFlow::Continue(())
+    }
+}
+
+impl SpatialLookupAlgorithm for HashGrid {
+    fn prepare(&mut self, entities: &[(Entity, Vec3)]) {
+        self.prepare_with_extents(entities, &[]);
+    }
+
+    fn prepare_with_extents(&mut self, entities: &[(Entity, Vec3)], extents: &[SpatialExtent]) {
+        self.prepared_cell_size = self.cell_size;
+        self.cells.clear();
+        self.len = entities.len();
+        self.entity_cells.clear();
+        self.max_half_size = Vec3::ZERO;
+        self.max_half_size_counts = UVec3::ZERO;
+
+        for (entity, position, extent) in with_extents(entities, extents) {
+            self.add_half_size(extent.half_size());
+            self.cells
+                .entry(self.cell_of(position))
+                .or_default()
+                .push((entity, position, extent));
+        }
+    }
+
+    fn insert(&mut self, entity: Entity, position: Vec3, extent: SpatialExtent) -> bool {
+        self.prepare_incremental_update();
+
+        let cell = self.cell_of(position);
+        self.add_half_size(extent.half_size());
+        self.cells
+            .entry(cell)
+            .or_default()
+            .push((entity, position, extent));
+        self.entity_cells.insert(entity, cell);
+        self.len += 1;
+
+        true
+    }
+
+    fn update(&mut self, entity: Entity, position: Vec3, extent: SpatialExtent) -> bool {
+        self.remove(entity);
+        self.insert(entity, position, extent);
+
+        true
+    }
+
+    fn remove(&mut self, entity: Entity) -> bool {
+        self.prepare_incremental_update();
+
+        let Some(cell) = self.entity_cells.remove(&entity) else {
+            return true;
+        };
+        self.len -= 1;
+
+        if let Some(entities) = self.cells.get_mut(&cell)
+            && let Some(index) = entities
+                .iter()
+                .position(|(cell_entity, _position, _extent)| *cell_entity == entity)
+        {
+            let (_entity, _position, extent) = entities.swap_remove(index);
+            if entities.is_empty() {
+                self.cells.remove(&cell);
+            }
+
+            self.remove_half_size(extent.half_size());
+        }
+
+        true
+    }
+
+    fn begin_frame(&mut self) -> bool {
+        // the largest entities were removed, so queries no longer have to be grown as much
+        if self.max_half_size_counts.cmpeq(UVec3::ZERO).any() && self.len > 0 {
+            self.recalculate_max_half_size();
+        }
+
+        true
+    }
+
+    fn entities_in_radius(&self, sample_point: Vec3, radius: f32) -> Vec<Entity> {
+        let mut found_entities = Vec::new();
+
+        let _ = self.visit_in_radius(sample_point, radius, &mut |entity, _position| {
+            found_entities.push(entity);
+            ControlFlow::Continue(())
+        });
+
+        found_entities
+    }
+
+    fn visit_in_radius(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+        visit: &mut dyn FnMut(Entity, Vec3) -> ControlFlow<()>,
+    ) -> Option<ControlFlow<()>> {
+        Some(
+            self.try_for_each_cell_in(sample_point - radius, sample_point + radius, |entities| {
+                for (entity, position, extent) in entities {
+                    if extent.distance_squared_to_point(*position, sample_point) <= radius * radius
+                    {
+                        visit(*entity, *position)?;
+                    }
+                }
+
+                ControlFlow::Continue(())
+            }),
+        )
+    }
+
+    fn entities_in_aabb(&self, min: Vec3, max: Vec3) -> Option<Vec<Entity>> {
+        let mut found_entities = Vec::new();
+
+        self.for_each_cell_in(min, max, |entities| {
+            for (entity, position, extent) in entities {
+                if extent.intersects_aabb(*position, min, max) {
+                    found_entities.push(*entity);
+                }
+            }
+        });
+
+        Some(found_entities)
+    }
+
+    fn entities_in_shape(
+        &self,
+        shape: &dyn SpatialShape,
+        isometry: Isometry3d,
+    ) -> Option<Vec<Entity>> {
+        let mut found_entities = Vec::new();
+        let bounding_aabb = shape.bounding_aabb(isometry);
+
+        self.for_each_cell_in(
+            bounding_aabb.min.into(),
+            bounding_aabb.max.into(),
+            |entities| {
+                for (entity, position, extent) in entities {
+                    if extent.intersects_shape(*position, shape, isometry) {
+                        found_entities.push(*entity);
+                    }
+                }
+            },
+        );
+
+        Some(found_entities)
+    }
+
+    fn entities_along_ray(
+        &self,
+        ray: Ray3d,
+        max_distance: f32,
+        thickness: f32,
+    ) -> Option<Vec<(Entity, f32)>> {
+        let mut found_entities = Vec::new();
+        // infinite rays are clamped to f32::MAX, because 0 * infinity would result in NaN
+        let end = ray.get_point(max_distance.min(f32::MAX));
+
+        self.for_each_cell_in(
+            ray.origin.min(end) - thickness,
+            ray.origin.max(end) + thickness,
+            |entities| {
+                for (entity, position, extent) in entities {
+                    if let Some(distance) =
+                        extent.distance_along_ray(*position, ray, max_distance, thickness)
+                    {
+                        found_entities.push((*entity, distance));
+                    }
+                }
+            },
+        );
+
+        found_entities.sort_unstable_by_key(|(_entity, distance)| FloatOrd(*distance));
+
+        Some(found_entities)
+    }
+
+    fn debug_gizmos(&self, gizmos: &mut Gizmos) {
+        let max_entities_per_cell = self.cells.values().map(Vec::len).max().unwrap_or(1);
+
+        for (cell, entities) in &self.cells {
+            let cell_center = (cell.as_vec3() + 0.5) * self.prepared_cell_size;
+            let occupancy = entities.len() as f32 / max_entities_per_cell as f32;
+
+            gizmos.cuboid(
+                Transform::from_translation(cell_center)
+                    .with_scale(Vec3::splat(self.prepared_cell_size)),
+                Color::hsv((1.0 - occupancy) * 240., 0.8, 1.0),
+            );
+        }
+    }
+}
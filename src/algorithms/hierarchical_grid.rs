@@ -0,0 +1,408 @@
+//! Hierarchical spatial hash grid -accelerated spatial lookup
+
+use crate::SpatialLookupAlgorithm;
+use crate::spatial_extent::{SpatialExtent, with_extents};
+use crate::spatial_shape::SpatialShape;
+use bevy::ecs::entity::EntityHashMap;
+use bevy::math::FloatOrd;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use std::ops::ControlFlow;
+
+type EntityPositionExtent = (Entity, Vec3, SpatialExtent);
+
+/// Number of levels after which all larger entities are placed in the last level.
+const MAX_LEVELS: usize = 32;
+
+/// Hierarchical spatial hash grid -based spatial acceleration algorithm.
+///
+/// The grid consists of levels of uniform hash grids, where the cells of the first level have
+/// edges of `base_cell_size` and the cells of each following level are twice as large. Each
+/// entity is stored in the level with the smallest cells which are at least as large as its
+/// volume, in the cell containing its position. Points and small entities are therefore stored
+/// in the small cells of the first level, and each huge entity in a level of its own with only a
+/// few cells.
+///
+/// Queries visit the cells overlapping the query on every level which contains entities. Because
+/// the entities of each level are at most as large as its cells, the queries only have to be
+/// grown by the size of the largest entity on that level, so a few huge entities don't make the
+/// queries for the small entities slower like they do with `HashGrid`.
+///
+/// The grid supports incremental updates, and finding every pair of overlapping entities with
+/// `SpatialLookupState::overlapping_pairs`, which only tests each entity against the entities in
+/// the neighbouring cells of its own level and the levels above it.
+#[derive(Debug)]
+pub struct HierarchicalGrid {
+    /// Length of the edges of the cells of the first level. Changing this takes effect on the next
+    /// `prepare`.
+    pub base_cell_size: f32,
+    levels: Vec<GridLevel>,
+    /// Number of entities in `levels`.
+    len: usize,
+    /// Level and cell of each entity, only kept up to date by incremental updates.
+    entity_cells: EntityHashMap<(usize, IVec3)>,
+    /// Base cell size used when the grid was last prepared.
+    prepared_base_cell_size: f32,
+}
+
+/// Single level of a `HierarchicalGrid`.
+#[derive(Debug, Default)]
+struct GridLevel {
+    cells: HashMap<IVec3, Vec<EntityPositionExtent>>,
+    /// Largest half-size of the extents of the entities in this level.
+    max_half_size: Vec3,
+}
+
+impl Default for HierarchicalGrid {
+    fn default() -> Self {
+        HierarchicalGrid::with_base_cell_size(1.0)
+    }
+}
+
+impl HierarchicalGrid {
+    /// Creates an empty grid whose first level has cells of the given size.
+    pub fn with_base_cell_size(base_cell_size: f32) -> Self {
+        assert!(base_cell_size > 0.0, "cell size must be positive");
+
+        HierarchicalGrid {
+            base_cell_size,
+            levels: Vec::new(),
+            len: 0,
+            entity_cells: EntityHashMap::default(),
+            prepared_base_cell_size: base_cell_size,
+        }
+    }
+
+    /// Returns the length of the edges of the cells of the given level.
+    #[inline]
+    fn cell_size(&self, level: usize) -> f32 {
+        self.prepared_base_cell_size * (1_u64 << level) as f32
+    }
+
+    /// Returns the level with the smallest cells which fit the given volume.
+    #[inline]
+    fn level_of(&self, extent: &SpatialExtent) -> usize {
+        let size = extent.half_size().max_element() * 2.;
+        if size <= self.prepared_base_cell_size {
+            return 0;
+        }
+
+        ((size / self.prepared_base_cell_size).log2().ceil() as usize).min(MAX_LEVELS - 1)
+    }
+
+    /// Returns the coordinates of the cell containing the given point on the given level.
+    #[inline]
+    fn cell_of(&self, level: usize, point: Vec3) -> IVec3 {
+        (point / self.cell_size(level)).floor().as_ivec3()
+    }
+
+    /// Makes sure `entity_cells` matches `levels` before an incremental update.
+    fn prepare_incremental_update(&mut self) {
+        if self.entity_cells.len() != self.len {
+            self.entity_cells = self
+                .levels
+                .iter()
+                .enumerate()
+                .flat_map(|(level, grid_level)| {
+                    grid_level.cells.iter().flat_map(move |(cell, entities)| {
+                        entities
+                            .iter()
+                            .map(move |(entity, _position, _extent)| (*entity, (level, *cell)))
+                    })
+                })
+                .collect();
+        }
+    }
+
+    /// Adds an entity to the cell of its position on the level matching its volume.
+    fn insert_entity(&mut self, entity_position_extent: EntityPositionExtent) -> (usize, IVec3) {
+        let (_entity, position, extent) = entity_position_extent;
+        let level = self.level_of(&extent);
+        let cell = self.cell_of(level, position);
+
+        if self.levels.len() <= level {
+            self.levels.resize_with(level + 1, GridLevel::default);
+        }
+        let grid_level = &mut self.levels[level];
+        grid_level.max_half_size = grid_level.max_half_size.max(extent.half_size());
+        grid_level
+            .cells
+            .entry(cell)
+            .or_default()
+            .push(entity_position_extent);
+        self.len += 1;
+
+        (level, cell)
+    }
+
+    /// Calls `visit` for each occupied cell on `first_level` and the levels after it which may
+    /// contain entities overlapping the axis-aligned box spanned by `min` and `max`.
+    fn for_each_cell_in(
+        &self,
+        first_level: usize,
+        min: Vec3,
+        max: Vec3,
+        mut visit: impl FnMut(&[EntityPositionExtent]),
+    ) {
+        let _ = self.try_for_each_cell_in(first_level, min, max, |entities| {
+            visit(entities);
+            ControlFlow::Continue(())
+        });
+    }
+
+    /// Calls `visit` for each occupied cell on `first_level` and the levels after it which may
+    /// contain entities overlapping the axis-aligned box spanned by `min` and `max`, until `visit`
+    /// returns `ControlFlow::Break`.
+    ///
+    /// If the box covers more cells of a level than there are occupied cells on it, the occupied
+    /// cells are iterated instead, which keeps huge or unbounded queries from visiting empty
+    /// cells.
+    fn try_for_each_cell_in(
+        &self,
+        first_level: usize,
+        min: Vec3,
+        max: Vec3,
+        mut visit: impl FnMut(&[EntityPositionExtent]) -> ControlFlow<()>,
+    ) -> ControlFlow<()> {
+        for (level, grid_level) in self.levels.iter().enumerate().skip(first_level) {
+            if grid_level.cells.is_empty() {
+                continue;
+            }
+
+            // entities are stored in the cell of their position, but their volume may reach
+            // further
+            let min_cell = self.cell_of(level, min - grid_level.max_half_size);
+            let max_cell = self.cell_of(level, max + grid_level.max_half_size);
+
+            let covered_cells = (max_cell.as_dvec3() - min_cell.as_dvec3() + 1.0).element_product();
+            if covered_cells > grid_level.cells.len() as f64 {
+                for (cell, entities) in &grid_level.cells {
+                    if cell.cmpge(min_cell).all() && cell.cmple(max_cell).all() {
+                        visit(entities)?;
+                    }
+                }
+                continue;
+            }
+
+            for x in min_cell.x..=max_cell.x {
+                for y in min_cell.y..=max_cell.y {
+                    for z in min_cell.z..=max_cell.z {
+                        if let Some(entities) = grid_level.cells.get(&IVec3::new(x, y, z)) {
+                            visit(entities)?;
+                        }
+                    }
+                }
+            }
+        }
+
+        ControlFlow::Continue(())
+    }
+}
+
+impl SpatialLookupAlgorithm for HierarchicalGrid {
+    fn prepare(&mut self, entities: &[(Entity, Vec3)]) {
+        self.prepare_with_extents(entities, &[]);
+    }
+
+    fn prepare_with_extents(&mut self, entities: &[(Entity, Vec3)], extents: &[SpatialExtent]) {
+        self.prepared_base_cell_size = self.base_cell_size;
+        self.levels.clear();
+        self.len = 0;
+        self.entity_cells.clear();
+
+        for entity_position_extent in with_extents(entities, extents) {
+            self.insert_entity(entity_position_extent);
+        }
+    }
+
+    fn insert(&mut self, entity: Entity, position: Vec3, extent: SpatialExtent) -> bool {
+        self.prepare_incremental_update();
+
+        let level_and_cell = self.insert_entity((entity, position, extent));
+        self.entity_cells.insert(entity, level_and_cell);
+
+        true
+    }
+
+    fn update(&mut self, entity: Entity, position: Vec3, extent: SpatialExtent) -> bool {
+        self.remove(entity);
+        self.insert(entity, position, extent);
+
+        true
+    }
+
+    fn remove(&mut self, entity: Entity) -> bool {
+        self.prepare_incremental_update();
+
+        let Some((level, cell)) = self.entity_cells.remove(&entity) else {
+            return true;
+        };
+        self.len -= 1;
+
+        let cells = &mut self.levels[level].cells;
+        if let Some(entities) = cells.get_mut(&cell) {
+            entities.retain(|(cell_entity, _position, _extent)| *cell_entity != entity);
+
+            if entities.is_empty() {
+                cells.remove(&cell);
+            }
+        }
+
+        true
+    }
+
+    fn entities_in_radius(&self, sample_point: Vec3, radius: f32) -> Vec<Entity> {
+        let mut found_entities = Vec::new();
+
+        let _ = self.visit_in_radius(sample_point, radius, &mut |entity, _position| {
+            found_entities.push(entity);
+            ControlFlow::Continue(())
+        });
+
+        found_entities
+    }
+
+    fn visit_in_radius(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+        visit: &mut dyn FnMut(Entity, Vec3) -> ControlFlow<()>,
+    ) -> Option<ControlFlow<()>> {
+        Some(self.try_for_each_cell_in(
+            0,
+            sample_point - radius,
+            sample_point + radius,
+            |entities| {
+                for (entity, position, extent) in entities {
+                    if extent.distance_squared_to_point(*position, sample_point) <= radius * radius
+                    {
+                        visit(*entity, *position)?;
+                    }
+                }
+
+                ControlFlow::Continue(())
+            },
+        ))
+    }
+
+    fn entities_in_aabb(&self, min: Vec3, max: Vec3) -> Option<Vec<Entity>> {
+        let mut found_entities = Vec::new();
+
+        self.for_each_cell_in(0, min, max, |entities| {
+            for (entity, position, extent) in entities {
+                if extent.intersects_aabb(*position, min, max) {
+                    found_entities.push(*entity);
+                }
+            }
+        });
+
+        Some(found_entities)
+    }
+
+    fn entities_in_shape(
+        &self,
+        shape: &dyn SpatialShape,
+        isometry: Isometry3d,
+    ) -> Option<Vec<Entity>> {
+        let mut found_entities = Vec::new();
+        let bounding_aabb = shape.bounding_aabb(isometry);
+
+        self.for_each_cell_in(
+            0,
+            bounding_aabb.min.into(),
+            bounding_aabb.max.into(),
+            |entities| {
+                for (entity, position, extent) in entities {
+                    if extent.intersects_shape(*position, shape, isometry) {
+                        found_entities.push(*entity);
+                    }
+                }
+            },
+        );
+
+        Some(found_entities)
+    }
+
+    fn entities_along_ray(
+        &self,
+        ray: Ray3d,
+        max_distance: f32,
+        thickness: f32,
+    ) -> Option<Vec<(Entity, f32)>> {
+        let mut found_entities = Vec::new();
+        // infinite rays are clamped to f32::MAX, because 0 * infinity would result in NaN
+        let end = ray.get_point(max_distance.min(f32::MAX));
+
+        self.for_each_cell_in(
+            0,
+            ray.origin.min(end) - thickness,
+            ray.origin.max(end) + thickness,
+            |entities| {
+                for (entity, position, extent) in entities {
+                    if let Some(distance) =
+                        extent.distance_along_ray(*position, ray, max_distance, thickness)
+                    {
+                        found_entities.push((*entity, distance));
+                    }
+                }
+            },
+        );
+
+        found_entities.sort_unstable_by_key(|(_entity, distance)| FloatOrd(*distance));
+
+        Some(found_entities)
+    }
+
+    fn overlapping_pairs(&self) -> Option<Vec<(Entity, Entity)>> {
+        let mut pairs = Vec::new();
+
+        // each entity looks for overlaps on its own level and the levels with larger entities, so
+        // pairs on different levels are found once by the smaller entity, and pairs on the same
+        // level are only reported by the smaller entity id
+        for (level, grid_level) in self.levels.iter().enumerate() {
+            for entities in grid_level.cells.values() {
+                for (entity, position, extent) in entities {
+                    let half_size = extent.half_size();
+
+                    self.for_each_cell_in(
+                        level,
+                        *position - half_size,
+                        *position + half_size,
+                        |others| {
+                            for (other_entity, other_position, other_extent) in others {
+                                if self.level_of(other_extent) == level && other_entity <= entity {
+                                    continue;
+                                }
+
+                                if extent.intersects_extent(
+                                    *position,
+                                    other_extent,
+                                    *other_position,
+                                ) {
+                                    pairs.push((*entity, *other_entity));
+                                }
+                            }
+                        },
+                    );
+                }
+            }
+        }
+
+        Some(pairs)
+    }
+
+    fn debug_gizmos(&self, gizmos: &mut Gizmos) {
+        for (level, grid_level) in self.levels.iter().enumerate() {
+            let cell_size = self.cell_size(level);
+            let color = Color::hsv((level as f32) / (self.levels.len() as f32) * 360., 0.8, 1.0);
+
+            for cell in grid_level.cells.keys() {
+                gizmos.cuboid(
+                    Transform::from_translation((cell.as_vec3() + 0.5) * cell_size)
+                        .with_scale(Vec3::splat(cell_size)),
+                    color,
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,461 @@
+//! k-d tree -accelerated spatial lookup
+
+use crate::SpatialLookupAlgorithm;
+use crate::algorithms::nearest::{KNearest, NearestCandidates, NearestIter, NearestTree};
+use crate::algorithms::traversal_stack::TraversalStack;
+use crate::spatial_extent::{SpatialExtent, with_extents};
+use crate::spatial_shape::SpatialShape;
+use bevy::math::bounding::{Aabb3d, BoundingVolume, RayCast3d};
+use bevy::math::{FloatOrd, FloatPow};
+use bevy::prelude::*;
+use bevy::tasks::{ComputeTaskPool, TaskPool};
+use std::ops::ControlFlow;
+
+type EntityPositionExtent = (Entity, Vec3, SpatialExtent);
+
+/// Subtrees with fewer entities than this are built on the calling thread, because spawning tasks
+/// for them costs more than building them.
+const PARALLEL_BUILD_THRESHOLD: usize = 4096;
+
+/// k-d tree -based spatial acceleration algorithm, optimized for nearest-neighbour queries.
+///
+/// The tree is built by recursively splitting the entities at their median along the longest axis
+/// of the cell being split, with both halves built in parallel on the `ComputeTaskPool`. Splitting
+/// at the median keeps the tree perfectly balanced, so the tree is stored without any pointers:
+/// each subtree is a contiguous range of a flat array, with the splitting entity in the middle of
+/// the range, the left subtree before it and the right subtree after it. Ranges of at most
+/// `entities_per_leaf` entities are not split any further.
+///
+/// `k_nearest` and `nearest_iter` visit the cell closest to the sample point first and skip every
+/// cell further away than the entities found so far, which makes them much faster than with the
+/// SAH-based [`Bvh`](super::Bvh), whose nodes are optimized for volume queries instead.
+///
+/// Entities with a `SpatialExtent` are stored by their position, and cells are grown by the
+/// largest extent in the tree when checking them against queries. A few very large entities
+/// therefore make all queries slower.
+///
+/// The tree doesn't support incremental updates, and is rebuilt from scratch by every `prepare`.
+#[derive(Debug)]
+pub struct KdTree {
+    /// Maximum number of entities in a range which isn't split any further.
+    pub entities_per_leaf: usize,
+    /// `entities_per_leaf` used when the tree was last prepared.
+    prepared_entities_per_leaf: usize,
+    /// Entities in tree order.
+    entities: Vec<EntityPositionExtent>,
+    /// Axis which each range is split along, stored at the index of its splitting entity.
+    split_axes: Vec<u8>,
+    /// Bounding box of the positions of all entities, i.e. the cell of the root.
+    bounds: Aabb3d,
+    /// Largest half-size of the extents of the entities in the tree.
+    max_half_size: Vec3,
+}
+
+impl Default for KdTree {
+    fn default() -> Self {
+        KdTree {
+            entities_per_leaf: 8,
+            prepared_entities_per_leaf: 8,
+            entities: Vec::new(),
+            split_axes: Vec::new(),
+            bounds: Aabb3d::new(Vec3::ZERO, Vec3::ZERO),
+            max_half_size: Vec3::ZERO,
+        }
+    }
+}
+
+impl KdTree {
+    /// Returns the root of the tree.
+    #[inline]
+    fn root(&self) -> KdTreeRange {
+        KdTreeRange {
+            start: 0,
+            end: self.entities.len(),
+            cell: self.bounds,
+        }
+    }
+
+    /// Returns the cell of the range, grown by the largest extent in the tree so that it contains
+    /// the whole volume of each entity in the range.
+    #[inline]
+    fn grown_cell(&self, range: &KdTreeRange) -> Aabb3d {
+        range.cell.grow(self.max_half_size)
+    }
+
+    /// Returns the splitting entity and the two child ranges of the range, or `None` if the range
+    /// isn't split.
+    #[inline]
+    fn split(
+        &self,
+        range: &KdTreeRange,
+    ) -> Option<(&EntityPositionExtent, KdTreeRange, KdTreeRange)> {
+        let len = range.end - range.start;
+        if len <= self.prepared_entities_per_leaf {
+            return None;
+        }
+
+        let mid = range.start + len / 2;
+        let axis = self.split_axes[mid] as usize;
+        let pivot = &self.entities[mid];
+
+        let mut left_cell = range.cell;
+        left_cell.max[axis] = pivot.1[axis];
+        let mut right_cell = range.cell;
+        right_cell.min[axis] = pivot.1[axis];
+
+        Some((
+            pivot,
+            KdTreeRange {
+                start: range.start,
+                end: mid,
+                cell: left_cell,
+            },
+            KdTreeRange {
+                start: mid + 1,
+                end: range.end,
+                cell: right_cell,
+            },
+        ))
+    }
+
+    /// Calls `visit` for every entity in the ranges whose grown cell passes `enter`.
+    fn for_each_entity_in(
+        &self,
+        enter: impl FnMut(&Aabb3d) -> bool,
+        mut visit: impl FnMut(&EntityPositionExtent),
+    ) {
+        let _ = self.try_for_each_entity_in(enter, |entity_position_extent| {
+            visit(entity_position_extent);
+            ControlFlow::Continue(())
+        });
+    }
+
+    /// Calls `visit` for every entity in the ranges whose grown cell passes `enter`, until `visit`
+    /// returns `ControlFlow::Break`.
+    fn try_for_each_entity_in(
+        &self,
+        mut enter: impl FnMut(&Aabb3d) -> bool,
+        mut visit: impl FnMut(&EntityPositionExtent) -> ControlFlow<()>,
+    ) -> ControlFlow<()> {
+        if self.entities.is_empty() {
+            return ControlFlow::Continue(());
+        }
+
+        let mut ranges = TraversalStack::with_root(self.root());
+        while let Some(range) = ranges.pop() {
+            if !enter(&self.grown_cell(&range)) {
+                continue;
+            }
+
+            match self.split(&range) {
+                Some((pivot, left, right)) => {
+                    visit(pivot)?;
+                    ranges.push(left);
+                    ranges.push(right);
+                }
+                None => self.entities[range.start..range.end]
+                    .iter()
+                    .try_for_each(&mut visit)?,
+            }
+        }
+
+        ControlFlow::Continue(())
+    }
+
+    /// Finds up to `k` entities nearest to the sample point in the range, visiting the child
+    /// range closer to the sample point first.
+    fn k_nearest_in(&self, range: &KdTreeRange, sample_point: Vec3, nearest: &mut KNearest) {
+        if self.distance_squared_to_node(range, sample_point) > nearest.max_distance_squared() {
+            return;
+        }
+
+        let mut consider = |(entity, position, extent): &EntityPositionExtent| {
+            nearest.consider(
+                *entity,
+                extent.distance_squared_to_point(*position, sample_point),
+            );
+        };
+
+        match self.split(range) {
+            Some((pivot, left, right)) => {
+                consider(pivot);
+
+                let axis = self.split_axes[left.end] as usize;
+                let (near, far) = if sample_point[axis] < pivot.1[axis] {
+                    (left, right)
+                } else {
+                    (right, left)
+                };
+
+                self.k_nearest_in(&near, sample_point, nearest);
+                self.k_nearest_in(&far, sample_point, nearest);
+            }
+            None => self.entities[range.start..range.end]
+                .iter()
+                .for_each(consider),
+        }
+    }
+}
+
+impl SpatialLookupAlgorithm for KdTree {
+    fn prepare(&mut self, entities: &[(Entity, Vec3)]) {
+        self.prepare_with_extents(entities, &[]);
+    }
+
+    fn prepare_with_extents(&mut self, entities: &[(Entity, Vec3)], extents: &[SpatialExtent]) {
+        self.prepared_entities_per_leaf = self.entities_per_leaf.max(1);
+        self.entities.clear();
+        self.entities.extend(with_extents(entities, extents));
+        self.split_axes.clear();
+        self.split_axes.resize(self.entities.len(), 0);
+
+        let mut min = Vec3::INFINITY;
+        let mut max = Vec3::NEG_INFINITY;
+        self.max_half_size = Vec3::ZERO;
+        for (_entity, position, extent) in &self.entities {
+            min = min.min(*position);
+            max = max.max(*position);
+            self.max_half_size = self.max_half_size.max(extent.half_size());
+        }
+
+        if self.entities.is_empty() {
+            return;
+        }
+
+        self.bounds = Aabb3d {
+            min: min.into(),
+            max: max.into(),
+        };
+
+        build(
+            &mut self.entities,
+            &mut self.split_axes,
+            self.bounds,
+            self.prepared_entities_per_leaf,
+            ComputeTaskPool::get_or_init(TaskPool::default),
+        );
+    }
+
+    fn entities_in_radius(&self, sample_point: Vec3, radius: f32) -> Vec<Entity> {
+        let mut found_entities = Vec::new();
+
+        let _ = self.visit_in_radius(sample_point, radius, &mut |entity, _position| {
+            found_entities.push(entity);
+            ControlFlow::Continue(())
+        });
+
+        found_entities
+    }
+
+    fn visit_in_radius(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+        visit: &mut dyn FnMut(Entity, Vec3) -> ControlFlow<()>,
+    ) -> Option<ControlFlow<()>> {
+        Some(self.try_for_each_entity_in(
+            |cell| {
+                let closest_point: Vec3 = cell.closest_point(sample_point).into();
+                closest_point.distance_squared(sample_point) <= radius.squared()
+            },
+            |(entity, position, extent)| {
+                if extent.distance_squared_to_point(*position, sample_point) <= radius.squared() {
+                    visit(*entity, *position)?;
+                }
+
+                ControlFlow::Continue(())
+            },
+        ))
+    }
+
+    fn entities_in_aabb(&self, min: Vec3, max: Vec3) -> Option<Vec<Entity>> {
+        let mut found_entities = Vec::new();
+
+        self.for_each_entity_in(
+            |cell| cell.min.cmple(max.into()).all() && cell.max.cmpge(min.into()).all(),
+            |(entity, position, extent)| {
+                if extent.intersects_aabb(*position, min, max) {
+                    found_entities.push(*entity);
+                }
+            },
+        );
+
+        Some(found_entities)
+    }
+
+    fn entities_in_shape(
+        &self,
+        shape: &dyn SpatialShape,
+        isometry: Isometry3d,
+    ) -> Option<Vec<Entity>> {
+        let mut found_entities = Vec::new();
+
+        self.for_each_entity_in(
+            |cell| shape.intersects_aabb(isometry, cell),
+            |(entity, position, extent)| {
+                if extent.intersects_shape(*position, shape, isometry) {
+                    found_entities.push(*entity);
+                }
+            },
+        );
+
+        Some(found_entities)
+    }
+
+    fn entities_along_ray(
+        &self,
+        ray: Ray3d,
+        max_distance: f32,
+        thickness: f32,
+    ) -> Option<Vec<(Entity, f32)>> {
+        let mut found_entities = Vec::new();
+        let ray_cast = RayCast3d::from_ray(ray, max_distance);
+
+        self.for_each_entity_in(
+            |cell| {
+                ray_cast
+                    .aabb_intersection_at(&cell.grow(Vec3::splat(thickness)))
+                    .is_some()
+            },
+            |(entity, position, extent)| {
+                if let Some(distance) =
+                    extent.distance_along_ray(*position, ray, max_distance, thickness)
+                {
+                    found_entities.push((*entity, distance));
+                }
+            },
+        );
+
+        found_entities.sort_unstable_by_key(|(_entity, distance)| FloatOrd(*distance));
+
+        Some(found_entities)
+    }
+
+    fn k_nearest(&self, sample_point: Vec3, k: usize, max_distance: f32) -> Option<Vec<Entity>> {
+        if k == 0 || self.entities.is_empty() {
+            return Some(Vec::new());
+        }
+
+        let mut nearest = KNearest::new(k, max_distance);
+        self.k_nearest_in(&self.root(), sample_point, &mut nearest);
+
+        Some(nearest.into_sorted_vec())
+    }
+
+    fn nearest_iter(&self, sample_point: Vec3) -> Option<Box<dyn Iterator<Item = Entity> + '_>> {
+        Some(Box::new(NearestIter::new(
+            self,
+            sample_point,
+            (!self.entities.is_empty()).then(|| self.root()),
+        )))
+    }
+
+    fn debug_gizmos(&self, gizmos: &mut Gizmos) {
+        if self.entities.is_empty() {
+            return;
+        }
+
+        let max_depth = (self.entities.len() / self.prepared_entities_per_leaf)
+            .max(1)
+            .ilog2()
+            + 1;
+        let mut ranges = vec![(self.root(), 0)];
+        while let Some((range, depth)) = ranges.pop() {
+            match self.split(&range) {
+                Some((_pivot, left, right)) => {
+                    ranges.push((left, depth + 1));
+                    ranges.push((right, depth + 1));
+                }
+                None => {
+                    gizmos.cuboid(
+                        Transform::from_translation(range.cell.center().into())
+                            .with_scale((range.cell.max - range.cell.min).into()),
+                        Color::hsv((depth as f32) / (max_depth as f32) * 360., 0.8, 1.0),
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Recursively reorders the entities into a k-d tree, splitting each range at its median along
+/// the longest axis of its cell.
+fn build(
+    entities: &mut [EntityPositionExtent],
+    split_axes: &mut [u8],
+    cell: Aabb3d,
+    entities_per_leaf: usize,
+    task_pool: &TaskPool,
+) {
+    if entities.len() <= entities_per_leaf {
+        return;
+    }
+
+    let size = cell.max - cell.min;
+    let axis = (0..3)
+        .max_by_key(|axis| FloatOrd(size[*axis]))
+        .unwrap_or_default();
+    let mid = entities.len() / 2;
+    entities
+        .select_nth_unstable_by_key(mid, |(_entity, position, _extent)| FloatOrd(position[axis]));
+    split_axes[mid] = axis as u8;
+
+    let pivot = entities[mid].1[axis];
+    let mut left_cell = cell;
+    left_cell.max[axis] = pivot;
+    let mut right_cell = cell;
+    right_cell.min[axis] = pivot;
+
+    let (left, rest) = entities.split_at_mut(mid);
+    let right = &mut rest[1..];
+    let (left_axes, rest_axes) = split_axes.split_at_mut(mid);
+    let right_axes = &mut rest_axes[1..];
+
+    if left.len() < PARALLEL_BUILD_THRESHOLD {
+        build(left, left_axes, left_cell, entities_per_leaf, task_pool);
+        build(right, right_axes, right_cell, entities_per_leaf, task_pool);
+        return;
+    }
+
+    task_pool.scope(|scope| {
+        scope.spawn(async move {
+            build(left, left_axes, left_cell, entities_per_leaf, task_pool);
+        });
+        scope.spawn(async move {
+            build(right, right_axes, right_cell, entities_per_leaf, task_pool);
+        });
+    });
+}
+
+/// Contiguous range of the entities of a k-d tree forming a subtree, along with its cell.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct KdTreeRange {
+    start: usize,
+    end: usize,
+    /// Bounding box of the positions in the range, as bounded by the splits above it.
+    cell: Aabb3d,
+}
+
+impl NearestTree for KdTree {
+    type Node = KdTreeRange;
+
+    fn distance_squared_to_node(&self, range: &KdTreeRange, sample_point: Vec3) -> f32 {
+        let closest_point: Vec3 = self.grown_cell(range).closest_point(sample_point).into();
+        closest_point.distance_squared(sample_point)
+    }
+
+    fn open_node(&self, range: KdTreeRange, candidates: &mut NearestCandidates<'_, Self>) {
+        match self.split(&range) {
+            Some(((entity, position, extent), left, right)) => {
+                candidates.push_entity(*entity, *position, *extent);
+                candidates.push_node(left);
+                candidates.push_node(right);
+            }
+            None => {
+                for (entity, position, extent) in &self.entities[range.start..range.end] {
+                    candidates.push_entity(*entity, *position, *extent);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,605 @@
+//! Loose tree shared by the octree and the quadtree
+
+use crate::spatial_extent::{SpatialExtent, with_extents};
+use crate::spatial_shape::SpatialShape;
+use bevy::ecs::entity::EntityHashMap;
+use bevy::math::bounding::{Aabb3d, BoundingVolume, RayCast3d};
+use bevy::math::{FloatOrd, FloatPow};
+use bevy::prelude::*;
+use std::fmt::Debug;
+use std::ops::{Add, ControlFlow, Mul, Sub};
+
+type EntityPositionExtent = (Entity, Vec3, SpatialExtent);
+
+/// Vector of the space divided by a loose tree, i.e. `Vec3` for octrees and `Vec2` for quadtrees.
+pub(crate) trait TreeVector:
+    Copy + Debug + Add<Output = Self> + Sub<Output = Self> + Mul<f32, Output = Self>
+{
+    /// Number of children of each node.
+    const CHILDREN: usize;
+
+    fn splat(value: f32) -> Self;
+
+    fn min(self, other: Self) -> Self;
+
+    fn max(self, other: Self) -> Self;
+
+    fn midpoint(self, other: Self) -> Self;
+
+    fn max_element(self) -> f32;
+
+    /// Returns true if no component of `self` is larger than the same component of `other`.
+    fn all_le(self, other: Self) -> bool;
+
+    /// Returns the index of the child of a node centered at `center` whose cube contains `self`.
+    fn child_index(self, center: Self) -> usize;
+
+    /// Returns the direction from the center of a node to the center of the child with the given
+    /// index, with each component being either 1 or -1.
+    fn child_direction(index: usize) -> Self;
+}
+
+impl TreeVector for Vec3 {
+    const CHILDREN: usize = 8;
+
+    #[inline]
+    fn splat(value: f32) -> Self {
+        Vec3::splat(value)
+    }
+
+    #[inline]
+    fn min(self, other: Self) -> Self {
+        Vec3::min(self, other)
+    }
+
+    #[inline]
+    fn max(self, other: Self) -> Self {
+        Vec3::max(self, other)
+    }
+
+    #[inline]
+    fn midpoint(self, other: Self) -> Self {
+        Vec3::midpoint(self, other)
+    }
+
+    #[inline]
+    fn max_element(self) -> f32 {
+        Vec3::max_element(self)
+    }
+
+    #[inline]
+    fn all_le(self, other: Self) -> bool {
+        self.cmple(other).all()
+    }
+
+    #[inline]
+    fn child_index(self, center: Self) -> usize {
+        let above_center = self.cmpge(center);
+
+        above_center.x as usize | (above_center.y as usize) << 1 | (above_center.z as usize) << 2
+    }
+
+    #[inline]
+    fn child_direction(index: usize) -> Self {
+        Vec3::new(
+            if index & 1 != 0 { 1. } else { -1. },
+            if index & 2 != 0 { 1. } else { -1. },
+            if index & 4 != 0 { 1. } else { -1. },
+        )
+    }
+}
+
+impl TreeVector for Vec2 {
+    const CHILDREN: usize = 4;
+
+    #[inline]
+    fn splat(value: f32) -> Self {
+        Vec2::splat(value)
+    }
+
+    #[inline]
+    fn min(self, other: Self) -> Self {
+        Vec2::min(self, other)
+    }
+
+    #[inline]
+    fn max(self, other: Self) -> Self {
+        Vec2::max(self, other)
+    }
+
+    #[inline]
+    fn midpoint(self, other: Self) -> Self {
+        Vec2::midpoint(self, other)
+    }
+
+    #[inline]
+    fn max_element(self) -> f32 {
+        Vec2::max_element(self)
+    }
+
+    #[inline]
+    fn all_le(self, other: Self) -> bool {
+        self.cmple(other).all()
+    }
+
+    #[inline]
+    fn child_index(self, center: Self) -> usize {
+        let above_center = self.cmpge(center);
+
+        above_center.x as usize | (above_center.y as usize) << 1
+    }
+
+    #[inline]
+    fn child_direction(index: usize) -> Self {
+        Vec2::new(
+            if index & 1 != 0 { 1. } else { -1. },
+            if index & 2 != 0 { 1. } else { -1. },
+        )
+    }
+}
+
+/// Maps the volumes of the entities to the space divided by a loose tree.
+pub(crate) trait TreeSpace: Debug {
+    type Vector: TreeVector;
+
+    /// Projects a point in the world into the space of the tree.
+    fn project(&self, point: Vec3) -> Self::Vector;
+
+    /// Called with the volume of every entity added to the tree.
+    fn include(&mut self, _position: Vec3, _extent: SpatialExtent) {}
+
+    /// Returns the bounds in the world of the cube with the given center and half-size, which
+    /// contain the volumes of all entities inside the cube.
+    fn world_aabb(&self, center: Self::Vector, half_size: f32) -> Aabb3d;
+
+    /// Draws the outline of the cube with the given center and half-size.
+    fn draw_cube(&self, gizmos: &mut Gizmos, center: Self::Vector, half_size: f32, color: Color);
+}
+
+/// Settings of a loose tree, see `Octree` and `Quadtree`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct LooseTreeSettings {
+    pub max_depth: usize,
+    pub node_capacity: usize,
+    pub looseness: f32,
+}
+
+/// Loose tree which recursively divides the space `S` into cubes, with 2^N children per node.
+#[derive(Debug)]
+pub(crate) struct LooseTree<S: TreeSpace> {
+    space: S,
+    root: Option<LooseTreeNode<S::Vector>>,
+    /// Position and extent of each entity, only kept up to date by incremental updates.
+    entity_volumes: EntityHashMap<(Vec3, SpatialExtent)>,
+    /// Number of entities in the tree.
+    len: usize,
+}
+
+impl<S: TreeSpace> LooseTree<S> {
+    pub fn new(space: S) -> Self {
+        LooseTree {
+            space,
+            root: None,
+            entity_volumes: EntityHashMap::default(),
+            len: 0,
+        }
+    }
+
+    /// Makes sure `entity_volumes` matches the tree before an incremental update.
+    fn prepare_incremental_update(&mut self) {
+        if self.entity_volumes.len() != self.len {
+            self.entity_volumes.clear();
+
+            if let Some(root) = &self.root {
+                root.for_each_entity(&mut |(entity, position, extent)| {
+                    self.entity_volumes.insert(*entity, (*position, *extent));
+                });
+            }
+        }
+    }
+
+    /// Rebuilds the tree in the given space from scratch.
+    pub fn prepare(
+        &mut self,
+        space: S,
+        entities: &[(Entity, Vec3)],
+        extents: &[SpatialExtent],
+        settings: LooseTreeSettings,
+    ) {
+        assert!(settings.looseness >= 1.0, "looseness must be at least 1");
+
+        self.space = space;
+        self.entity_volumes.clear();
+        self.len = entities.len();
+
+        // the root is the bounding cube of all entity volumes, so every entity fits into it
+        let mut min = S::Vector::splat(f32::INFINITY);
+        let mut max = S::Vector::splat(f32::NEG_INFINITY);
+        for (_entity, position, extent) in with_extents(entities, extents) {
+            let position = self.space.project(position);
+            let half_size = self.space.project(extent.half_size());
+            min = min.min(position - half_size);
+            max = max.max(position + half_size);
+        }
+
+        if entities.is_empty() {
+            self.root = None;
+            return;
+        }
+
+        let half_size = ((max - min) * 0.5).max_element().max(f32::EPSILON);
+        let mut root = LooseTreeNode::new(min.midpoint(max), half_size, settings.looseness);
+
+        for (entity, position, extent) in with_extents(entities, extents) {
+            self.space.include(position, extent);
+            root.insert((entity, position, extent), 0, &self.space, settings);
+        }
+
+        self.root = Some(root);
+    }
+
+    /// Adds an entity to the tree.
+    ///
+    /// Returns false if the entity doesn't fit into the root, in which case the tree has to be
+    /// rebuilt.
+    pub fn insert(
+        &mut self,
+        entity: Entity,
+        position: Vec3,
+        extent: SpatialExtent,
+        settings: LooseTreeSettings,
+    ) -> bool {
+        self.prepare_incremental_update();
+
+        let Some(root) = &mut self.root else {
+            return false;
+        };
+
+        let fits = root.fits((entity, position, extent), &self.space);
+        if fits {
+            self.space.include(position, extent);
+            root.insert((entity, position, extent), 0, &self.space, settings);
+            self.entity_volumes.insert(entity, (position, extent));
+            self.len += 1;
+        }
+
+        fits
+    }
+
+    /// Removes an entity from the tree, if it's in the tree.
+    pub fn remove(&mut self, entity: Entity) {
+        self.prepare_incremental_update();
+
+        if let Some((position, _extent)) = self.entity_volumes.remove(&entity)
+            && let Some(root) = &mut self.root
+            && root.remove(entity, self.space.project(position))
+        {
+            self.len -= 1;
+        }
+    }
+
+    pub fn visit_in_radius(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+        visit: &mut dyn FnMut(Entity, Vec3) -> ControlFlow<()>,
+    ) -> ControlFlow<()> {
+        match &self.root {
+            Some(root) => root.visit_in_radius(&self.space, sample_point, radius, visit),
+            None => ControlFlow::Continue(()),
+        }
+    }
+
+    pub fn entities_in_aabb(&self, min: Vec3, max: Vec3) -> Vec<Entity> {
+        let mut found_entities = Vec::new();
+
+        if let Some(root) = &self.root {
+            let aabb = Aabb3d {
+                min: min.into(),
+                max: max.into(),
+            };
+            root.entities_in_aabb(&self.space, &aabb, &mut found_entities);
+        }
+
+        found_entities
+    }
+
+    pub fn entities_in_shape(&self, shape: &dyn SpatialShape, isometry: Isometry3d) -> Vec<Entity> {
+        let mut found_entities = Vec::new();
+
+        if let Some(root) = &self.root {
+            root.entities_in_shape(&self.space, shape, isometry, &mut found_entities);
+        }
+
+        found_entities
+    }
+
+    pub fn entities_along_ray(
+        &self,
+        ray: Ray3d,
+        max_distance: f32,
+        thickness: f32,
+    ) -> Vec<(Entity, f32)> {
+        let mut found_entities = Vec::new();
+
+        if let Some(root) = &self.root {
+            let ray_cast = RayCast3d::from_ray(ray, max_distance);
+            root.entities_along_ray(&self.space, ray, &ray_cast, thickness, &mut found_entities);
+        }
+
+        found_entities.sort_unstable_by_key(|(_entity, distance)| FloatOrd(*distance));
+
+        found_entities
+    }
+
+    pub fn draw_gizmos(&self, gizmos: &mut Gizmos, max_depth: usize) {
+        if let Some(root) = &self.root {
+            root.draw_gizmos(&self.space, gizmos, 0, max_depth);
+        }
+    }
+}
+
+/// Node of a loose tree.
+///
+/// Each node is a cube, and holds the entities which fit in its loose bounds but not in the loose
+/// bounds of any of its children.
+#[derive(Debug)]
+struct LooseTreeNode<V: TreeVector> {
+    center: V,
+    half_size: f32,
+    /// Half-size of the loose bounds of the node.
+    loose_half_size: f32,
+    entities: Vec<EntityPositionExtent>,
+    children: Option<Box<[LooseTreeNode<V>]>>,
+}
+
+impl<V: TreeVector> LooseTreeNode<V> {
+    fn new(center: V, half_size: f32, looseness: f32) -> Self {
+        LooseTreeNode {
+            center,
+            half_size,
+            loose_half_size: half_size * looseness,
+            entities: Vec::new(),
+            children: None,
+        }
+    }
+
+    /// Returns the loose bounds of this node in the world.
+    #[inline]
+    fn loose_aabb(&self, space: &impl TreeSpace<Vector = V>) -> Aabb3d {
+        space.world_aabb(self.center, self.loose_half_size)
+    }
+
+    /// Returns true if the projected volume of the entity is inside the loose bounds of this node.
+    #[inline]
+    fn fits(
+        &self,
+        (_entity, position, extent): EntityPositionExtent,
+        space: &impl TreeSpace<Vector = V>,
+    ) -> bool {
+        let position = space.project(position);
+        let half_size = space.project(extent.half_size());
+        let loose_half_size = V::splat(self.loose_half_size);
+
+        (self.center - loose_half_size).all_le(position - half_size)
+            && (position + half_size).all_le(self.center + loose_half_size)
+    }
+
+    /// Adds an entity to this node or the deepest child node it fits in, splitting nodes which
+    /// grow over `node_capacity`.
+    fn insert(
+        &mut self,
+        entity_position_extent: EntityPositionExtent,
+        depth: usize,
+        space: &impl TreeSpace<Vector = V>,
+        settings: LooseTreeSettings,
+    ) {
+        let index = space
+            .project(entity_position_extent.1)
+            .child_index(self.center);
+        if let Some(children) = &mut self.children {
+            let child = &mut children[index];
+
+            if child.fits(entity_position_extent, space) {
+                child.insert(entity_position_extent, depth + 1, space, settings);
+            } else {
+                self.entities.push(entity_position_extent);
+            }
+
+            return;
+        }
+
+        self.entities.push(entity_position_extent);
+
+        if self.entities.len() > settings.node_capacity && depth < settings.max_depth {
+            self.split(depth, space, settings);
+        }
+    }
+
+    /// Splits this node into child nodes, and moves the entities which fit into them.
+    fn split(
+        &mut self,
+        depth: usize,
+        space: &impl TreeSpace<Vector = V>,
+        settings: LooseTreeSettings,
+    ) {
+        let child_half_size = self.half_size * 0.5;
+        let children = (0..V::CHILDREN)
+            .map(|index| {
+                LooseTreeNode::new(
+                    self.center + V::child_direction(index) * child_half_size,
+                    child_half_size,
+                    settings.looseness,
+                )
+            })
+            .collect();
+        self.children = Some(children);
+
+        for entity_position_extent in std::mem::take(&mut self.entities) {
+            self.insert(entity_position_extent, depth, space, settings);
+        }
+    }
+
+    /// Removes an entity which was inserted at the given projected position.
+    ///
+    /// Returns false if the entity wasn't found.
+    fn remove(&mut self, entity: Entity, position: V) -> bool {
+        // entities are always stored along the path of child nodes containing their position
+        if let Some(index) = self
+            .entities
+            .iter()
+            .position(|(node_entity, _position, _extent)| *node_entity == entity)
+        {
+            self.entities.swap_remove(index);
+            return true;
+        }
+
+        let index = position.child_index(self.center);
+        match &mut self.children {
+            Some(children) => children[index].remove(entity, position),
+            None => false,
+        }
+    }
+
+    /// Calls `f` for every entity in this node and its children.
+    fn for_each_entity(&self, f: &mut impl FnMut(&EntityPositionExtent)) {
+        self.entities.iter().for_each(&mut *f);
+
+        if let Some(children) = &self.children {
+            for child in children.iter() {
+                child.for_each_entity(f);
+            }
+        }
+    }
+
+    /// Calls `visit` with the entities that are in radius of the given sample point, until
+    /// `visit` returns `ControlFlow::Break`.
+    fn visit_in_radius(
+        &self,
+        space: &impl TreeSpace<Vector = V>,
+        sample_point: Vec3,
+        radius: f32,
+        visit: &mut dyn FnMut(Entity, Vec3) -> ControlFlow<()>,
+    ) -> ControlFlow<()> {
+        let closest_point: Vec3 = self.loose_aabb(space).closest_point(sample_point).into();
+        if closest_point.distance_squared(sample_point) > radius.squared() {
+            return ControlFlow::Continue(());
+        }
+
+        for (entity, position, extent) in &self.entities {
+            if extent.distance_squared_to_point(*position, sample_point) <= radius.squared() {
+                visit(*entity, *position)?;
+            }
+        }
+
+        if let Some(children) = &self.children {
+            for child in children.iter() {
+                child.visit_in_radius(space, sample_point, radius, visit)?;
+            }
+        }
+
+        ControlFlow::Continue(())
+    }
+
+    /// Finds the entities that are inside the given AABB.
+    fn entities_in_aabb(
+        &self,
+        space: &impl TreeSpace<Vector = V>,
+        aabb: &Aabb3d,
+        found: &mut Vec<Entity>,
+    ) {
+        let loose_aabb = self.loose_aabb(space);
+        if loose_aabb.min.cmpgt(aabb.max).any() || loose_aabb.max.cmplt(aabb.min).any() {
+            return;
+        }
+
+        for (entity, position, extent) in &self.entities {
+            if extent.intersects_aabb(*position, aabb.min.into(), aabb.max.into()) {
+                found.push(*entity);
+            }
+        }
+
+        if let Some(children) = &self.children {
+            for child in children.iter() {
+                child.entities_in_aabb(space, aabb, found);
+            }
+        }
+    }
+
+    /// Finds the entities that are inside the given shape.
+    fn entities_in_shape(
+        &self,
+        space: &impl TreeSpace<Vector = V>,
+        shape: &dyn SpatialShape,
+        isometry: Isometry3d,
+        found: &mut Vec<Entity>,
+    ) {
+        if !shape.intersects_aabb(isometry, &self.loose_aabb(space)) {
+            return;
+        }
+
+        for (entity, position, extent) in &self.entities {
+            if extent.intersects_shape(*position, shape, isometry) {
+                found.push(*entity);
+            }
+        }
+
+        if let Some(children) = &self.children {
+            for child in children.iter() {
+                child.entities_in_shape(space, shape, isometry, found);
+            }
+        }
+    }
+
+    /// Finds the entities within `thickness` of the ray, along with their distance along the ray.
+    fn entities_along_ray(
+        &self,
+        space: &impl TreeSpace<Vector = V>,
+        ray: Ray3d,
+        ray_cast: &RayCast3d,
+        thickness: f32,
+        found: &mut Vec<(Entity, f32)>,
+    ) {
+        let grown_aabb = self.loose_aabb(space).grow(Vec3::splat(thickness));
+        if ray_cast.aabb_intersection_at(&grown_aabb).is_none() {
+            return;
+        }
+
+        for (entity, position, extent) in &self.entities {
+            if let Some(distance) =
+                extent.distance_along_ray(*position, ray, ray_cast.max, thickness)
+            {
+                found.push((*entity, distance));
+            }
+        }
+
+        if let Some(children) = &self.children {
+            for child in children.iter() {
+                child.entities_along_ray(space, ray, ray_cast, thickness, found);
+            }
+        }
+    }
+
+    fn draw_gizmos(
+        &self,
+        space: &impl TreeSpace<Vector = V>,
+        gizmos: &mut Gizmos,
+        depth: usize,
+        max_depth: usize,
+    ) {
+        if !self.entities.is_empty() {
+            space.draw_cube(
+                gizmos,
+                self.center,
+                self.half_size,
+                Color::hsv((depth as f32) / (max_depth as f32) * 360., 0.8, 1.0),
+            );
+        }
+
+        if let Some(children) = &self.children {
+            for child in children.iter() {
+                child.draw_gizmos(space, gizmos, depth + 1, max_depth);
+            }
+        }
+    }
+}
@@ -2,12 +2,31 @@
 //!
 //! You can implement your own algorithm by implementing the `SpatialLookupAlgorithm` trait.
 
+mod adaptive;
 mod bvh;
-mod naive;
+mod hash_grid;
+mod hierarchical_grid;
+mod kd_tree;
+mod loose_tree;
+pub(crate) mod naive;
+mod nearest;
+mod octree;
+mod quadtree;
+mod spatial_plane;
+mod sweep_and_prune;
+mod traversal_stack;
 
 // Re-export algorithms for ease of use.
-pub use bvh::Bvh;
+pub use adaptive::{Adaptive, AdaptiveCost};
+pub use bvh::{Bvh, BvhBuildStrategy};
+pub use hash_grid::HashGrid;
+pub use hierarchical_grid::HierarchicalGrid;
+pub use kd_tree::KdTree;
 pub use naive::Naive;
+pub use octree::Octree;
+pub use quadtree::Quadtree;
+pub use spatial_plane::SpatialPlane;
+pub use sweep_and_prune::SweepAndPrune;
 
 /// Common tests which test all algorithms with the same World setup,
 /// to make sure they all return the same entities.
@@ -15,13 +34,19 @@ pub use naive::Naive;
 /// TODO: Consider using a fixture-based test framework
 #[cfg(test)]
 mod tests {
-    use crate::{SpatialLookupState, algorithms};
+    use crate::{
+        SpatialExtent, SpatialLookupAlgorithm, SpatialLookupState, SpatialShape, algorithms,
+    };
+    use bevy::math::FloatOrd;
     use bevy::prelude::*;
+    use std::ops::ControlFlow;
     use turborand::SeededCore;
     use turborand::prelude::*;
 
     const WORLD_SIZE: f32 = 10.0;
     const LOOKUP_RADIUS: f32 = 1.0;
+    const K_NEAREST: usize = 10;
+    const LOOKUP_HALF_EXTENTS: Vec3 = Vec3::new(1.0, 0.5, 2.0);
 
     /// Helper function to make a list of pseudo-randomly places entities
     fn world_with_n_entities(n: u32) -> Vec<(Entity, Vec3)> {
@@ -42,6 +67,35 @@ mod tests {
         entities
     }
 
+    /// Helper function to give every entity a pseudo-random sphere or box extent
+    fn extents_for_n_entities(n: u32) -> Vec<SpatialExtent> {
+        let rng = Rng::with_seed(1812);
+
+        (0..n)
+            .map(|_| {
+                if rng.bool() {
+                    SpatialExtent::Sphere(rng.f32() * LOOKUP_RADIUS)
+                } else {
+                    SpatialExtent::Cuboid(
+                        Vec3::new(rng.f32(), rng.f32(), rng.f32()) * LOOKUP_RADIUS,
+                    )
+                }
+            })
+            .collect()
+    }
+
+    /// Helper function to find the `k` nearest entities by sorting all entities by distance
+    fn sorted_k_nearest(entities: &[(Entity, Vec3)], sample_point: Vec3, k: usize) -> Vec<Entity> {
+        let mut entities = entities.to_vec();
+        entities.sort_by_key(|(_entity, position)| FloatOrd(position.distance(sample_point)));
+
+        entities
+            .into_iter()
+            .take(k)
+            .map(|(entity, _position)| entity)
+            .collect()
+    }
+
     #[test]
     fn test_bvh_in_range() {
         let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::Bvh::default());
@@ -63,4 +117,1598 @@ mod tests {
 
         assert_eq!(found.len(), 39);
     }
+
+    #[test]
+    fn test_bvh_in_aabb() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::Bvh::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        let found = lookup_state.entities_in_aabb(-LOOKUP_HALF_EXTENTS, LOOKUP_HALF_EXTENTS);
+
+        assert_eq!(found.len(), 79);
+    }
+
+    #[test]
+    fn test_naive_in_aabb() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::Naive::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        let found = lookup_state.entities_in_aabb(-LOOKUP_HALF_EXTENTS, LOOKUP_HALF_EXTENTS);
+
+        assert_eq!(found.len(), 79);
+    }
+
+    #[test]
+    fn test_bvh_in_shape() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::Bvh::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        let sphere = Sphere::new(LOOKUP_RADIUS);
+        let found = lookup_state.entities_in_shape(&sphere, Isometry3d::IDENTITY);
+        assert_eq!(found.len(), 39);
+
+        let cuboid = Cuboid::from_size(LOOKUP_HALF_EXTENTS * 2.);
+        let found = lookup_state.entities_in_shape(&cuboid, Isometry3d::IDENTITY);
+        assert_eq!(found.len(), 79);
+    }
+
+    #[test]
+    fn test_naive_in_shape() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::Naive::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        let sphere = Sphere::new(LOOKUP_RADIUS);
+        let found = lookup_state.entities_in_shape(&sphere, Isometry3d::IDENTITY);
+        assert_eq!(found.len(), 39);
+
+        let cuboid = Cuboid::from_size(LOOKUP_HALF_EXTENTS * 2.);
+        let found = lookup_state.entities_in_shape(&cuboid, Isometry3d::IDENTITY);
+        assert_eq!(found.len(), 79);
+    }
+
+    /// Orthographic frustum which covers the same volume as the `LOOKUP_HALF_EXTENTS` box.
+    #[cfg(feature = "bevy_render")]
+    fn box_frustum() -> bevy::render::primitives::Frustum {
+        let clip_from_world = Mat4::orthographic_rh(
+            -LOOKUP_HALF_EXTENTS.x,
+            LOOKUP_HALF_EXTENTS.x,
+            -LOOKUP_HALF_EXTENTS.y,
+            LOOKUP_HALF_EXTENTS.y,
+            -LOOKUP_HALF_EXTENTS.z,
+            LOOKUP_HALF_EXTENTS.z,
+        );
+
+        bevy::render::primitives::Frustum::from_clip_from_world(&clip_from_world)
+    }
+
+    #[test]
+    #[cfg(feature = "bevy_render")]
+    fn test_bvh_in_frustum() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::Bvh::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        let found = lookup_state.entities_in_shape(&box_frustum(), Isometry3d::IDENTITY);
+
+        assert_eq!(found.len(), 79);
+    }
+
+    #[test]
+    #[cfg(feature = "bevy_render")]
+    fn test_naive_in_frustum() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::Naive::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        let found = lookup_state.entities_in_shape(&box_frustum(), Isometry3d::IDENTITY);
+
+        assert_eq!(found.len(), 79);
+    }
+
+    #[test]
+    fn test_bvh_along_ray() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::Bvh::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        let ray = Ray3d::new(Vec3::new(-WORLD_SIZE, 0., 0.), Dir3::X);
+        let found = lookup_state.entities_along_ray(ray, WORLD_SIZE, LOOKUP_RADIUS);
+
+        assert_eq!(found.len(), 380);
+        assert!(found.is_sorted_by_key(|(_entity, distance)| FloatOrd(*distance)));
+    }
+
+    #[test]
+    fn test_naive_along_ray() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::Naive::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        let ray = Ray3d::new(Vec3::new(-WORLD_SIZE, 0., 0.), Dir3::X);
+        let found = lookup_state.entities_along_ray(ray, WORLD_SIZE, LOOKUP_RADIUS);
+
+        assert_eq!(found.len(), 380);
+        assert!(found.is_sorted_by_key(|(_entity, distance)| FloatOrd(*distance)));
+    }
+
+    #[test]
+    fn test_bvh_k_nearest() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::Bvh::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        let found = lookup_state.k_nearest(Vec3::ZERO, K_NEAREST, LOOKUP_RADIUS);
+        let expected = sorted_k_nearest(&lookup_state.entities, Vec3::ZERO, K_NEAREST);
+
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn test_naive_k_nearest() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::Naive::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        let found = lookup_state.k_nearest(Vec3::ZERO, K_NEAREST, LOOKUP_RADIUS);
+        let expected = sorted_k_nearest(&lookup_state.entities, Vec3::ZERO, K_NEAREST);
+
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn test_bvh_nearest_matching() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::Bvh::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        let is_odd = |entity: Entity| entity.index() % 2 == 1;
+        let found = lookup_state.nearest_matching(Vec3::ZERO, is_odd);
+        let expected = sorted_k_nearest(&lookup_state.entities, Vec3::ZERO, K_NEAREST)
+            .into_iter()
+            .find(|entity| is_odd(*entity));
+
+        assert!(expected.is_some());
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn test_naive_nearest_matching() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::Naive::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        let is_odd = |entity: Entity| entity.index() % 2 == 1;
+        let found = lookup_state.nearest_matching(Vec3::ZERO, is_odd);
+        let expected = sorted_k_nearest(&lookup_state.entities, Vec3::ZERO, K_NEAREST)
+            .into_iter()
+            .find(|entity| is_odd(*entity));
+
+        assert!(expected.is_some());
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn test_bvh_with_extents() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::Bvh::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.extents = extents_for_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        let found = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+        assert_eq!(found.len(), 213);
+
+        let found = lookup_state.entities_in_aabb(-LOOKUP_HALF_EXTENTS, LOOKUP_HALF_EXTENTS);
+        assert_eq!(found.len(), 362);
+
+        let cuboid = Cuboid::from_size(LOOKUP_HALF_EXTENTS * 2.);
+        let found = lookup_state.entities_in_shape(&cuboid, Isometry3d::IDENTITY);
+        assert_eq!(found.len(), 362);
+
+        let ray = Ray3d::new(Vec3::new(-WORLD_SIZE, 0., 0.), Dir3::X);
+        let found = lookup_state.entities_along_ray(ray, WORLD_SIZE, LOOKUP_RADIUS);
+        assert_eq!(found.len(), 1058);
+    }
+
+    #[test]
+    fn test_naive_with_extents() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::Naive::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.extents = extents_for_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        let found = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+        assert_eq!(found.len(), 213);
+
+        let found = lookup_state.entities_in_aabb(-LOOKUP_HALF_EXTENTS, LOOKUP_HALF_EXTENTS);
+        assert_eq!(found.len(), 362);
+
+        let cuboid = Cuboid::from_size(LOOKUP_HALF_EXTENTS * 2.);
+        let found = lookup_state.entities_in_shape(&cuboid, Isometry3d::IDENTITY);
+        assert_eq!(found.len(), 362);
+
+        let ray = Ray3d::new(Vec3::new(-WORLD_SIZE, 0., 0.), Dir3::X);
+        let found = lookup_state.entities_along_ray(ray, WORLD_SIZE, LOOKUP_RADIUS);
+        assert_eq!(found.len(), 1058);
+    }
+
+    #[test]
+    fn test_bvh_finds_large_extent() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::Bvh::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.extents = vec![SpatialExtent::default(); 100_000];
+
+        // an entity far outside the world, but with a volume reaching the origin
+        let building = Entity::from_raw(100_000);
+        lookup_state
+            .entities
+            .push((building, Vec3::new(WORLD_SIZE * 3., 0., 0.)));
+        lookup_state
+            .extents
+            .push(SpatialExtent::Cuboid(Vec3::splat(WORLD_SIZE * 3.)));
+        lookup_state.prepare_algorithm();
+
+        let found = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+        assert_eq!(found.len(), 40);
+        assert!(found.contains(&building));
+
+        let found = lookup_state.k_nearest(Vec3::ZERO, 1, LOOKUP_RADIUS);
+        assert_eq!(found, vec![building]);
+    }
+
+    /// Helper function to check that an entity is found far from its position, where only its
+    /// volume reaches
+    fn assert_finds_large_extent_off_center(mut lookup_state: SpatialLookupState) {
+        let point = Entity::from_raw(0);
+        let sphere = Entity::from_raw(1);
+        lookup_state.entities = vec![(point, Vec3::ZERO), (sphere, Vec3::X)];
+        lookup_state.extents = vec![SpatialExtent::default(), SpatialExtent::Sphere(100.)];
+        lookup_state.prepare_algorithm();
+
+        let sample_point = Vec3::new(50., 0., 0.);
+        assert_eq!(
+            lookup_state.entities_in_radius(sample_point, 1.),
+            vec![sphere]
+        );
+        assert_eq!(
+            lookup_state.entities_in_aabb(sample_point - 1., sample_point + 1.),
+            vec![sphere]
+        );
+        assert_eq!(
+            lookup_state
+                .entities_in_shape(&Sphere::new(1.), Isometry3d::from_translation(sample_point)),
+            vec![sphere]
+        );
+        assert_eq!(lookup_state.k_nearest(sample_point, 2, 1.), vec![sphere]);
+    }
+
+    /// Sample points for comparing queries. The last ones are outside the world, where only large
+    /// volumes reach.
+    const SAMPLE_POINTS: [Vec3; 5] = [
+        Vec3::ZERO,
+        Vec3::new(3., -2., 5.),
+        Vec3::new(-WORLD_SIZE, WORLD_SIZE * 0.5, 0.),
+        Vec3::new(WORLD_SIZE * 5.8, 0., 0.),
+        Vec3::new(50., 0., 0.),
+    ];
+
+    /// Entities with their positions, and their extents if any
+    type World = (Vec<(Entity, Vec3)>, Vec<SpatialExtent>);
+
+    /// Helper function to make worlds to compare algorithms in: points, points with small volumes,
+    /// and points with a few volumes reaching far beyond their positions
+    fn comparison_worlds(n: u32) -> Vec<World> {
+        let entities = world_with_n_entities(n);
+
+        let mut large_entities = entities.clone();
+        let mut large_extents = vec![SpatialExtent::default(); n as usize];
+        for (index, (position, extent)) in [
+            (
+                Vec3::new(WORLD_SIZE * 3., 0., 0.),
+                SpatialExtent::Cuboid(Vec3::splat(WORLD_SIZE * 3.)),
+            ),
+            (Vec3::X, SpatialExtent::Sphere(100.)),
+            (
+                Vec3::new(-WORLD_SIZE * 2., WORLD_SIZE, 0.),
+                SpatialExtent::Cuboid(Vec3::new(WORLD_SIZE, 0.5, WORLD_SIZE * 2.)),
+            ),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            large_entities.push((Entity::from_raw(n + index as u32), position));
+            large_extents.push(extent);
+        }
+
+        vec![
+            (entities.clone(), vec![]),
+            (entities, extents_for_n_entities(n)),
+            (large_entities, large_extents),
+        ]
+    }
+
+    /// Helper function to sort entities, for comparing results regardless of order
+    fn sorted<T: Ord>(mut items: Vec<T>) -> Vec<T> {
+        items.sort();
+        items
+    }
+
+    /// Helper function to check that every query of the algorithm returns the same results as the
+    /// `Naive` algorithm, in worlds with and without volumes, on and off the origin.
+    fn assert_matches_naive<T: SpatialLookupAlgorithm + Send + Sync + 'static>(
+        new_algorithm: impl Fn() -> T,
+    ) {
+        let mut lookup_state = SpatialLookupState::with_algorithm(new_algorithm());
+        lookup_state.prepare_algorithm();
+        assert!(
+            lookup_state
+                .entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS)
+                .is_empty()
+        );
+        assert!(
+            lookup_state
+                .k_nearest(Vec3::ZERO, K_NEAREST, LOOKUP_RADIUS)
+                .is_empty()
+        );
+
+        let rotation = Quat::from_euler(EulerRot::XYZ, 0.3, 0.5, 0.7);
+        let shapes: [&dyn SpatialShape; 7] = [
+            &Sphere::new(LOOKUP_RADIUS * 2.),
+            &Cuboid::from_size(LOOKUP_HALF_EXTENTS * 2.),
+            &Capsule3d::new(LOOKUP_RADIUS * 0.5, LOOKUP_RADIUS * 3.),
+            &Cylinder::new(LOOKUP_RADIUS, LOOKUP_RADIUS * 2.),
+            &Cone::new(LOOKUP_RADIUS * 1.5, LOOKUP_RADIUS * 3.),
+            &Circle::new(LOOKUP_RADIUS * 1.5),
+            &Rectangle::new(LOOKUP_RADIUS * 2., LOOKUP_RADIUS * 3.),
+        ];
+        #[cfg(feature = "bevy_render")]
+        let frustum = box_frustum();
+        #[cfg(feature = "bevy_render")]
+        let shapes: Vec<&dyn SpatialShape> = shapes.into_iter().chain([&frustum as _]).collect();
+
+        for (entities, extents) in comparison_worlds(20_000) {
+            let mut lookup_state = SpatialLookupState::with_algorithm(new_algorithm());
+            lookup_state.entities = entities.clone();
+            lookup_state.extents = extents.clone();
+            lookup_state.prepare_algorithm();
+
+            let mut expected_state =
+                SpatialLookupState::with_algorithm(algorithms::Naive::default());
+            expected_state.entities = entities;
+            expected_state.extents = extents;
+            expected_state.prepare_algorithm();
+
+            let distance = |entity: Entity, sample_point: Vec3| {
+                let index = expected_state
+                    .entities
+                    .iter()
+                    .position(|(other, _position)| *other == entity)
+                    .unwrap();
+                let extent = expected_state
+                    .extents
+                    .get(index)
+                    .copied()
+                    .unwrap_or_default();
+                extent.distance_squared_to_point(expected_state.entities[index].1, sample_point)
+            };
+
+            for sample_point in SAMPLE_POINTS {
+                for radius in [LOOKUP_RADIUS, LOOKUP_RADIUS * 3.] {
+                    let expected = sorted(expected_state.entities_in_radius(sample_point, radius));
+                    let found = sorted(lookup_state.entities_in_radius(sample_point, radius));
+                    assert_eq!(found, expected, "radius {radius} around {sample_point}");
+                }
+
+                let (min, max) = (
+                    sample_point - LOOKUP_HALF_EXTENTS,
+                    sample_point + LOOKUP_HALF_EXTENTS,
+                );
+                assert_eq!(
+                    sorted(lookup_state.entities_in_aabb(min, max)),
+                    sorted(expected_state.entities_in_aabb(min, max)),
+                    "aabb around {sample_point}"
+                );
+
+                let isometry = Isometry3d::new(sample_point, rotation);
+                for (index, shape) in shapes.iter().enumerate() {
+                    assert_eq!(
+                        sorted(lookup_state.entities_in_shape(*shape, isometry)),
+                        sorted(expected_state.entities_in_shape(*shape, isometry)),
+                        "shape {index} around {sample_point}"
+                    );
+                }
+
+                let ray = Ray3d::new(sample_point - Vec3::X * WORLD_SIZE, Dir3::X);
+                let found = lookup_state.entities_along_ray(ray, WORLD_SIZE * 2., LOOKUP_RADIUS);
+                let expected =
+                    expected_state.entities_along_ray(ray, WORLD_SIZE * 2., LOOKUP_RADIUS);
+                assert!(found.is_sorted_by_key(|(_entity, distance)| FloatOrd(*distance)));
+                assert_eq!(
+                    sorted(found.into_iter().map(|(entity, _)| entity).collect()),
+                    sorted(expected.into_iter().map(|(entity, _)| entity).collect()),
+                    "ray through {sample_point}"
+                );
+
+                // entities at the same distance may be returned in any order, so compare distances
+                for max_distance in [LOOKUP_RADIUS, f32::INFINITY] {
+                    let distances = |found: Vec<Entity>| -> Vec<f32> {
+                        found
+                            .into_iter()
+                            .map(|entity| distance(entity, sample_point))
+                            .collect()
+                    };
+                    let found =
+                        distances(lookup_state.k_nearest(sample_point, K_NEAREST, max_distance));
+                    let expected =
+                        distances(expected_state.k_nearest(sample_point, K_NEAREST, max_distance));
+                    assert_eq!(found, expected, "k nearest to {sample_point}");
+                }
+
+                let is_odd = |entity: Entity| entity.index() % 2 == 1;
+                let found = lookup_state.nearest_matching(sample_point, is_odd);
+                let expected = expected_state.nearest_matching(sample_point, is_odd);
+                assert_eq!(
+                    found.map(|entity| distance(entity, sample_point)),
+                    expected.map(|entity| distance(entity, sample_point)),
+                    "nearest matching to {sample_point}"
+                );
+            }
+        }
+
+        for (entities, extents) in comparison_worlds(2_000) {
+            let mut lookup_state = SpatialLookupState::with_algorithm(new_algorithm());
+            lookup_state.entities = entities.clone();
+            lookup_state.extents = extents.clone();
+            lookup_state.prepare_algorithm();
+
+            let sorted_pairs = |pairs: Vec<(Entity, Entity)>| {
+                sorted(
+                    pairs
+                        .into_iter()
+                        .map(|(entity, other)| (entity.min(other), entity.max(other)))
+                        .collect(),
+                )
+            };
+            let expected = algorithms::naive::overlapping_pairs(&entities, &extents);
+            assert_eq!(
+                sorted_pairs(lookup_state.overlapping_pairs()),
+                sorted_pairs(expected)
+            );
+        }
+    }
+
+    /// Helper function to move, remove and add entities through the incremental update API, and
+    /// check that the result matches a lookup prepared from scratch
+    fn assert_incremental_updates_match_prepare(mut lookup_state: SpatialLookupState) {
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        for index in 0..1_000 {
+            lookup_state.remove(Entity::from_raw(index));
+        }
+        for index in 1_000..2_000 {
+            let position = Vec3::splat(index as f32 / 2_000. - 0.5);
+            lookup_state.insert_or_update(
+                Entity::from_raw(index),
+                position,
+                SpatialExtent::default(),
+            );
+        }
+        for index in 100_000..100_100 {
+            lookup_state.insert_or_update(
+                Entity::from_raw(index),
+                Vec3::ZERO,
+                SpatialExtent::Sphere(LOOKUP_RADIUS),
+            );
+        }
+        lookup_state.apply_changes();
+
+        let mut expected_state = SpatialLookupState::with_algorithm(algorithms::Naive::default());
+        expected_state.entities = lookup_state.entities.clone();
+        expected_state.extents = lookup_state.extents.clone();
+        expected_state.prepare_algorithm();
+
+        let mut found = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+        let mut expected = expected_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+        found.sort();
+        expected.sort();
+
+        assert_eq!(lookup_state.entities.len(), 99_100);
+        assert_eq!(found, expected);
+        assert!(found.contains(&Entity::from_raw(1_500)));
+        assert!(found.contains(&Entity::from_raw(100_050)));
+    }
+
+    /// Helper function to check that `visit_in_radius` visits the same entities as
+    /// `entities_in_radius`, and stops as soon as the visitor breaks
+    fn assert_visit_in_radius_matches(mut lookup_state: SpatialLookupState) {
+        lookup_state.entities = world_with_n_entities(10_000);
+        lookup_state.extents = extents_for_n_entities(10_000);
+        lookup_state.prepare_algorithm();
+
+        let mut expected = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS * 2.);
+        let mut visited = Vec::new();
+        let flow =
+            lookup_state.visit_in_radius(Vec3::ZERO, LOOKUP_RADIUS * 2., |entity, position| {
+                assert!(lookup_state.entities.contains(&(entity, position)));
+                visited.push(entity);
+                ControlFlow::Continue(())
+            });
+        expected.sort();
+        visited.sort();
+
+        assert!(expected.len() > 5);
+        assert_eq!(flow, ControlFlow::Continue(()));
+        assert_eq!(visited, expected);
+
+        let mut visit_count = 0;
+        let flow =
+            lookup_state.visit_in_radius(Vec3::ZERO, LOOKUP_RADIUS * 2., |_entity, _position| {
+                visit_count += 1;
+                if visit_count == 5 {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                }
+            });
+
+        assert_eq!(flow, ControlFlow::Break(()));
+        assert_eq!(visit_count, 5);
+
+        let mut found = vec![Entity::PLACEHOLDER];
+        lookup_state.entities_in_radius_into(Vec3::ZERO, LOOKUP_RADIUS * 2., &mut found);
+        found.sort();
+
+        assert_eq!(found.len(), expected.len() + 1);
+        assert!(found.contains(&Entity::PLACEHOLDER));
+    }
+
+    #[test]
+    fn test_bvh_incremental_updates() {
+        assert_incremental_updates_match_prepare(SpatialLookupState::with_algorithm(
+            algorithms::Bvh::default(),
+        ));
+    }
+
+    #[test]
+    fn test_bvh_visit_in_radius() {
+        assert_visit_in_radius_matches(SpatialLookupState::with_algorithm(
+            algorithms::Bvh::default(),
+        ));
+    }
+
+    #[test]
+    fn test_naive_incremental_updates() {
+        assert_incremental_updates_match_prepare(SpatialLookupState::with_algorithm(
+            algorithms::Naive::default(),
+        ));
+    }
+
+    #[test]
+    fn test_naive_visit_in_radius() {
+        assert_visit_in_radius_matches(SpatialLookupState::with_algorithm(
+            algorithms::Naive::default(),
+        ));
+    }
+
+    #[test]
+    fn test_bvh_refit_incremental_updates() {
+        let mut bvh = algorithms::Bvh::default();
+        bvh.refit_max_surface_area_growth = Some(0.5);
+
+        assert_incremental_updates_match_prepare(SpatialLookupState::with_algorithm(bvh));
+    }
+
+    #[test]
+    fn test_bvh_small_leaves_k_nearest() {
+        let mut bvh = algorithms::Bvh::default();
+        bvh.entities_per_leaf = 8;
+
+        let mut lookup_state = SpatialLookupState::with_algorithm(bvh);
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        let found = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+        assert_eq!(found.len(), 39);
+
+        let found = lookup_state.k_nearest(Vec3::ZERO, K_NEAREST, LOOKUP_RADIUS);
+        let expected = sorted_k_nearest(&lookup_state.entities, Vec3::ZERO, K_NEAREST);
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn test_bvh_empty_world() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::Bvh::default());
+        lookup_state.prepare_algorithm();
+
+        assert!(
+            lookup_state
+                .entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS)
+                .is_empty()
+        );
+        assert!(
+            lookup_state
+                .k_nearest(Vec3::ZERO, K_NEAREST, LOOKUP_RADIUS)
+                .is_empty()
+        );
+    }
+
+    /// Helper function to make a BVH using the LBVH builder, with small enough leaves to make sure
+    /// the hierarchy gets tested.
+    fn lbvh() -> algorithms::Bvh {
+        let mut bvh = algorithms::Bvh::default();
+        bvh.build_strategy = algorithms::BvhBuildStrategy::Lbvh;
+        bvh.entities_per_leaf = 64;
+
+        bvh
+    }
+
+    #[test]
+    fn test_lbvh_in_range() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(lbvh());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        let found = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+
+        assert_eq!(found.len(), 39);
+    }
+
+    #[test]
+    fn test_lbvh_matches_naive() {
+        for plane in [None, Some(algorithms::SpatialPlane::XZ)] {
+            assert_matches_naive(|| {
+                let mut bvh = lbvh();
+                bvh.plane = plane;
+                bvh
+            });
+        }
+    }
+
+    #[test]
+    fn test_lbvh_with_extents() {
+        for plane in [None, Some(algorithms::SpatialPlane::XZ)] {
+            let mut bvh = lbvh();
+            bvh.plane = plane;
+
+            let mut lookup_state = SpatialLookupState::with_algorithm(bvh);
+            lookup_state.entities = world_with_n_entities(100_000);
+            lookup_state.extents = extents_for_n_entities(100_000);
+            lookup_state.prepare_algorithm();
+
+            let found = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+            assert_eq!(found.len(), 213);
+
+            let found = lookup_state.entities_in_aabb(-LOOKUP_HALF_EXTENTS, LOOKUP_HALF_EXTENTS);
+            assert_eq!(found.len(), 362);
+
+            let cuboid = Cuboid::from_size(LOOKUP_HALF_EXTENTS * 2.);
+            let found = lookup_state.entities_in_shape(&cuboid, Isometry3d::IDENTITY);
+            assert_eq!(found.len(), 362);
+
+            let ray = Ray3d::new(Vec3::new(-WORLD_SIZE, 0., 0.), Dir3::X);
+            let found = lookup_state.entities_along_ray(ray, WORLD_SIZE, LOOKUP_RADIUS);
+            assert_eq!(found.len(), 1058);
+        }
+    }
+
+    #[test]
+    fn test_lbvh_incremental_updates() {
+        let mut bvh = lbvh();
+        bvh.refit_max_surface_area_growth = Some(0.5);
+
+        assert_incremental_updates_match_prepare(SpatialLookupState::with_algorithm(bvh));
+    }
+
+    #[test]
+    fn test_lbvh_visit_in_radius() {
+        assert_visit_in_radius_matches(SpatialLookupState::with_algorithm(lbvh()));
+    }
+
+    #[test]
+    fn test_bvh_2d_with_extents() {
+        for plane in [algorithms::SpatialPlane::XY, algorithms::SpatialPlane::XZ] {
+            let mut bvh = algorithms::Bvh::default();
+            bvh.entities_per_leaf = 64;
+            bvh.plane = Some(plane);
+
+            let mut lookup_state = SpatialLookupState::with_algorithm(bvh);
+            lookup_state.entities = world_with_n_entities(100_000);
+            lookup_state.extents = extents_for_n_entities(100_000);
+            lookup_state.prepare_algorithm();
+
+            let found = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+            assert_eq!(found.len(), 213);
+
+            let found = lookup_state.entities_in_aabb(-LOOKUP_HALF_EXTENTS, LOOKUP_HALF_EXTENTS);
+            assert_eq!(found.len(), 362);
+
+            let ray = Ray3d::new(Vec3::new(-WORLD_SIZE, 0., 0.), Dir3::X);
+            let found = lookup_state.entities_along_ray(ray, WORLD_SIZE, LOOKUP_RADIUS);
+            assert_eq!(found.len(), 1058);
+
+            let found = lookup_state.k_nearest(Vec3::ZERO, 1, LOOKUP_RADIUS);
+            assert_eq!(found.len(), 1);
+        }
+    }
+
+    #[test]
+    fn test_bvh_2d_matches_naive() {
+        for plane in [algorithms::SpatialPlane::XY, algorithms::SpatialPlane::XZ] {
+            assert_matches_naive(|| {
+                let mut bvh = algorithms::Bvh::default();
+                bvh.entities_per_leaf = 64;
+                bvh.plane = Some(plane);
+                bvh
+            });
+        }
+    }
+
+    #[test]
+    fn test_bvh_2d_refit_incremental_updates() {
+        let mut bvh = algorithms::Bvh::default();
+        bvh.refit_max_surface_area_growth = Some(0.5);
+        bvh.plane = Some(algorithms::SpatialPlane::XZ);
+
+        assert_incremental_updates_match_prepare(SpatialLookupState::with_algorithm(bvh));
+    }
+
+    #[test]
+    fn test_hash_grid_in_range() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::HashGrid::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        let found = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+
+        assert_eq!(found.len(), 39);
+    }
+
+    #[test]
+    fn test_hash_grid_matches_naive() {
+        assert_matches_naive(algorithms::HashGrid::default);
+    }
+
+    #[test]
+    fn test_hash_grid_with_extents() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::HashGrid::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.extents = extents_for_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        let found = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+        assert_eq!(found.len(), 213);
+
+        let found = lookup_state.entities_in_aabb(-LOOKUP_HALF_EXTENTS, LOOKUP_HALF_EXTENTS);
+        assert_eq!(found.len(), 362);
+
+        let cuboid = Cuboid::from_size(LOOKUP_HALF_EXTENTS * 2.);
+        let found = lookup_state.entities_in_shape(&cuboid, Isometry3d::IDENTITY);
+        assert_eq!(found.len(), 362);
+
+        let ray = Ray3d::new(Vec3::new(-WORLD_SIZE, 0., 0.), Dir3::X);
+        let found = lookup_state.entities_along_ray(ray, WORLD_SIZE, LOOKUP_RADIUS);
+        assert_eq!(found.len(), 1058);
+    }
+
+    #[test]
+    fn test_hash_grid_incremental_updates() {
+        assert_incremental_updates_match_prepare(SpatialLookupState::with_algorithm(
+            algorithms::HashGrid::default(),
+        ));
+    }
+
+    #[test]
+    fn test_hash_grid_visit_in_radius() {
+        assert_visit_in_radius_matches(SpatialLookupState::with_algorithm(
+            algorithms::HashGrid::default(),
+        ));
+    }
+
+    #[test]
+    fn test_hash_grid_finds_large_extent() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::HashGrid::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.extents = vec![SpatialExtent::default(); 100_000];
+
+        // an entity far outside the world, but with a volume reaching the origin
+        let building = Entity::from_raw(100_000);
+        lookup_state
+            .entities
+            .push((building, Vec3::new(WORLD_SIZE * 3., 0., 0.)));
+        lookup_state
+            .extents
+            .push(SpatialExtent::Cuboid(Vec3::splat(WORLD_SIZE * 3.)));
+        lookup_state.prepare_algorithm();
+
+        let found = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+        assert_eq!(found.len(), 40);
+        assert!(found.contains(&building));
+
+        let found = lookup_state.k_nearest(Vec3::ZERO, 1, LOOKUP_RADIUS);
+        assert_eq!(found, vec![building]);
+    }
+
+    #[test]
+    fn test_hash_grid_finds_large_extent_after_removals() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::HashGrid::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.extents = vec![SpatialExtent::default(); 100_000];
+        lookup_state.prepare_algorithm();
+
+        // two entities far outside the world, with volumes reaching the origin
+        let building_extent = SpatialExtent::Cuboid(Vec3::splat(WORLD_SIZE * 3.));
+        let building = Entity::from_raw(100_000);
+        let other_building = Entity::from_raw(100_001);
+        lookup_state.begin_frame();
+        lookup_state.insert_or_update(
+            building,
+            Vec3::new(WORLD_SIZE * 3., 0., 0.),
+            building_extent,
+        );
+        lookup_state.insert_or_update(
+            other_building,
+            Vec3::new(-WORLD_SIZE * 3., 0., 0.),
+            building_extent,
+        );
+        lookup_state.apply_changes();
+        assert_eq!(
+            lookup_state
+                .entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS)
+                .len(),
+            41
+        );
+
+        // the remaining building is still as large as the largest removed one
+        lookup_state.begin_frame();
+        lookup_state.remove(other_building);
+        lookup_state.insert_or_update(
+            building,
+            Vec3::new(WORLD_SIZE * 3., 0., 1.),
+            building_extent,
+        );
+        lookup_state.apply_changes();
+        lookup_state.begin_frame();
+        let found = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+        assert_eq!(found.len(), 40);
+        assert!(found.contains(&building));
+
+        // a smaller building after the largest one was removed
+        lookup_state.remove(building);
+        lookup_state.apply_changes();
+        lookup_state.begin_frame();
+        assert_eq!(
+            lookup_state
+                .entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS)
+                .len(),
+            39
+        );
+        lookup_state.insert_or_update(
+            other_building,
+            Vec3::new(WORLD_SIZE * 2., 0., 0.),
+            SpatialExtent::Cuboid(Vec3::splat(WORLD_SIZE * 2.)),
+        );
+        lookup_state.apply_changes();
+        lookup_state.begin_frame();
+        let found = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+        assert_eq!(found.len(), 40);
+        assert!(found.contains(&other_building));
+    }
+
+    #[test]
+    fn test_hierarchical_grid_in_range() {
+        let mut lookup_state =
+            SpatialLookupState::with_algorithm(algorithms::HierarchicalGrid::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        let found = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+
+        assert_eq!(found.len(), 39);
+    }
+
+    #[test]
+    fn test_hierarchical_grid_matches_naive() {
+        assert_matches_naive(algorithms::HierarchicalGrid::default);
+    }
+
+    #[test]
+    fn test_hierarchical_grid_with_extents() {
+        let mut lookup_state =
+            SpatialLookupState::with_algorithm(algorithms::HierarchicalGrid::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.extents = extents_for_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        let found = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+        assert_eq!(found.len(), 213);
+
+        let found = lookup_state.entities_in_aabb(-LOOKUP_HALF_EXTENTS, LOOKUP_HALF_EXTENTS);
+        assert_eq!(found.len(), 362);
+
+        let cuboid = Cuboid::from_size(LOOKUP_HALF_EXTENTS * 2.);
+        let found = lookup_state.entities_in_shape(&cuboid, Isometry3d::IDENTITY);
+        assert_eq!(found.len(), 362);
+
+        let ray = Ray3d::new(Vec3::new(-WORLD_SIZE, 0., 0.), Dir3::X);
+        let found = lookup_state.entities_along_ray(ray, WORLD_SIZE, LOOKUP_RADIUS);
+        assert_eq!(found.len(), 1058);
+    }
+
+    #[test]
+    fn test_hierarchical_grid_incremental_updates() {
+        assert_incremental_updates_match_prepare(SpatialLookupState::with_algorithm(
+            algorithms::HierarchicalGrid::default(),
+        ));
+    }
+
+    #[test]
+    fn test_hierarchical_grid_visit_in_radius() {
+        assert_visit_in_radius_matches(SpatialLookupState::with_algorithm(
+            algorithms::HierarchicalGrid::default(),
+        ));
+    }
+
+    #[test]
+    fn test_hierarchical_grid_finds_large_extent() {
+        let mut lookup_state =
+            SpatialLookupState::with_algorithm(algorithms::HierarchicalGrid::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.extents = vec![SpatialExtent::default(); 100_000];
+
+        // an entity far outside the world, but with a volume reaching the origin
+        let building = Entity::from_raw(100_000);
+        lookup_state
+            .entities
+            .push((building, Vec3::new(WORLD_SIZE * 3., 0., 0.)));
+        lookup_state
+            .extents
+            .push(SpatialExtent::Cuboid(Vec3::splat(WORLD_SIZE * 3.)));
+        lookup_state.prepare_algorithm();
+
+        let found = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+        assert_eq!(found.len(), 40);
+        assert!(found.contains(&building));
+
+        let found = lookup_state.k_nearest(Vec3::ZERO, 1, LOOKUP_RADIUS);
+        assert_eq!(found, vec![building]);
+    }
+
+    #[test]
+    fn test_hierarchical_grid_overlapping_pairs() {
+        let mut lookup_state =
+            SpatialLookupState::with_algorithm(algorithms::HierarchicalGrid::default());
+        lookup_state.entities = world_with_n_entities(10_000);
+        lookup_state.extents = extents_for_n_entities(10_000);
+
+        // a few huge entities overlapping many small ones
+        for (index, half_size) in [2., 5., WORLD_SIZE].into_iter().enumerate() {
+            lookup_state
+                .entities
+                .push((Entity::from_raw(10_000 + index as u32), Vec3::ZERO));
+            lookup_state
+                .extents
+                .push(SpatialExtent::Cuboid(Vec3::splat(half_size)));
+        }
+        lookup_state.prepare_algorithm();
+
+        let mut expected_state = SpatialLookupState::with_algorithm(algorithms::Naive::default());
+        expected_state.entities = lookup_state.entities.clone();
+        expected_state.extents = lookup_state.extents.clone();
+        expected_state.prepare_algorithm();
+
+        let sorted_pairs = |pairs: Vec<(Entity, Entity)>| {
+            let mut pairs: Vec<_> = pairs
+                .into_iter()
+                .map(|(entity, other)| (entity.min(other), entity.max(other)))
+                .collect();
+            pairs.sort();
+            pairs
+        };
+        let found = sorted_pairs(lookup_state.overlapping_pairs());
+        let expected = sorted_pairs(expected_state.overlapping_pairs());
+
+        assert!(expected.len() > 10_000);
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn test_octree_in_range() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::Octree::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        let found = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+
+        assert_eq!(found.len(), 39);
+    }
+
+    #[test]
+    fn test_octree_matches_naive() {
+        assert_matches_naive(algorithms::Octree::default);
+    }
+
+    #[test]
+    fn test_octree_with_extents() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::Octree::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.extents = extents_for_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        let found = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+        assert_eq!(found.len(), 213);
+
+        let found = lookup_state.entities_in_aabb(-LOOKUP_HALF_EXTENTS, LOOKUP_HALF_EXTENTS);
+        assert_eq!(found.len(), 362);
+
+        let cuboid = Cuboid::from_size(LOOKUP_HALF_EXTENTS * 2.);
+        let found = lookup_state.entities_in_shape(&cuboid, Isometry3d::IDENTITY);
+        assert_eq!(found.len(), 362);
+
+        let ray = Ray3d::new(Vec3::new(-WORLD_SIZE, 0., 0.), Dir3::X);
+        let found = lookup_state.entities_along_ray(ray, WORLD_SIZE, LOOKUP_RADIUS);
+        assert_eq!(found.len(), 1058);
+    }
+
+    #[test]
+    fn test_octree_incremental_updates() {
+        assert_incremental_updates_match_prepare(SpatialLookupState::with_algorithm(
+            algorithms::Octree::default(),
+        ));
+    }
+
+    #[test]
+    fn test_octree_visit_in_radius() {
+        assert_visit_in_radius_matches(SpatialLookupState::with_algorithm(
+            algorithms::Octree::default(),
+        ));
+    }
+
+    #[test]
+    fn test_octree_finds_large_extent() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::Octree::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.extents = vec![SpatialExtent::default(); 100_000];
+
+        // an entity far outside the world, but with a volume reaching the origin
+        let building = Entity::from_raw(100_000);
+        lookup_state
+            .entities
+            .push((building, Vec3::new(WORLD_SIZE * 3., 0., 0.)));
+        lookup_state
+            .extents
+            .push(SpatialExtent::Cuboid(Vec3::splat(WORLD_SIZE * 3.)));
+        lookup_state.prepare_algorithm();
+
+        let found = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+        assert_eq!(found.len(), 40);
+        assert!(found.contains(&building));
+
+        let found = lookup_state.k_nearest(Vec3::ZERO, 1, LOOKUP_RADIUS);
+        assert_eq!(found, vec![building]);
+
+        // outside of the world of the other entities, only the volume of the building is found
+        let sample_point = Vec3::new(WORLD_SIZE * 5.8, 0., 0.);
+        let found = lookup_state.entities_in_radius(sample_point, LOOKUP_RADIUS);
+        assert_eq!(found, vec![building]);
+
+        let found = lookup_state.entities_in_aabb(sample_point - 1., sample_point + 1.);
+        assert_eq!(found, vec![building]);
+    }
+
+    #[test]
+    fn test_octree_finds_large_extent_off_center() {
+        assert_finds_large_extent_off_center(SpatialLookupState::with_algorithm(
+            algorithms::Octree::default(),
+        ));
+    }
+
+    #[test]
+    fn test_quadtree_in_range() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::Quadtree::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        let found = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+
+        assert_eq!(found.len(), 39);
+    }
+
+    #[test]
+    fn test_quadtree_matches_naive() {
+        assert_matches_naive(algorithms::Quadtree::default);
+    }
+
+    #[test]
+    fn test_quadtree_with_extents() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::Quadtree::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.extents = extents_for_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        let found = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+        assert_eq!(found.len(), 213);
+
+        let found = lookup_state.entities_in_aabb(-LOOKUP_HALF_EXTENTS, LOOKUP_HALF_EXTENTS);
+        assert_eq!(found.len(), 362);
+
+        let cuboid = Cuboid::from_size(LOOKUP_HALF_EXTENTS * 2.);
+        let found = lookup_state.entities_in_shape(&cuboid, Isometry3d::IDENTITY);
+        assert_eq!(found.len(), 362);
+
+        let ray = Ray3d::new(Vec3::new(-WORLD_SIZE, 0., 0.), Dir3::X);
+        let found = lookup_state.entities_along_ray(ray, WORLD_SIZE, LOOKUP_RADIUS);
+        assert_eq!(found.len(), 1058);
+    }
+
+    #[test]
+    fn test_quadtree_incremental_updates() {
+        assert_incremental_updates_match_prepare(SpatialLookupState::with_algorithm(
+            algorithms::Quadtree::default(),
+        ));
+    }
+
+    #[test]
+    fn test_quadtree_visit_in_radius() {
+        assert_visit_in_radius_matches(SpatialLookupState::with_algorithm(
+            algorithms::Quadtree::default(),
+        ));
+    }
+
+    #[test]
+    fn test_quadtree_finds_large_extent() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::Quadtree::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.extents = vec![SpatialExtent::default(); 100_000];
+
+        // an entity far outside the world, but with a volume reaching the origin
+        let building = Entity::from_raw(100_000);
+        lookup_state
+            .entities
+            .push((building, Vec3::new(WORLD_SIZE * 3., 0., 0.)));
+        lookup_state
+            .extents
+            .push(SpatialExtent::Cuboid(Vec3::splat(WORLD_SIZE * 3.)));
+        lookup_state.prepare_algorithm();
+
+        let found = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+        assert_eq!(found.len(), 40);
+        assert!(found.contains(&building));
+
+        let found = lookup_state.k_nearest(Vec3::ZERO, 1, LOOKUP_RADIUS);
+        assert_eq!(found, vec![building]);
+
+        // outside of the world of the other entities, only the volume of the building is found
+        let sample_point = Vec3::new(WORLD_SIZE * 5.8, 0., 0.);
+        let found = lookup_state.entities_in_radius(sample_point, LOOKUP_RADIUS);
+        assert_eq!(found, vec![building]);
+
+        let found = lookup_state.entities_in_aabb(sample_point - 1., sample_point + 1.);
+        assert_eq!(found, vec![building]);
+    }
+    #[test]
+    fn test_quadtree_finds_large_extent_off_center() {
+        assert_finds_large_extent_off_center(SpatialLookupState::with_algorithm(
+            algorithms::Quadtree::default(),
+        ));
+    }
+
+    #[test]
+    fn test_quadtree_xz_with_extents() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(
+            algorithms::Quadtree::with_plane(algorithms::SpatialPlane::XZ),
+        );
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.extents = extents_for_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        let found = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+        assert_eq!(found.len(), 213);
+
+        let found = lookup_state.entities_in_aabb(-LOOKUP_HALF_EXTENTS, LOOKUP_HALF_EXTENTS);
+        assert_eq!(found.len(), 362);
+
+        let cuboid = Cuboid::from_size(LOOKUP_HALF_EXTENTS * 2.);
+        let found = lookup_state.entities_in_shape(&cuboid, Isometry3d::IDENTITY);
+        assert_eq!(found.len(), 362);
+
+        let ray = Ray3d::new(Vec3::new(-WORLD_SIZE, 0., 0.), Dir3::X);
+        let found = lookup_state.entities_along_ray(ray, WORLD_SIZE, LOOKUP_RADIUS);
+        assert_eq!(found.len(), 1058);
+    }
+
+    #[test]
+    fn test_quadtree_xz_matches_naive() {
+        assert_matches_naive(|| algorithms::Quadtree::with_plane(algorithms::SpatialPlane::XZ));
+    }
+
+    #[test]
+    fn test_kd_tree_in_range() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::KdTree::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        let found = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+
+        assert_eq!(found.len(), 39);
+    }
+
+    #[test]
+    fn test_kd_tree_matches_naive() {
+        assert_matches_naive(algorithms::KdTree::default);
+    }
+
+    #[test]
+    fn test_kd_tree_with_extents() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::KdTree::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.extents = extents_for_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        let found = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+        assert_eq!(found.len(), 213);
+
+        let found = lookup_state.entities_in_aabb(-LOOKUP_HALF_EXTENTS, LOOKUP_HALF_EXTENTS);
+        assert_eq!(found.len(), 362);
+
+        let cuboid = Cuboid::from_size(LOOKUP_HALF_EXTENTS * 2.);
+        let found = lookup_state.entities_in_shape(&cuboid, Isometry3d::IDENTITY);
+        assert_eq!(found.len(), 362);
+
+        let ray = Ray3d::new(Vec3::new(-WORLD_SIZE, 0., 0.), Dir3::X);
+        let found = lookup_state.entities_along_ray(ray, WORLD_SIZE, LOOKUP_RADIUS);
+        assert_eq!(found.len(), 1058);
+    }
+
+    #[test]
+    fn test_kd_tree_incremental_updates() {
+        assert_incremental_updates_match_prepare(SpatialLookupState::with_algorithm(
+            algorithms::KdTree::default(),
+        ));
+    }
+
+    #[test]
+    fn test_kd_tree_visit_in_radius() {
+        assert_visit_in_radius_matches(SpatialLookupState::with_algorithm(
+            algorithms::KdTree::default(),
+        ));
+    }
+
+    #[test]
+    fn test_kd_tree_finds_large_extent() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::KdTree::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.extents = vec![SpatialExtent::default(); 100_000];
+
+        // an entity far outside the world, but with a volume reaching the origin
+        let building = Entity::from_raw(100_000);
+        lookup_state
+            .entities
+            .push((building, Vec3::new(WORLD_SIZE * 3., 0., 0.)));
+        lookup_state
+            .extents
+            .push(SpatialExtent::Cuboid(Vec3::splat(WORLD_SIZE * 3.)));
+        lookup_state.prepare_algorithm();
+
+        let found = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+        assert_eq!(found.len(), 40);
+        assert!(found.contains(&building));
+
+        let found = lookup_state.k_nearest(Vec3::ZERO, 1, LOOKUP_RADIUS);
+        assert_eq!(found, vec![building]);
+    }
+
+    #[test]
+    fn test_sweep_and_prune_in_range() {
+        let mut lookup_state =
+            SpatialLookupState::with_algorithm(algorithms::SweepAndPrune::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        let found = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+
+        assert_eq!(found.len(), 39);
+    }
+
+    #[test]
+    fn test_sweep_and_prune_matches_naive() {
+        assert_matches_naive(algorithms::SweepAndPrune::default);
+    }
+
+    #[test]
+    fn test_sweep_and_prune_xz_matches_naive() {
+        assert_matches_naive(|| {
+            algorithms::SweepAndPrune::with_axes(BVec3::new(true, false, true))
+        });
+    }
+
+    #[test]
+    fn test_sweep_and_prune_with_extents() {
+        let mut lookup_state =
+            SpatialLookupState::with_algorithm(algorithms::SweepAndPrune::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.extents = extents_for_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        let found = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+        assert_eq!(found.len(), 213);
+
+        let found = lookup_state.entities_in_aabb(-LOOKUP_HALF_EXTENTS, LOOKUP_HALF_EXTENTS);
+        assert_eq!(found.len(), 362);
+
+        let cuboid = Cuboid::from_size(LOOKUP_HALF_EXTENTS * 2.);
+        let found = lookup_state.entities_in_shape(&cuboid, Isometry3d::IDENTITY);
+        assert_eq!(found.len(), 362);
+
+        let ray = Ray3d::new(Vec3::new(-WORLD_SIZE, 0., 0.), Dir3::X);
+        let found = lookup_state.entities_along_ray(ray, WORLD_SIZE, LOOKUP_RADIUS);
+        assert_eq!(found.len(), 1058);
+    }
+
+    #[test]
+    fn test_sweep_and_prune_incremental_updates() {
+        assert_incremental_updates_match_prepare(SpatialLookupState::with_algorithm(
+            algorithms::SweepAndPrune::default(),
+        ));
+    }
+
+    #[test]
+    fn test_sweep_and_prune_visit_in_radius() {
+        assert_visit_in_radius_matches(SpatialLookupState::with_algorithm(
+            algorithms::SweepAndPrune::default(),
+        ));
+    }
+
+    #[test]
+    fn test_sweep_and_prune_finds_large_extent() {
+        let mut lookup_state =
+            SpatialLookupState::with_algorithm(algorithms::SweepAndPrune::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.extents = vec![SpatialExtent::default(); 100_000];
+
+        // an entity far outside the world, but with a volume reaching the origin
+        let building = Entity::from_raw(100_000);
+        lookup_state
+            .entities
+            .push((building, Vec3::new(WORLD_SIZE * 3., 0., 0.)));
+        lookup_state
+            .extents
+            .push(SpatialExtent::Cuboid(Vec3::splat(WORLD_SIZE * 3.)));
+        lookup_state.prepare_algorithm();
+
+        let found = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+        assert_eq!(found.len(), 40);
+        assert!(found.contains(&building));
+
+        let found = lookup_state.k_nearest(Vec3::ZERO, 1, LOOKUP_RADIUS);
+        assert_eq!(found, vec![building]);
+    }
+
+    #[test]
+    fn test_sweep_and_prune_overlapping_pairs() {
+        let mut lookup_state =
+            SpatialLookupState::with_algorithm(algorithms::SweepAndPrune::default());
+        lookup_state.entities = world_with_n_entities(10_000);
+        lookup_state.extents = extents_for_n_entities(10_000);
+        lookup_state.prepare_algorithm();
+
+        let mut expected_state = SpatialLookupState::with_algorithm(algorithms::Naive::default());
+        expected_state.entities = lookup_state.entities.clone();
+        expected_state.extents = lookup_state.extents.clone();
+        expected_state.prepare_algorithm();
+
+        let sorted_pairs = |pairs: Vec<(Entity, Entity)>| {
+            let mut pairs: Vec<_> = pairs
+                .into_iter()
+                .map(|(entity, other)| (entity.min(other), entity.max(other)))
+                .collect();
+            pairs.sort();
+            pairs
+        };
+        let found = sorted_pairs(lookup_state.overlapping_pairs());
+        let expected = sorted_pairs(expected_state.overlapping_pairs());
+
+        assert!(!expected.is_empty());
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn test_sweep_and_prune_moving_entities() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(
+            algorithms::SweepAndPrune::with_axes(BVec3::new(true, false, true)),
+        );
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        // move every entity a little, and replace some of them with new entities
+        for (entity, position) in &mut lookup_state.entities {
+            *position += Vec3::splat((entity.index() % 7) as f32 * 0.01 - 0.03);
+            if entity.index() % 100 == 0 {
+                *entity = Entity::from_raw(entity.index() + 100_000);
+            }
+        }
+        lookup_state.entities.truncate(99_000);
+        lookup_state.prepare_algorithm();
+
+        let mut expected_state = SpatialLookupState::with_algorithm(algorithms::Naive::default());
+        expected_state.entities = lookup_state.entities.clone();
+        expected_state.prepare_algorithm();
+
+        let mut found = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+        let mut expected = expected_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+        found.sort();
+        expected.sort();
+        assert_eq!(found, expected);
+
+        let mut found = lookup_state.entities_in_aabb(-LOOKUP_HALF_EXTENTS, LOOKUP_HALF_EXTENTS);
+        let mut expected =
+            expected_state.entities_in_aabb(-LOOKUP_HALF_EXTENTS, LOOKUP_HALF_EXTENTS);
+        found.sort();
+        expected.sort();
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn test_adaptive_in_range() {
+        let mut lookup_state = SpatialLookupState::with_algorithm(algorithms::Adaptive::default());
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+
+        let found = lookup_state.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+
+        assert_eq!(found.len(), 39);
+    }
+
+    #[test]
+    fn test_adaptive_matches_naive() {
+        assert_matches_naive(algorithms::Adaptive::default);
+        assert_matches_naive(|| {
+            algorithms::Adaptive::new(algorithms::Bvh::default(), algorithms::AdaptiveCost::BVH)
+        });
+    }
+
+    #[test]
+    fn test_adaptive_incremental_updates() {
+        assert_incremental_updates_match_prepare(SpatialLookupState::with_algorithm(
+            algorithms::Adaptive::default(),
+        ));
+    }
+
+    #[test]
+    fn test_adaptive_visit_in_radius() {
+        assert_visit_in_radius_matches(SpatialLookupState::with_algorithm(
+            algorithms::Adaptive::default(),
+        ));
+    }
+
+    #[test]
+    fn test_adaptive_counts_only_accepted_changes() {
+        let mut adaptive =
+            algorithms::Adaptive::new(algorithms::Bvh::default(), algorithms::AdaptiveCost::BVH);
+        adaptive.prepare(&world_with_n_entities(1_000));
+        adaptive.begin_frame();
+        let costs: Vec<f32> = adaptive.estimated_costs().collect();
+
+        // the BVH rejects incremental updates, so the lookup is rebuilt instead
+        assert!(!adaptive.insert(
+            Entity::from_raw(1_000),
+            Vec3::ZERO,
+            SpatialExtent::default()
+        ));
+        assert!(!adaptive.remove(Entity::from_raw(0)));
+        assert_eq!(adaptive.estimated_costs().collect::<Vec<_>>(), costs);
+
+        let mut adaptive = algorithms::Adaptive::new(
+            algorithms::Naive::default(),
+            algorithms::AdaptiveCost::NAIVE,
+        );
+        adaptive.prepare(&world_with_n_entities(1_000));
+        adaptive.begin_frame();
+        let costs: Vec<f32> = adaptive.estimated_costs().collect();
+
+        // changes to entities which aren't in the lookup, or already are, aren't applied
+        assert!(!adaptive.remove(Entity::from_raw(5_000)));
+        assert!(!adaptive.update(
+            Entity::from_raw(5_000),
+            Vec3::ZERO,
+            SpatialExtent::default()
+        ));
+        assert!(!adaptive.insert(Entity::from_raw(0), Vec3::ZERO, SpatialExtent::default()));
+        assert_eq!(adaptive.estimated_costs().collect::<Vec<_>>(), costs);
+
+        assert!(adaptive.remove(Entity::from_raw(0)));
+        assert_ne!(adaptive.estimated_costs().collect::<Vec<_>>(), costs);
+    }
+
+    /// Algorithm which only implements the required methods, so that `SpatialLookupState` falls
+    /// back to `entities_in_radius` and the naive scans
+    #[derive(Default)]
+    struct RadiusOnly(algorithms::Naive);
+
+    impl SpatialLookupAlgorithm for RadiusOnly {
+        fn prepare(&mut self, entities: &[(Entity, Vec3)]) {
+            self.0.prepare(entities);
+        }
+
+        fn entities_in_radius(&self, sample_point: Vec3, radius: f32) -> Vec<Entity> {
+            self.0.entities_in_radius(sample_point, radius)
+        }
+    }
+
+    #[test]
+    fn test_adaptive_counts_each_query_once() {
+        let mut adaptive =
+            algorithms::Adaptive::new(RadiusOnly::default(), algorithms::AdaptiveCost::NAIVE);
+        adaptive.smoothing = 1.;
+
+        adaptive.prepare(&world_with_n_entities(1_000));
+
+        // `SpatialLookupState` falls back to `entities_in_radius` for the unanswered visit
+        assert!(
+            adaptive
+                .visit_in_radius(Vec3::ZERO, LOOKUP_RADIUS, &mut |_entity, _position| {
+                    ControlFlow::Continue(())
+                })
+                .is_none()
+        );
+        adaptive.entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS);
+        // and to a naive scan for the unanswered aabb query
+        assert!(adaptive.entities_in_aabb(Vec3::ZERO, Vec3::ONE).is_none());
+        adaptive.begin_frame();
+
+        assert_eq!(adaptive.queries_per_frame(), 1.);
+    }
+
+    #[test]
+    fn test_adaptive_switches_algorithm() {
+        let mut adaptive = algorithms::Adaptive::default();
+        adaptive.min_frames_between_switches = 10;
+
+        let mut lookup_state = SpatialLookupState::with_algorithm(adaptive);
+        lookup_state.entities = world_with_n_entities(100_000);
+        lookup_state.prepare_algorithm();
+        assert_eq!(
+            lookup_state.algorithm.name(),
+            std::any::type_name::<algorithms::Naive>()
+        );
+
+        // many queries on a static world favour the BVH
+        for _ in 0..20 {
+            lookup_state.begin_frame();
+            lookup_state.apply_changes();
+            for _ in 0..100 {
+                assert_eq!(
+                    lookup_state
+                        .entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS)
+                        .len(),
+                    39
+                );
+            }
+        }
+        assert_eq!(
+            lookup_state.algorithm.name(),
+            std::any::type_name::<algorithms::Bvh>()
+        );
+
+        // rebuilding every frame without queries favours the naive algorithm
+        for _ in 0..100 {
+            lookup_state.begin_frame();
+            lookup_state.prepare_algorithm();
+        }
+        lookup_state.begin_frame();
+        lookup_state.apply_changes();
+        assert_eq!(
+            lookup_state.algorithm.name(),
+            std::any::type_name::<algorithms::Naive>()
+        );
+        assert_eq!(
+            lookup_state
+                .entities_in_radius(Vec3::ZERO, LOOKUP_RADIUS)
+                .len(),
+            39
+        );
+    }
 }
@@ -1,6 +1,11 @@
 //! Naive Spatial Lookup: Just iterate all entities every time!
+use super::nearest::KNearest;
 use crate::prelude::*;
+use crate::spatial_extent::with_extents;
+use bevy::ecs::entity::EntityHashMap;
+use bevy::math::FloatOrd;
 use bevy::prelude::*;
+use std::ops::ControlFlow;
 
 /// Naive spatial lookup: just iterate all entities every time.
 ///
@@ -10,22 +15,314 @@ use bevy::prelude::*;
 #[derive(Debug, Default)]
 pub struct Naive {
     entities: Vec<(Entity, Vec3)>,
+    extents: Vec<SpatialExtent>,
+    /// Index of each entity in `entities`, built on the first incremental update.
+    indices: EntityHashMap<usize>,
+}
+
+impl Naive {
+    /// Makes sure `extents` and `indices` match `entities` before an incremental update.
+    fn prepare_incremental_update(&mut self) {
+        if self.extents.len() != self.entities.len() {
+            self.extents
+                .resize(self.entities.len(), SpatialExtent::default());
+        }
+
+        if self.indices.len() != self.entities.len() {
+            self.indices = self
+                .entities
+                .iter()
+                .enumerate()
+                .map(|(index, (entity, _position))| (*entity, index))
+                .collect();
+        }
+    }
 }
 
 impl SpatialLookupAlgorithm for Naive {
     fn prepare(&mut self, entities: &[(Entity, Vec3)]) {
+        self.prepare_with_extents(entities, &[]);
+    }
+
+    fn prepare_with_extents(&mut self, entities: &[(Entity, Vec3)], extents: &[SpatialExtent]) {
         self.entities = entities.to_owned();
+        self.extents = extents.to_owned();
+        self.indices.clear();
+    }
+
+    // Changes to entities which aren't in the lookup, or inserting an entity twice, return false
+    // so that wrappers like `Adaptive` only count the changes which were applied.
+    fn insert(&mut self, entity: Entity, position: Vec3, extent: SpatialExtent) -> bool {
+        self.prepare_incremental_update();
+
+        if self.indices.contains_key(&entity) {
+            return false;
+        }
+
+        self.indices.insert(entity, self.entities.len());
+        self.entities.push((entity, position));
+        self.extents.push(extent);
+
+        true
+    }
+
+    fn update(&mut self, entity: Entity, position: Vec3, extent: SpatialExtent) -> bool {
+        self.prepare_incremental_update();
+
+        let Some(&index) = self.indices.get(&entity) else {
+            return false;
+        };
+        self.entities[index].1 = position;
+        self.extents[index] = extent;
+
+        true
+    }
+
+    fn remove(&mut self, entity: Entity) -> bool {
+        self.prepare_incremental_update();
+
+        let Some(index) = self.indices.remove(&entity) else {
+            return false;
+        };
+        self.entities.swap_remove(index);
+        self.extents.swap_remove(index);
+
+        if let Some((moved_entity, _position)) = self.entities.get(index) {
+            self.indices.insert(*moved_entity, index);
+        }
+
+        true
     }
 
     fn entities_in_radius(&self, sample_point: Vec3, radius: f32) -> Vec<Entity> {
         let mut found_entities = Vec::new();
 
-        for (entity, position) in &self.entities {
-            if position.distance(sample_point) <= radius {
-                found_entities.push(*entity);
+        for (entity, position, extent) in with_extents(&self.entities, &self.extents) {
+            if extent.distance_squared_to_point(position, sample_point) <= radius * radius {
+                found_entities.push(entity);
             }
         }
 
         found_entities
     }
+
+    fn visit_in_radius(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+        visit: &mut dyn FnMut(Entity, Vec3) -> ControlFlow<()>,
+    ) -> Option<ControlFlow<()>> {
+        Some(visit_in_radius(
+            &self.entities,
+            &self.extents,
+            sample_point,
+            radius,
+            visit,
+        ))
+    }
+
+    fn entities_in_aabb(&self, min: Vec3, max: Vec3) -> Option<Vec<Entity>> {
+        Some(entities_in_aabb(&self.entities, &self.extents, min, max))
+    }
+
+    fn entities_in_shape(
+        &self,
+        shape: &dyn SpatialShape,
+        isometry: Isometry3d,
+    ) -> Option<Vec<Entity>> {
+        Some(entities_in_shape(
+            &self.entities,
+            &self.extents,
+            shape,
+            isometry,
+        ))
+    }
+
+    fn entities_along_ray(
+        &self,
+        ray: Ray3d,
+        max_distance: f32,
+        thickness: f32,
+    ) -> Option<Vec<(Entity, f32)>> {
+        Some(entities_along_ray(
+            &self.entities,
+            &self.extents,
+            ray,
+            max_distance,
+            thickness,
+        ))
+    }
+
+    fn k_nearest(&self, sample_point: Vec3, k: usize, max_distance: f32) -> Option<Vec<Entity>> {
+        Some(k_nearest(
+            &self.entities,
+            &self.extents,
+            sample_point,
+            k,
+            max_distance,
+        ))
+    }
+
+    fn overlapping_pairs(&self) -> Option<Vec<(Entity, Entity)>> {
+        Some(overlapping_pairs(&self.entities, &self.extents))
+    }
+}
+
+/// Visits all entities in radius of the sample point by scanning every entity, until `visit`
+/// returns `ControlFlow::Break`.
+fn visit_in_radius(
+    entities: &[(Entity, Vec3)],
+    extents: &[SpatialExtent],
+    sample_point: Vec3,
+    radius: f32,
+    visit: &mut dyn FnMut(Entity, Vec3) -> ControlFlow<()>,
+) -> ControlFlow<()> {
+    for (entity, position, extent) in with_extents(entities, extents) {
+        if extent.distance_squared_to_point(position, sample_point) <= radius * radius {
+            visit(entity, position)?;
+        }
+    }
+
+    ControlFlow::Continue(())
+}
+
+/// Finds all entities inside the axis-aligned box spanned by `min` and `max` by scanning every
+/// entity.
+///
+/// This is also used by `SpatialLookupState` for algorithms that don't implement
+/// `entities_in_aabb`.
+pub(crate) fn entities_in_aabb(
+    entities: &[(Entity, Vec3)],
+    extents: &[SpatialExtent],
+    min: Vec3,
+    max: Vec3,
+) -> Vec<Entity> {
+    let mut found_entities = Vec::new();
+
+    for (entity, position, extent) in with_extents(entities, extents) {
+        if extent.intersects_aabb(position, min, max) {
+            found_entities.push(entity);
+        }
+    }
+
+    found_entities
+}
+
+/// Finds all entities inside the given shape by scanning every entity.
+///
+/// This is also used by `SpatialLookupState` for algorithms that don't implement
+/// `entities_in_shape`.
+pub(crate) fn entities_in_shape(
+    entities: &[(Entity, Vec3)],
+    extents: &[SpatialExtent],
+    shape: &dyn SpatialShape,
+    isometry: Isometry3d,
+) -> Vec<Entity> {
+    let mut found_entities = Vec::new();
+
+    for (entity, position, extent) in with_extents(entities, extents) {
+        if extent.intersects_shape(position, shape, isometry) {
+            found_entities.push(entity);
+        }
+    }
+
+    found_entities
+}
+
+/// Finds all entities within `thickness` of the ray by scanning every entity, sorted by their
+/// distance along the ray.
+///
+/// This is also used by `SpatialLookupState` for algorithms that don't implement
+/// `entities_along_ray`.
+pub(crate) fn entities_along_ray(
+    entities: &[(Entity, Vec3)],
+    extents: &[SpatialExtent],
+    ray: Ray3d,
+    max_distance: f32,
+    thickness: f32,
+) -> Vec<(Entity, f32)> {
+    let mut found_entities = Vec::new();
+
+    for (entity, position, extent) in with_extents(entities, extents) {
+        if let Some(distance) = extent.distance_along_ray(position, ray, max_distance, thickness) {
+            found_entities.push((entity, distance));
+        }
+    }
+
+    found_entities.sort_unstable_by_key(|(_entity, distance)| FloatOrd(*distance));
+
+    found_entities
+}
+
+/// Finds the `k` nearest entities by scanning every entity, keeping the current best candidates
+/// in a bounded max-heap.
+///
+/// This is also used by `SpatialLookupState` for algorithms that don't implement `k_nearest`.
+pub(crate) fn k_nearest(
+    entities: &[(Entity, Vec3)],
+    extents: &[SpatialExtent],
+    sample_point: Vec3,
+    k: usize,
+    max_distance: f32,
+) -> Vec<Entity> {
+    if k == 0 {
+        return Vec::new();
+    }
+
+    let mut nearest = KNearest::new(k, max_distance);
+    for (entity, position, extent) in with_extents(entities, extents) {
+        nearest.consider(
+            entity,
+            extent.distance_squared_to_point(position, sample_point),
+        );
+    }
+
+    nearest.into_sorted_vec()
+}
+
+/// Finds the nearest entity for which `predicate` returns true by scanning every entity.
+///
+/// The predicate is only evaluated for entities closer than the current best match.
+pub(crate) fn nearest_matching(
+    entities: &[(Entity, Vec3)],
+    extents: &[SpatialExtent],
+    sample_point: Vec3,
+    mut predicate: impl FnMut(Entity) -> bool,
+) -> Option<Entity> {
+    let mut nearest: Option<(f32, Entity)> = None;
+
+    for (entity, position, extent) in with_extents(entities, extents) {
+        let distance_squared = extent.distance_squared_to_point(position, sample_point);
+        if nearest.is_some_and(|(nearest_distance, _)| distance_squared >= nearest_distance) {
+            continue;
+        }
+
+        if predicate(entity) {
+            nearest = Some((distance_squared, entity));
+        }
+    }
+
+    nearest.map(|(_distance, entity)| entity)
+}
+
+/// Finds every pair of entities whose volumes overlap by testing every pair of entities.
+///
+/// This is also used by `SpatialLookupState` for algorithms that don't implement
+/// `overlapping_pairs`.
+pub(crate) fn overlapping_pairs(
+    entities: &[(Entity, Vec3)],
+    extents: &[SpatialExtent],
+) -> Vec<(Entity, Entity)> {
+    let entities: Vec<_> = with_extents(entities, extents).collect();
+    let mut pairs = Vec::new();
+
+    for (index, (entity, position, extent)) in entities.iter().enumerate() {
+        for (other_entity, other_position, other_extent) in &entities[index + 1..] {
+            if extent.intersects_extent(*position, other_extent, *other_position) {
+                pairs.push((*entity, *other_entity));
+            }
+        }
+    }
+
+    pairs
 }
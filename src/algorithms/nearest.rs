@@ -0,0 +1,176 @@
+//! Building blocks for nearest-neighbour searches shared by the algorithms.
+
+use crate::SpatialExtent;
+use bevy::math::FloatOrd;
+use bevy::prelude::*;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+/// Item paired with its squared distance to a sample point, used for best-first traversal.
+///
+/// Ordering is reversed so that `BinaryHeap` pops the closest item first.
+pub(crate) struct ByDistance<T> {
+    pub(crate) distance_squared: f32,
+    pub(crate) item: T,
+}
+
+impl<T> PartialEq for ByDistance<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for ByDistance<T> {}
+
+impl<T> PartialOrd for ByDistance<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for ByDistance<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        FloatOrd(other.distance_squared).cmp(&FloatOrd(self.distance_squared))
+    }
+}
+
+/// The `k` nearest entities found so far, kept in a bounded max-heap so the furthest one can be
+/// replaced when a closer entity is found.
+pub(crate) struct KNearest {
+    k: usize,
+    max_distance_squared: f32,
+    nearest: BinaryHeap<(FloatOrd, Entity)>,
+}
+
+impl KNearest {
+    pub(crate) fn new(k: usize, max_distance: f32) -> Self {
+        KNearest {
+            k,
+            max_distance_squared: max_distance * max_distance,
+            nearest: BinaryHeap::with_capacity(k + 1),
+        }
+    }
+
+    /// Returns the squared distance an entity must be within to be one of the `k` nearest.
+    ///
+    /// Starts at the maximum distance and shrinks to the distance of the `k`th nearest entity
+    /// once `k` entities have been found, so searches can skip anything further away.
+    #[inline]
+    pub(crate) fn max_distance_squared(&self) -> f32 {
+        self.max_distance_squared
+    }
+
+    /// Keeps the entity if it's closer than the current `k`th nearest entity.
+    #[inline]
+    pub(crate) fn consider(&mut self, entity: Entity, distance_squared: f32) {
+        if self.k == 0 || distance_squared > self.max_distance_squared {
+            return;
+        }
+
+        if self.nearest.len() < self.k {
+            self.nearest.push((FloatOrd(distance_squared), entity));
+        } else if let Some(mut furthest) = self.nearest.peek_mut()
+            && FloatOrd(distance_squared) < furthest.0
+        {
+            *furthest = (FloatOrd(distance_squared), entity);
+        }
+
+        if self.nearest.len() == self.k {
+            // Unwrap is fine because k > 0
+            self.max_distance_squared = self.nearest.peek().unwrap().0.0;
+        }
+    }
+
+    /// Returns the entities ordered by ascending distance.
+    pub(crate) fn into_sorted_vec(self) -> Vec<Entity> {
+        self.nearest
+            .into_sorted_vec()
+            .into_iter()
+            .map(|(_distance, entity)| entity)
+            .collect()
+    }
+}
+
+/// Hierarchy of nodes which `NearestIter` can search best-first.
+pub(crate) trait NearestTree: Sized {
+    type Node;
+
+    /// Returns the squared distance from the sample point to the closest point of any entity
+    /// volume in the node.
+    fn distance_squared_to_node(&self, node: &Self::Node, sample_point: Vec3) -> f32;
+
+    /// Pushes the children and entities of the node to `candidates`.
+    fn open_node(&self, node: Self::Node, candidates: &mut NearestCandidates<'_, Self>);
+}
+
+/// Candidate visited by `NearestIter`: either a node that still needs to be opened, or an entity.
+enum NearestCandidate<N> {
+    Node(N),
+    Entity(Entity),
+}
+
+/// Priority queue of the nodes and entities still to be visited by `NearestIter`.
+pub(crate) struct NearestCandidates<'a, T: NearestTree> {
+    tree: &'a T,
+    sample_point: Vec3,
+    heap: BinaryHeap<ByDistance<NearestCandidate<T::Node>>>,
+}
+
+impl<T: NearestTree> NearestCandidates<'_, T> {
+    pub(crate) fn push_node(&mut self, node: T::Node) {
+        self.heap.push(ByDistance {
+            distance_squared: self.tree.distance_squared_to_node(&node, self.sample_point),
+            item: NearestCandidate::Node(node),
+        });
+    }
+
+    pub(crate) fn push_entity(&mut self, entity: Entity, position: Vec3, extent: SpatialExtent) {
+        self.heap.push(ByDistance {
+            distance_squared: extent.distance_squared_to_point(position, self.sample_point),
+            item: NearestCandidate::Entity(entity),
+        });
+    }
+}
+
+/// Iterator over the entities of a tree in ascending order of distance to a sample point.
+///
+/// Nodes and entities share a single priority queue, so an entity is only yielded once every node
+/// that could contain a closer entity has been opened. Entities are found lazily, which makes
+/// this suitable for searches that stop at the first entity matching some condition.
+pub(crate) struct NearestIter<'a, T: NearestTree> {
+    candidates: NearestCandidates<'a, T>,
+}
+
+impl<'a, T: NearestTree> NearestIter<'a, T> {
+    /// Creates an iterator searching from the root node, or an empty iterator without a root.
+    pub(crate) fn new(tree: &'a T, sample_point: Vec3, root: Option<T::Node>) -> Self {
+        let mut candidates = NearestCandidates {
+            tree,
+            sample_point,
+            heap: BinaryHeap::new(),
+        };
+        if let Some(root) = root {
+            candidates.push_node(root);
+        }
+
+        NearestIter { candidates }
+    }
+}
+
+impl<T: NearestTree> Iterator for NearestIter<'_, T> {
+    type Item = Entity;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(ByDistance { item, .. }) = self.candidates.heap.pop() {
+            match item {
+                NearestCandidate::Entity(entity) => return Some(entity),
+                NearestCandidate::Node(node) => {
+                    let tree = self.candidates.tree;
+                    tree.open_node(node, &mut self.candidates);
+                }
+            }
+        }
+
+        None
+    }
+}
@@ -0,0 +1,158 @@
+//! Loose octree -accelerated spatial lookup
+
+use crate::SpatialLookupAlgorithm;
+use crate::algorithms::loose_tree::{LooseTree, LooseTreeSettings, TreeSpace};
+use crate::spatial_extent::SpatialExtent;
+use crate::spatial_shape::SpatialShape;
+use bevy::math::bounding::Aabb3d;
+use bevy::prelude::*;
+use std::ops::ControlFlow;
+
+/// Loose octree -based spatial acceleration algorithm.
+///
+/// The octree recursively divides the bounding cube of all entities into eight equally sized
+/// child cubes, but only where it's needed: a node is split once it holds more than
+/// `node_capacity` entities, unless it's already at `max_depth`. This adapts well to strongly
+/// clustered populations, where the tree is deep inside the clusters and shallow everywhere else.
+///
+/// The bounds of each node are "loose", i.e. `looseness` times larger than the cube of the node,
+/// so that the bounds of neighbouring nodes overlap. Each entity is stored in the deepest node
+/// whose loose bounds contain its whole volume, which means that entities with a `SpatialExtent`
+/// don't have to be split between nodes, and entities moving along the boundary of two nodes
+/// don't keep moving between very different depths.
+///
+/// The octree supports incremental insertion and removal of entities. Entities moving outside of
+/// the root node cause a full rebuild.
+#[derive(Debug)]
+pub struct Octree {
+    /// Maximum depth of the tree. Nodes at this depth are never split.
+    pub max_depth: usize,
+    /// Number of entities a node can hold before it is split into eight child nodes.
+    pub node_capacity: usize,
+    /// How much larger the loose bounds of a node are than its cube, must be at least 1.
+    ///
+    /// Larger values let larger entities be stored deeper in the tree, but make the nodes overlap
+    /// more, which means more nodes are visited per query.
+    pub looseness: f32,
+    tree: LooseTree<WorldSpace>,
+}
+
+impl Default for Octree {
+    fn default() -> Self {
+        Octree {
+            max_depth: 8,
+            node_capacity: 64,
+            looseness: 2.0,
+            tree: LooseTree::new(WorldSpace),
+        }
+    }
+}
+
+impl Octree {
+    fn settings(&self) -> LooseTreeSettings {
+        LooseTreeSettings {
+            max_depth: self.max_depth,
+            node_capacity: self.node_capacity,
+            looseness: self.looseness,
+        }
+    }
+}
+
+impl SpatialLookupAlgorithm for Octree {
+    fn prepare(&mut self, entities: &[(Entity, Vec3)]) {
+        self.prepare_with_extents(entities, &[]);
+    }
+
+    fn prepare_with_extents(&mut self, entities: &[(Entity, Vec3)], extents: &[SpatialExtent]) {
+        let settings = self.settings();
+        self.tree.prepare(WorldSpace, entities, extents, settings);
+    }
+
+    fn insert(&mut self, entity: Entity, position: Vec3, extent: SpatialExtent) -> bool {
+        let settings = self.settings();
+        self.tree.insert(entity, position, extent, settings)
+    }
+
+    fn update(&mut self, entity: Entity, position: Vec3, extent: SpatialExtent) -> bool {
+        self.remove(entity);
+        self.insert(entity, position, extent)
+    }
+
+    fn remove(&mut self, entity: Entity) -> bool {
+        self.tree.remove(entity);
+
+        true
+    }
+
+    fn entities_in_radius(&self, sample_point: Vec3, radius: f32) -> Vec<Entity> {
+        let mut found_entities = Vec::new();
+
+        let _ = self
+            .tree
+            .visit_in_radius(sample_point, radius, &mut |entity, _position| {
+                found_entities.push(entity);
+                ControlFlow::Continue(())
+            });
+
+        found_entities
+    }
+
+    fn visit_in_radius(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+        visit: &mut dyn FnMut(Entity, Vec3) -> ControlFlow<()>,
+    ) -> Option<ControlFlow<()>> {
+        Some(self.tree.visit_in_radius(sample_point, radius, visit))
+    }
+
+    fn entities_in_aabb(&self, min: Vec3, max: Vec3) -> Option<Vec<Entity>> {
+        Some(self.tree.entities_in_aabb(min, max))
+    }
+
+    fn entities_in_shape(
+        &self,
+        shape: &dyn SpatialShape,
+        isometry: Isometry3d,
+    ) -> Option<Vec<Entity>> {
+        Some(self.tree.entities_in_shape(shape, isometry))
+    }
+
+    fn entities_along_ray(
+        &self,
+        ray: Ray3d,
+        max_distance: f32,
+        thickness: f32,
+    ) -> Option<Vec<(Entity, f32)>> {
+        Some(self.tree.entities_along_ray(ray, max_distance, thickness))
+    }
+
+    fn debug_gizmos(&self, gizmos: &mut Gizmos) {
+        self.tree.draw_gizmos(gizmos, self.max_depth);
+    }
+}
+
+/// Space of the octree, which is the world itself.
+#[derive(Debug)]
+struct WorldSpace;
+
+impl TreeSpace for WorldSpace {
+    type Vector = Vec3;
+
+    #[inline]
+    fn project(&self, point: Vec3) -> Vec3 {
+        point
+    }
+
+    #[inline]
+    fn world_aabb(&self, center: Vec3, half_size: f32) -> Aabb3d {
+        Aabb3d::new(center, Vec3::splat(half_size))
+    }
+
+    fn draw_cube(&self, gizmos: &mut Gizmos, center: Vec3, half_size: f32, color: Color) {
+        gizmos.cuboid(
+            Transform::from_translation(center).with_scale(Vec3::splat(half_size * 2.)),
+            color,
+        );
+    }
+}
@@ -0,0 +1,204 @@
+//! Loose quadtree -accelerated spatial lookup for 2D worlds
+
+use crate::SpatialLookupAlgorithm;
+use crate::algorithms::SpatialPlane;
+use crate::algorithms::loose_tree::{LooseTree, LooseTreeSettings, TreeSpace};
+use crate::spatial_extent::SpatialExtent;
+use crate::spatial_shape::SpatialShape;
+use bevy::math::bounding::Aabb3d;
+use bevy::prelude::*;
+use std::ops::ControlFlow;
+
+/// Loose quadtree -based spatial acceleration algorithm for 2D worlds.
+///
+/// This is the 2D counterpart of [`Octree`](super::Octree): the bounding square of all entities,
+/// as projected onto `plane`, is recursively divided into four equally sized child squares once a
+/// node holds more than `node_capacity` entities. The depth axis is ignored when building the
+/// tree, so no time is spent dividing a world which is flat anyway.
+///
+/// Like the octree, the bounds of each node are `looseness` times larger than its square, and each
+/// entity is stored in the deepest node whose loose bounds contain its whole projected volume.
+///
+/// Queries are still exact in 3D, so entities on different depths (e.g. background and foreground
+/// sprites) are not mixed up, but all entities on the same spot of the plane share the same nodes.
+///
+/// The quadtree supports incremental insertion and removal of entities. Entities moving outside of
+/// the root node cause a full rebuild.
+#[derive(Debug)]
+pub struct Quadtree {
+    /// Plane the tree is built on. Changing this takes effect on the next `prepare`.
+    pub plane: SpatialPlane,
+    /// Maximum depth of the tree. Nodes at this depth are never split.
+    pub max_depth: usize,
+    /// Number of entities a node can hold before it is split into four child nodes.
+    pub node_capacity: usize,
+    /// How much larger the loose bounds of a node are than its square, must be at least 1.
+    ///
+    /// Larger values let larger entities be stored deeper in the tree, but make the nodes overlap
+    /// more, which means more nodes are visited per query.
+    pub looseness: f32,
+    tree: LooseTree<PlaneSpace>,
+}
+
+impl Default for Quadtree {
+    fn default() -> Self {
+        Quadtree::with_plane(SpatialPlane::default())
+    }
+}
+
+impl Quadtree {
+    /// Creates an empty quadtree on the given plane.
+    pub fn with_plane(plane: SpatialPlane) -> Self {
+        Quadtree {
+            plane,
+            max_depth: 10,
+            node_capacity: 64,
+            looseness: 2.0,
+            tree: LooseTree::new(PlaneSpace::new(plane)),
+        }
+    }
+
+    fn settings(&self) -> LooseTreeSettings {
+        LooseTreeSettings {
+            max_depth: self.max_depth,
+            node_capacity: self.node_capacity,
+            looseness: self.looseness,
+        }
+    }
+}
+
+impl SpatialLookupAlgorithm for Quadtree {
+    fn prepare(&mut self, entities: &[(Entity, Vec3)]) {
+        self.prepare_with_extents(entities, &[]);
+    }
+
+    fn prepare_with_extents(&mut self, entities: &[(Entity, Vec3)], extents: &[SpatialExtent]) {
+        let settings = self.settings();
+        self.tree
+            .prepare(PlaneSpace::new(self.plane), entities, extents, settings);
+    }
+
+    fn insert(&mut self, entity: Entity, position: Vec3, extent: SpatialExtent) -> bool {
+        let settings = self.settings();
+        self.tree.insert(entity, position, extent, settings)
+    }
+
+    fn update(&mut self, entity: Entity, position: Vec3, extent: SpatialExtent) -> bool {
+        self.remove(entity);
+        self.insert(entity, position, extent)
+    }
+
+    fn remove(&mut self, entity: Entity) -> bool {
+        self.tree.remove(entity);
+
+        true
+    }
+
+    fn entities_in_radius(&self, sample_point: Vec3, radius: f32) -> Vec<Entity> {
+        let mut found_entities = Vec::new();
+
+        let _ = self
+            .tree
+            .visit_in_radius(sample_point, radius, &mut |entity, _position| {
+                found_entities.push(entity);
+                ControlFlow::Continue(())
+            });
+
+        found_entities
+    }
+
+    fn visit_in_radius(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+        visit: &mut dyn FnMut(Entity, Vec3) -> ControlFlow<()>,
+    ) -> Option<ControlFlow<()>> {
+        Some(self.tree.visit_in_radius(sample_point, radius, visit))
+    }
+
+    fn entities_in_aabb(&self, min: Vec3, max: Vec3) -> Option<Vec<Entity>> {
+        Some(self.tree.entities_in_aabb(min, max))
+    }
+
+    fn entities_in_shape(
+        &self,
+        shape: &dyn SpatialShape,
+        isometry: Isometry3d,
+    ) -> Option<Vec<Entity>> {
+        Some(self.tree.entities_in_shape(shape, isometry))
+    }
+
+    fn entities_along_ray(
+        &self,
+        ray: Ray3d,
+        max_distance: f32,
+        thickness: f32,
+    ) -> Option<Vec<(Entity, f32)>> {
+        Some(self.tree.entities_along_ray(ray, max_distance, thickness))
+    }
+
+    fn debug_gizmos(&self, gizmos: &mut Gizmos) {
+        self.tree.draw_gizmos(gizmos, self.max_depth);
+    }
+}
+
+/// Space of the quadtree, which is the plane the tree was prepared on.
+#[derive(Debug)]
+struct PlaneSpace {
+    plane: SpatialPlane,
+    /// Smallest and largest depth covered by the volumes of the entities in the tree.
+    depth_range: Vec2,
+}
+
+impl PlaneSpace {
+    fn new(plane: SpatialPlane) -> Self {
+        PlaneSpace {
+            plane,
+            depth_range: Vec2::new(f32::INFINITY, f32::NEG_INFINITY),
+        }
+    }
+}
+
+impl TreeSpace for PlaneSpace {
+    type Vector = Vec2;
+
+    #[inline]
+    fn project(&self, point: Vec3) -> Vec2 {
+        self.plane.project(point)
+    }
+
+    /// Grows `depth_range` to cover the volume of the entity.
+    fn include(&mut self, position: Vec3, extent: SpatialExtent) {
+        let depth_axis = self.plane.depth_axis();
+        let depth = position[depth_axis];
+        let half_size = extent.half_size()[depth_axis];
+
+        self.depth_range.x = self.depth_range.x.min(depth - half_size);
+        self.depth_range.y = self.depth_range.y.max(depth + half_size);
+    }
+
+    /// Returns the square on the plane, extended along the depth axis to cover all entities.
+    #[inline]
+    fn world_aabb(&self, center: Vec2, half_size: f32) -> Aabb3d {
+        Aabb3d {
+            min: self
+                .plane
+                .unproject(center - half_size, self.depth_range.x)
+                .into(),
+            max: self
+                .plane
+                .unproject(center + half_size, self.depth_range.y)
+                .into(),
+        }
+    }
+
+    fn draw_cube(&self, gizmos: &mut Gizmos, center: Vec2, half_size: f32, color: Color) {
+        let depth = self.depth_range.x.midpoint(self.depth_range.y);
+
+        gizmos.rect(
+            self.plane.gizmo_isometry(center, depth),
+            Vec2::splat(half_size * 2.),
+            color,
+        );
+    }
+}
@@ -0,0 +1,67 @@
+//! Planes used by the 2D lookup algorithms.
+
+use bevy::prelude::*;
+use std::f32::consts::FRAC_PI_2;
+
+/// Plane which 2D lookup algorithms organize the entities on.
+///
+/// The remaining axis is the depth axis, which 2D algorithms ignore when building their
+/// structure. Queries still consider all three axes, so entities at different depths are not
+/// mixed up, but the algorithms are fastest when the depth of all entities is about the same.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum SpatialPlane {
+    /// The XY plane, with Z as the depth axis. This is the plane used by bevy's 2D cameras.
+    #[default]
+    XY,
+    /// The XZ plane, with Y as the depth axis. This is the ground plane of a Y-up 3D world.
+    XZ,
+}
+
+impl SpatialPlane {
+    /// Returns the indices of the two axes on the plane.
+    #[inline]
+    pub fn axes(self) -> [usize; 2] {
+        match self {
+            SpatialPlane::XY => [0, 1],
+            SpatialPlane::XZ => [0, 2],
+        }
+    }
+
+    /// Returns the index of the depth axis.
+    #[inline]
+    pub fn depth_axis(self) -> usize {
+        match self {
+            SpatialPlane::XY => 2,
+            SpatialPlane::XZ => 1,
+        }
+    }
+
+    /// Projects a point onto the plane.
+    #[inline]
+    pub fn project(self, point: Vec3) -> Vec2 {
+        match self {
+            SpatialPlane::XY => point.xy(),
+            SpatialPlane::XZ => point.xz(),
+        }
+    }
+
+    /// Returns the 3D point at the given point on the plane and depth.
+    #[inline]
+    pub fn unproject(self, point: Vec2, depth: f32) -> Vec3 {
+        match self {
+            SpatialPlane::XY => point.extend(depth),
+            SpatialPlane::XZ => Vec3::new(point.x, depth, point.y),
+        }
+    }
+
+    /// Returns an isometry which maps the XY plane of gizmos to this plane, centered on the given
+    /// point on the plane.
+    pub(crate) fn gizmo_isometry(self, center: Vec2, depth: f32) -> Isometry3d {
+        let rotation = match self {
+            SpatialPlane::XY => Quat::IDENTITY,
+            SpatialPlane::XZ => Quat::from_rotation_x(FRAC_PI_2),
+        };
+
+        Isometry3d::new(self.unproject(center, depth), rotation)
+    }
+}
@@ -0,0 +1,365 @@
+//! Sweep and prune -accelerated spatial lookup
+
+use crate::SpatialLookupAlgorithm;
+use crate::spatial_extent::{SpatialExtent, with_extents};
+use crate::spatial_shape::SpatialShape;
+use bevy::ecs::entity::EntityHashMap;
+use bevy::math::{BVec3, FloatOrd};
+use bevy::prelude::*;
+use std::ops::ControlFlow;
+
+type EntityPositionExtent = (Entity, Vec3, SpatialExtent);
+
+/// Number of moves per entity after which the insertion sort gives up and the entities are sorted
+/// from scratch instead.
+const MAX_INSERTION_SORT_MOVES_PER_ENTITY: usize = 8;
+
+/// Sweep and prune -based spatial acceleration algorithm.
+///
+/// The entities are kept sorted by the lower bound of their volume along one or more axes. The
+/// sorted order is kept between `prepare`s, and re-sorted with an insertion sort, which is close
+/// to O(n) when the entities only move a little between frames. If the entities have moved too
+/// much, e.g. when they are teleported or the lookup is prepared for the first time, the
+/// insertion sort gives up and the entities are sorted from scratch.
+///
+/// Queries binary search the range of entities whose volumes may overlap the bounding box of the
+/// query along each sorted axis, and only test the entities of the axis with the smallest range.
+/// Sorting along more than one axis therefore helps queries which are long along some axis, at
+/// the cost of sorting the entities along each axis.
+///
+/// The algorithm also finds every pair of overlapping entities with a single sweep along the
+/// sorted axis, which makes it a good broad phase for collision detection, see
+/// `SpatialLookupState::overlapping_pairs`.
+///
+/// All entities are sorted again whenever any of them changes, so this works best when most
+/// entities move every frame. Queries test every entity whose volume overlaps the query along the
+/// sorted axis, so they are slower than with the tree -based algorithms, especially in worlds
+/// which are big along the other axes.
+#[derive(Debug)]
+pub struct SweepAndPrune {
+    /// Axes to keep the entities sorted along. Changing this takes effect on the next `prepare`.
+    pub axes: BVec3,
+    entities: Vec<EntityPositionExtent>,
+    sorted_axes: Vec<SortedAxis>,
+    /// Largest size of the volumes of the entities along each axis.
+    max_size: Vec3,
+}
+
+impl Default for SweepAndPrune {
+    fn default() -> Self {
+        SweepAndPrune::with_axes(BVec3::new(true, false, false))
+    }
+}
+
+/// Entities sorted along a single axis.
+#[derive(Debug)]
+struct SortedAxis {
+    axis: usize,
+    /// Lower bound of the volume of each entity along the axis, and the index of the entity in
+    /// `SweepAndPrune::entities`, sorted by the lower bound.
+    lower_bounds: Vec<(f32, u32)>,
+}
+
+impl SweepAndPrune {
+    /// Creates an empty lookup which sorts the entities along the given axes.
+    pub fn with_axes(axes: BVec3) -> Self {
+        assert!(axes.any(), "at least one axis must be sorted");
+
+        SweepAndPrune {
+            axes,
+            entities: Vec::new(),
+            sorted_axes: Vec::new(),
+            max_size: Vec3::ZERO,
+        }
+    }
+
+    /// Calls `visit` for each entity whose volume may overlap the axis-aligned box spanned by
+    /// `min` and `max`.
+    fn for_each_candidate(
+        &self,
+        min: Vec3,
+        max: Vec3,
+        mut visit: impl FnMut(&EntityPositionExtent),
+    ) {
+        let _ = self.try_for_each_candidate(min, max, |entity_position_extent| {
+            visit(entity_position_extent);
+            ControlFlow::Continue(())
+        });
+    }
+
+    /// Calls `visit` for each entity whose volume may overlap the axis-aligned box spanned by
+    /// `min` and `max`, until `visit` returns `ControlFlow::Break`.
+    fn try_for_each_candidate(
+        &self,
+        min: Vec3,
+        max: Vec3,
+        mut visit: impl FnMut(&EntityPositionExtent) -> ControlFlow<()>,
+    ) -> ControlFlow<()> {
+        // the lower bounds are sorted, so the entities overlapping the box along an axis are the
+        // ones whose lower bound is at most the largest size of a volume before the box
+        let candidates = self
+            .sorted_axes
+            .iter()
+            .map(|sorted_axis| {
+                let axis = sorted_axis.axis;
+                let start = sorted_axis
+                    .lower_bounds
+                    .partition_point(|(lower_bound, _index)| {
+                        *lower_bound < min[axis] - self.max_size[axis]
+                    });
+                let end = sorted_axis
+                    .lower_bounds
+                    .partition_point(|(lower_bound, _index)| *lower_bound <= max[axis]);
+
+                &sorted_axis.lower_bounds[start..end.max(start)]
+            })
+            .min_by_key(|candidates| candidates.len());
+
+        match candidates {
+            Some(candidates) => candidates
+                .iter()
+                .try_for_each(|(_lower_bound, index)| visit(&self.entities[*index as usize])),
+            // no axes are sorted, so every entity is a candidate
+            None => self.entities.iter().try_for_each(visit),
+        }
+    }
+}
+
+impl SpatialLookupAlgorithm for SweepAndPrune {
+    fn prepare(&mut self, entities: &[(Entity, Vec3)]) {
+        self.prepare_with_extents(entities, &[]);
+    }
+
+    fn prepare_with_extents(&mut self, entities: &[(Entity, Vec3)], extents: &[SpatialExtent]) {
+        let previous_entities = std::mem::replace(
+            &mut self.entities,
+            with_extents(entities, extents).collect(),
+        );
+        self.max_size = self
+            .entities
+            .iter()
+            .fold(Vec3::ZERO, |max_size, (_entity, _position, extent)| {
+                max_size.max(extent.half_size() * 2.)
+            });
+
+        let axes: Vec<usize> = (0..3).filter(|axis| self.axes.test(*axis)).collect();
+        if !self
+            .sorted_axes
+            .iter()
+            .map(|sorted_axis| sorted_axis.axis)
+            .eq(axes.iter().copied())
+        {
+            self.sorted_axes = axes
+                .into_iter()
+                .map(|axis| SortedAxis {
+                    axis,
+                    lower_bounds: Vec::new(),
+                })
+                .collect();
+        }
+
+        let indices: EntityHashMap<u32> = self
+            .entities
+            .iter()
+            .enumerate()
+            .map(|(index, (entity, _position, _extent))| (*entity, index as u32))
+            .collect();
+        let lower_bound = |(_entity, position, extent): &EntityPositionExtent, axis: usize| {
+            position[axis] - extent.half_size()[axis]
+        };
+
+        let mut is_sorted = vec![false; self.entities.len()];
+        for sorted_axis in &mut self.sorted_axes {
+            let axis = sorted_axis.axis;
+            is_sorted.fill(false);
+
+            // keep the order of the entities which were already sorted, and move them to the
+            // indices they have this time
+            sorted_axis.lower_bounds.retain_mut(|(bound, index)| {
+                let (entity, _position, _extent) = previous_entities[*index as usize];
+                let Some(&new_index) = indices.get(&entity) else {
+                    return false;
+                };
+                if is_sorted[new_index as usize] {
+                    return false;
+                }
+
+                *index = new_index;
+                *bound = lower_bound(&self.entities[new_index as usize], axis);
+                is_sorted[new_index as usize] = true;
+                true
+            });
+
+            sorted_axis.lower_bounds.extend(
+                (0..self.entities.len())
+                    .filter(|index| !is_sorted[*index])
+                    .map(|index| (lower_bound(&self.entities[index], axis), index as u32)),
+            );
+
+            if !insertion_sort(&mut sorted_axis.lower_bounds) {
+                sorted_axis
+                    .lower_bounds
+                    .sort_unstable_by_key(|(bound, _index)| FloatOrd(*bound));
+            }
+        }
+    }
+
+    fn entities_in_radius(&self, sample_point: Vec3, radius: f32) -> Vec<Entity> {
+        let mut found_entities = Vec::new();
+
+        let _ = self.visit_in_radius(sample_point, radius, &mut |entity, _position| {
+            found_entities.push(entity);
+            ControlFlow::Continue(())
+        });
+
+        found_entities
+    }
+
+    fn visit_in_radius(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+        visit: &mut dyn FnMut(Entity, Vec3) -> ControlFlow<()>,
+    ) -> Option<ControlFlow<()>> {
+        Some(self.try_for_each_candidate(
+            sample_point - radius,
+            sample_point + radius,
+            |(entity, position, extent)| {
+                if extent.distance_squared_to_point(*position, sample_point) <= radius * radius {
+                    visit(*entity, *position)?;
+                }
+
+                ControlFlow::Continue(())
+            },
+        ))
+    }
+
+    fn entities_in_aabb(&self, min: Vec3, max: Vec3) -> Option<Vec<Entity>> {
+        let mut found_entities = Vec::new();
+
+        self.for_each_candidate(min, max, |(entity, position, extent)| {
+            if extent.intersects_aabb(*position, min, max) {
+                found_entities.push(*entity);
+            }
+        });
+
+        Some(found_entities)
+    }
+
+    fn entities_in_shape(
+        &self,
+        shape: &dyn SpatialShape,
+        isometry: Isometry3d,
+    ) -> Option<Vec<Entity>> {
+        let mut found_entities = Vec::new();
+        let bounding_aabb = shape.bounding_aabb(isometry);
+
+        self.for_each_candidate(
+            bounding_aabb.min.into(),
+            bounding_aabb.max.into(),
+            |(entity, position, extent)| {
+                if extent.intersects_shape(*position, shape, isometry) {
+                    found_entities.push(*entity);
+                }
+            },
+        );
+
+        Some(found_entities)
+    }
+
+    fn entities_along_ray(
+        &self,
+        ray: Ray3d,
+        max_distance: f32,
+        thickness: f32,
+    ) -> Option<Vec<(Entity, f32)>> {
+        let mut found_entities = Vec::new();
+        // infinite rays are clamped to f32::MAX, because 0 * infinity would result in NaN
+        let end = ray.get_point(max_distance.min(f32::MAX));
+
+        self.for_each_candidate(
+            ray.origin.min(end) - thickness,
+            ray.origin.max(end) + thickness,
+            |(entity, position, extent)| {
+                if let Some(distance) =
+                    extent.distance_along_ray(*position, ray, max_distance, thickness)
+                {
+                    found_entities.push((*entity, distance));
+                }
+            },
+        );
+
+        found_entities.sort_unstable_by_key(|(_entity, distance)| FloatOrd(*distance));
+
+        Some(found_entities)
+    }
+
+    fn overlapping_pairs(&self) -> Option<Vec<(Entity, Entity)>> {
+        // sweeping along the axis the entities are most spread out on tests the fewest pairs
+        let sorted_axis = self.sorted_axes.iter().max_by_key(|sorted_axis| {
+            match (
+                sorted_axis.lower_bounds.first(),
+                sorted_axis.lower_bounds.last(),
+            ) {
+                (Some((first, _index)), Some((last, _last_index))) => FloatOrd(last - first),
+                _ => FloatOrd(0.),
+            }
+        })?;
+        let axis = sorted_axis.axis;
+
+        let mut pairs = Vec::new();
+        for (start, (_lower_bound, index)) in sorted_axis.lower_bounds.iter().enumerate() {
+            let (entity, position, extent) = &self.entities[*index as usize];
+            let upper_bound = position[axis] + extent.half_size()[axis];
+
+            for (other_lower_bound, other_index) in &sorted_axis.lower_bounds[start + 1..] {
+                if *other_lower_bound > upper_bound {
+                    break;
+                }
+
+                let (other_entity, other_position, other_extent) =
+                    &self.entities[*other_index as usize];
+                if extent.intersects_extent(*position, other_extent, *other_position) {
+                    pairs.push((*entity, *other_entity));
+                }
+            }
+        }
+
+        Some(pairs)
+    }
+
+    fn debug_gizmos(&self, gizmos: &mut Gizmos) {
+        for (_entity, position, extent) in &self.entities {
+            if *extent != SpatialExtent::default() {
+                gizmos.cuboid(
+                    Transform::from_translation(*position).with_scale(extent.half_size() * 2.),
+                    Color::hsv(120., 0.8, 1.0),
+                );
+            }
+        }
+    }
+}
+
+/// Sorts nearly sorted lower bounds with an insertion sort.
+///
+/// Returns false without finishing the sort if the bounds were so far from sorted that it would
+/// be faster to sort them from scratch.
+fn insertion_sort(lower_bounds: &mut [(f32, u32)]) -> bool {
+    let max_moves = lower_bounds.len() * MAX_INSERTION_SORT_MOVES_PER_ENTITY;
+    let mut moves = 0;
+
+    for start in 1..lower_bounds.len() {
+        let mut index = start;
+        while index > 0 && lower_bounds[index - 1].0 > lower_bounds[index].0 {
+            lower_bounds.swap(index - 1, index);
+            index -= 1;
+
+            moves += 1;
+            if moves > max_moves {
+                return false;
+            }
+        }
+    }
+
+    true
+}
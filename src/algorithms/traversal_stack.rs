@@ -0,0 +1,51 @@
+//! Stack for traversing trees without allocating.
+
+/// Number of items stored inline before the stack spills onto the heap.
+const INLINE_CAPACITY: usize = 64;
+
+/// LIFO stack of nodes still to be visited by a depth-first tree traversal.
+///
+/// Traversals only need room for about one node per level of the tree, so the first
+/// `INLINE_CAPACITY` items are stored inline and queries don't allocate unless the tree is
+/// unusually deep.
+pub(crate) struct TraversalStack<T: Copy> {
+    inline: [T; INLINE_CAPACITY],
+    inline_len: usize,
+    /// Items pushed while `inline` is full, always popped before the inline items.
+    spilled: Vec<T>,
+}
+
+impl<T: Copy> TraversalStack<T> {
+    /// Creates a stack containing only the given root.
+    pub(crate) fn with_root(root: T) -> Self {
+        TraversalStack {
+            inline: [root; INLINE_CAPACITY],
+            inline_len: 1,
+            spilled: Vec::new(),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn push(&mut self, item: T) {
+        if self.inline_len < INLINE_CAPACITY {
+            self.inline[self.inline_len] = item;
+            self.inline_len += 1;
+        } else {
+            self.spilled.push(item);
+        }
+    }
+
+    #[inline]
+    pub(crate) fn pop(&mut self) -> Option<T> {
+        if let Some(item) = self.spilled.pop() {
+            return Some(item);
+        }
+
+        if self.inline_len == 0 {
+            return None;
+        }
+
+        self.inline_len -= 1;
+        Some(self.inline[self.inline_len])
+    }
+}
@@ -20,8 +20,10 @@
 //! ```
 //!
 //! This crate aims to provide an ergonomic and fast way of performing spatial queries, i.e.
-//! "nearby entities" -type queries. "Spatial" here refers purely to the `GlobalPosition` of an
-//! entity, and does not consider things like meshes or collision shapes.
+//! "nearby entities" -type queries. "Spatial" here refers to the `GlobalPosition` of an entity,
+//! and does not consider things like meshes or collision shapes. Entities can optionally be given
+//! a volume with the `SpatialExtent` component, in which case queries find them whenever the
+//! query overlaps that volume.
 //!
 //! By default, this crate uses a naive lookup algorithm as it outperforms more advanced algorithms
 //! for simple (less than 1 000 000 entities) scenes with few (less than 100) queries. A BVH-based
@@ -49,21 +51,106 @@
 //! app.insert_resource(SpatialLookupState::with_algorithm(YourAwesomeAlgorithm));
 //! ```
 //!
+use bevy::ecs::entity::EntityHashMap;
+use bevy::ecs::query::{QueryFilter, QueryItem};
 use bevy::prelude::*;
+use std::marker::PhantomData;
+use std::ops::ControlFlow;
 
 pub mod algorithms;
+mod spatial_extent;
 mod spatial_query;
 mod spatial_query_iterator;
+mod spatial_shape;
 
 pub mod prelude {
+    pub use crate::spatial_extent::SpatialExtent;
     pub use crate::spatial_query::SpatialQuery;
-    pub use crate::spatial_query_iterator::SpatialQueryIterator;
-    pub use crate::{SpatialLookupAlgorithm, SpatialLookupState, SpatialQueriesPlugin};
+    pub use crate::spatial_query_iterator::{SpatialQueryDistanceIterator, SpatialQueryIterator};
+    pub use crate::spatial_shape::SpatialShape;
+    pub use crate::{
+        FilteredSpatialQueriesPlugin, SpatialIndexPlugin, SpatialLookupAlgorithm,
+        SpatialLookupState, SpatialQueriesPlugin, SpatialTracked,
+    };
 }
 
+use crate::spatial_extent::SpatialExtent;
+use crate::spatial_shape::SpatialShape;
+
 /// Adds `SpatialQuery` support to bevy.
+///
+/// All entities with a `GlobalTransform` are added to the spatial lookup. Use
+/// `FilteredSpatialQueriesPlugin` to only track some of them.
 pub struct SpatialQueriesPlugin;
 
+/// Adds `SpatialQuery` support to bevy, only tracking entities which match the query filter `F`.
+///
+/// Entities which don't match the filter are never returned by spatial queries, and they don't
+/// slow down the lookup. The `SpatialTracked` marker component can be used to opt entities in:
+/// ```
+/// # use bevy::prelude::*;
+/// # use bevy_mod_spatial_query::prelude::*;
+/// #
+/// # let mut app = App::new();
+/// #
+/// app.add_plugins(FilteredSpatialQueriesPlugin::<With<SpatialTracked>>::default());
+/// ```
+pub struct FilteredSpatialQueriesPlugin<F: QueryFilter> {
+    _filter: PhantomData<fn() -> F>,
+}
+
+impl<F: QueryFilter> Default for FilteredSpatialQueriesPlugin<F> {
+    fn default() -> Self {
+        Self {
+            _filter: PhantomData,
+        }
+    }
+}
+
+/// Adds a separate spatial index for entities with the component `T`.
+///
+/// Each index has its own `SpatialLookupState<T>`, and therefore its own algorithm, which is
+/// queried with `SpatialQuery<D, F, T>`. Entities can be in any number of indices, and the
+/// untyped default index of `SpatialQueriesPlugin` keeps working alongside them.
+///
+/// The index only contains entities which also match the query filter `F`.
+/// ```
+/// # use bevy::prelude::*;
+/// # use bevy_mod_spatial_query::prelude::*;
+/// # use bevy_mod_spatial_query::algorithms::Bvh;
+/// #
+/// #[derive(Component)]
+/// struct Enemy;
+///
+/// fn nearby_enemies(mut enemies: SpatialQuery<&mut Transform, (), Enemy>) {
+///     for transform in enemies.in_radius(Vec3::ZERO, 10.) {
+///         // Do something with the enemies..
+///     }
+/// }
+///
+/// # let mut app = App::new();
+/// #
+/// app.insert_resource(SpatialLookupState::<Enemy>::new(Bvh::default()))
+///     .add_plugins(SpatialIndexPlugin::<Enemy>::default())
+///     .add_systems(Update, nearby_enemies);
+/// ```
+pub struct SpatialIndexPlugin<T: Component, F: QueryFilter = ()> {
+    _marker: PhantomData<fn() -> (T, F)>,
+}
+
+impl<T: Component, F: QueryFilter> Default for SpatialIndexPlugin<T, F> {
+    fn default() -> Self {
+        Self {
+            _marker: PhantomData,
+        }
+    }
+}
+
+/// Marker component for entities tracked by
+/// `FilteredSpatialQueriesPlugin::<With<SpatialTracked>>`.
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct SpatialTracked;
+
 /// System set for systems used to set up the spatial lookup.
 ///
 /// All systems using `SpatialQuery<_>` *MUST* be scheduled after this set, i.e.
@@ -75,8 +162,37 @@ pub struct PrepareSpatialLookup;
 
 impl Plugin for SpatialQueriesPlugin {
     fn build(&self, app: &mut App) {
-        app.insert_resource(SpatialLookupState::default())
-            .add_systems(First, prepare_spatial_lookup.in_set(PrepareSpatialLookup));
+        FilteredSpatialQueriesPlugin::<()>::default().build(app);
+    }
+}
+
+impl<F: QueryFilter + 'static> Plugin for FilteredSpatialQueriesPlugin<F> {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SpatialLookupState>().add_systems(
+            First,
+            (
+                remove_untracked_entities::<(), SpatialTracked, F>,
+                add_tracked_entities::<(), SpatialTracked, F>,
+                prepare_spatial_lookup::<(), F>,
+            )
+                .chain()
+                .in_set(PrepareSpatialLookup),
+        );
+    }
+}
+
+impl<T: Component, F: QueryFilter + 'static> Plugin for SpatialIndexPlugin<T, F> {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SpatialLookupState<T>>().add_systems(
+            First,
+            (
+                remove_untracked_entities::<T, T, (With<T>, F)>,
+                add_tracked_entities::<T, T, (With<T>, F)>,
+                prepare_spatial_lookup::<T, (With<T>, F)>,
+            )
+                .chain()
+                .in_set(PrepareSpatialLookup),
+        );
     }
 }
 
@@ -84,42 +200,226 @@ impl Plugin for SpatialQueriesPlugin {
 pub trait SpatialLookupAlgorithm {
     /// Prepares the lookup algorithm with a fresh set of entities and their positions.
     ///
-    /// This gets called once per frame in the `First` schedule, and therefore
-    /// the implementation should be fairly fast. The algorithm should implement its own change
-    /// detection if necessary.
+    /// This gets called in the `First` schedule whenever entities were added, moved or removed
+    /// and the algorithm doesn't support incremental updates, and therefore the implementation
+    /// should be fairly fast.
     fn prepare(&mut self, entities: &[(Entity, Vec3)]);
 
+    /// Prepares the lookup algorithm with a fresh set of entities, their positions and their
+    /// volumes.
+    ///
+    /// `extents` is either empty, in which case all entities are points, or has the extent of
+    /// each entity at the same index as in `entities`. Every query *MUST* then consider the volume
+    /// of the entity instead of just its position.
+    ///
+    /// The default implementation ignores the extents and calls `prepare`, so algorithms which
+    /// don't override this treat all entities as points.
+    fn prepare_with_extents(&mut self, entities: &[(Entity, Vec3)], _extents: &[SpatialExtent]) {
+        self.prepare(entities);
+    }
+
+    /// Adds an entity to an already prepared lookup.
+    ///
+    /// Returns false if the algorithm doesn't support incremental updates, in which case the
+    /// lookup is rebuilt with `prepare_with_extents` instead. The default implementation returns
+    /// false.
+    fn insert(&mut self, _entity: Entity, _position: Vec3, _extent: SpatialExtent) -> bool {
+        false
+    }
+
+    /// Changes the position and volume of an entity already in the lookup.
+    ///
+    /// Returns false if the algorithm doesn't support incremental updates, in which case the
+    /// lookup is rebuilt with `prepare_with_extents` instead. The default implementation returns
+    /// false.
+    fn update(&mut self, _entity: Entity, _position: Vec3, _extent: SpatialExtent) -> bool {
+        false
+    }
+
+    /// Removes an entity from the lookup.
+    ///
+    /// Returns false if the algorithm doesn't support incremental updates, in which case the
+    /// lookup is rebuilt with `prepare_with_extents` instead. The default implementation returns
+    /// false.
+    fn remove(&mut self, _entity: Entity) -> bool {
+        false
+    }
+
     /// Returns a list of all entities that are within the given radius of the sample point.
     ///
     /// This method *MUST* return all entities within the radius of the sample point, and it *MUST*
-    /// not return any entities outside of it.
+    /// not return any entities outside of it. For entities with a `SpatialExtent`, the distance is
+    /// measured to the closest point of their volume.
+    ///
+    /// Each entity should only be returned once. `SpatialQuery` removes duplicates before handing
+    /// out mutable query items, but they still cost time.
     fn entities_in_radius(&self, sample_point: Vec3, radius: f32) -> Vec<Entity>;
 
+    /// Calls `visit` with each entity within the given radius of the sample point, and its
+    /// position, without collecting them into a list.
+    ///
+    /// The search stops as soon as `visit` returns `ControlFlow::Break`, in which case this
+    /// returns `Some(ControlFlow::Break(()))`. The same entities as with `entities_in_radius` *MUST*
+    /// be visited, in any order.
+    ///
+    /// The default implementation returns `None`, in which case `SpatialLookupState` falls back
+    /// to collecting the entities with `entities_in_radius`.
+    fn visit_in_radius(
+        &self,
+        _sample_point: Vec3,
+        _radius: f32,
+        _visit: &mut dyn FnMut(Entity, Vec3) -> ControlFlow<()>,
+    ) -> Option<ControlFlow<()>> {
+        None
+    }
+
+    /// Returns a list of all entities inside the axis-aligned box spanned by `min` and `max`, or
+    /// overlapping it for entities with a `SpatialExtent`.
+    ///
+    /// Entities on the boundary of the box are considered to be inside it.
+    ///
+    /// The default implementation returns `None`, in which case `SpatialLookupState` falls back
+    /// to a linear scan over all entities.
+    fn entities_in_aabb(&self, _min: Vec3, _max: Vec3) -> Option<Vec<Entity>> {
+        None
+    }
+
+    /// Returns a list of all entities inside the given shape, placed in the world by the isometry.
+    ///
+    /// Entities with a `SpatialExtent` *MUST* be tested with `SpatialExtent::intersects_shape`, so
+    /// that all algorithms agree on entities with a `SpatialExtent::Cuboid` which the shape only
+    /// approximately overlaps, see `SpatialShape::intersects_cuboid`.
+    ///
+    /// The default implementation returns `None`, in which case `SpatialLookupState` falls back
+    /// to a linear scan over all entities.
+    fn entities_in_shape(
+        &self,
+        _shape: &dyn SpatialShape,
+        _isometry: Isometry3d,
+    ) -> Option<Vec<Entity>> {
+        None
+    }
+
+    /// Returns a list of all entities within `thickness` of the ray, along with their distance
+    /// along the ray, sorted by that distance.
+    ///
+    /// Only the part of the ray between its origin and `max_distance` is considered, which makes
+    /// this a segment cast for finite `max_distance`.
+    ///
+    /// The default implementation returns `None`, in which case `SpatialLookupState` falls back
+    /// to a linear scan over all entities.
+    fn entities_along_ray(
+        &self,
+        _ray: Ray3d,
+        _max_distance: f32,
+        _thickness: f32,
+    ) -> Option<Vec<(Entity, f32)>> {
+        None
+    }
+
+    /// Returns up to `k` entities closest to the sample point, ordered by ascending distance.
+    ///
+    /// Only entities within `max_distance` of the sample point are considered.
+    ///
+    /// The default implementation returns `None`, in which case `SpatialLookupState` falls back
+    /// to a linear scan over all entities. Algorithms with a better strategy should override this.
+    fn k_nearest(&self, _sample_point: Vec3, _k: usize, _max_distance: f32) -> Option<Vec<Entity>> {
+        None
+    }
+
+    /// Returns an iterator over all entities in ascending order of distance to the sample point.
+    ///
+    /// The iterator should find entities lazily, so that searches which stop early (e.g. at the
+    /// first entity matching a `QueryFilter`) don't pay for sorting the whole world.
+    ///
+    /// The default implementation returns `None`, in which case `SpatialLookupState` falls back
+    /// to a linear scan over all entities.
+    fn nearest_iter(&self, _sample_point: Vec3) -> Option<Box<dyn Iterator<Item = Entity> + '_>> {
+        None
+    }
+
+    /// Called once per frame by `prepare_spatial_lookup`, before the entities which were added or
+    /// moved during the frame are passed to the algorithm.
+    ///
+    /// Returns false if the lookup has to be rebuilt with `prepare_with_extents`, e.g. because the
+    /// algorithm wants to reorganize itself. The default implementation returns true.
+    fn begin_frame(&mut self) -> bool {
+        true
+    }
+
+    /// Returns the name of the algorithm, for diagnostics.
+    ///
+    /// The default implementation returns the type name of the algorithm. Algorithms which wrap
+    /// other algorithms, like `Adaptive`, return the name of the algorithm in use.
+    fn name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
+
+    /// Returns every pair of entities whose volumes overlap.
+    ///
+    /// Each pair is returned once, in no particular order. Entities without a `SpatialExtent` are
+    /// points, so they only overlap volumes which contain them.
+    ///
+    /// The default implementation returns `None`, in which case `SpatialLookupState` falls back
+    /// to testing every pair of entities.
+    fn overlapping_pairs(&self) -> Option<Vec<(Entity, Entity)>> {
+        None
+    }
+
     /// Draw debug gizmos
     fn debug_gizmos(&self, _gizmos: &mut Gizmos) {}
 }
 
 /// Resource which holds the configured `SpatialLookupAlgorithm` and relevant state.
+///
+/// The type parameter `I` identifies the index, see `SpatialIndexPlugin`. The untyped default
+/// index is `SpatialLookupState<()>`.
 #[derive(Resource)]
-pub struct SpatialLookupState {
+pub struct SpatialLookupState<I = ()> {
     pub entities: Vec<(Entity, Vec3)>,
+    /// Volumes of the entities, at the same index as in `entities`.
+    ///
+    /// If empty, all entities are treated as points.
+    pub extents: Vec<SpatialExtent>,
     pub algorithm: Box<dyn SpatialLookupAlgorithm + Send + Sync>,
+    /// Index of each entity in `entities`, rebuilt by `prepare_algorithm` and kept up to date by
+    /// incremental updates.
+    indices: EntityHashMap<usize>,
+    /// Set when the algorithm didn't support an incremental update.
+    rebuild_required: bool,
+    _index: PhantomData<fn() -> I>,
 }
 
-impl Default for SpatialLookupState {
+impl<I> Default for SpatialLookupState<I> {
     fn default() -> Self {
         SpatialLookupState {
             entities: Vec::new(),
+            extents: Vec::new(),
             algorithm: Box::new(algorithms::Naive::default()),
+            indices: EntityHashMap::default(),
+            rebuild_required: false,
+            _index: PhantomData,
         }
     }
 }
 
 impl SpatialLookupState {
+    /// Creates the lookup state of the untyped default index with the given algorithm.
     pub fn with_algorithm<T: SpatialLookupAlgorithm + Send + Sync + 'static>(algorithm: T) -> Self {
+        Self::new(algorithm)
+    }
+}
+
+impl<I> SpatialLookupState<I> {
+    /// Creates the lookup state of the index `I` with the given algorithm.
+    pub fn new<T: SpatialLookupAlgorithm + Send + Sync + 'static>(algorithm: T) -> Self {
         Self {
             entities: vec![],
+            extents: vec![],
             algorithm: Box::new(algorithm),
+            indices: EntityHashMap::default(),
+            rebuild_required: false,
+            _index: PhantomData,
         }
     }
 
@@ -128,30 +428,451 @@ impl SpatialLookupState {
         self.algorithm.entities_in_radius(sample_point, radius)
     }
 
+    /// Calls `visit` with each entity in the radius of the sample point, and its position, until
+    /// `visit` returns `ControlFlow::Break`.
+    ///
+    /// Returns `ControlFlow::Break` if the search was stopped by `visit`.
+    ///
+    /// For algorithms which don't implement `SpatialLookupAlgorithm::visit_in_radius`, the
+    /// entities are found with `entities_in_radius` like in `entities_in_radius_into`, and their
+    /// positions are looked up in `entities`.
+    pub fn visit_in_radius(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+        mut visit: impl FnMut(Entity, Vec3) -> ControlFlow<()>,
+    ) -> ControlFlow<()> {
+        if let Some(flow) = self
+            .algorithm
+            .visit_in_radius(sample_point, radius, &mut visit)
+        {
+            return flow;
+        }
+
+        self.algorithm
+            .entities_in_radius(sample_point, radius)
+            .into_iter()
+            .try_for_each(|entity| match self.indices.get(&entity) {
+                Some(&index) => visit(entity, self.entities[index].1),
+                None => ControlFlow::Continue(()),
+            })
+    }
+
+    /// Appends the entities in the radius of the sample point to `found`.
+    ///
+    /// Unlike `entities_in_radius`, this doesn't allocate if `found` already has enough capacity,
+    /// so the same buffer can be reused for many queries. `found` is not cleared first.
+    ///
+    /// For algorithms which don't implement `SpatialLookupAlgorithm::visit_in_radius`, this
+    /// appends the result of `entities_in_radius` instead.
+    pub fn entities_in_radius_into(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+        found: &mut Vec<Entity>,
+    ) {
+        let visited =
+            self.algorithm
+                .visit_in_radius(sample_point, radius, &mut |entity, _position| {
+                    found.push(entity);
+                    ControlFlow::Continue(())
+                });
+
+        if visited.is_none() {
+            found.extend(self.algorithm.entities_in_radius(sample_point, radius));
+        }
+    }
+
+    /// Returns a list of entities inside the axis-aligned box spanned by `min` and `max`.
+    pub fn entities_in_aabb(&self, min: Vec3, max: Vec3) -> Vec<Entity> {
+        self.algorithm
+            .entities_in_aabb(min, max)
+            .unwrap_or_else(|| {
+                algorithms::naive::entities_in_aabb(&self.entities, &self.extents, min, max)
+            })
+    }
+
+    /// Returns a list of entities inside the given shape, placed in the world by the isometry.
+    pub fn entities_in_shape(&self, shape: &dyn SpatialShape, isometry: Isometry3d) -> Vec<Entity> {
+        self.algorithm
+            .entities_in_shape(shape, isometry)
+            .unwrap_or_else(|| {
+                algorithms::naive::entities_in_shape(&self.entities, &self.extents, shape, isometry)
+            })
+    }
+
+    /// Returns a list of entities within `thickness` of the ray, along with their distance along
+    /// the ray, sorted by that distance.
+    pub fn entities_along_ray(
+        &self,
+        ray: Ray3d,
+        max_distance: f32,
+        thickness: f32,
+    ) -> Vec<(Entity, f32)> {
+        self.algorithm
+            .entities_along_ray(ray, max_distance, thickness)
+            .unwrap_or_else(|| {
+                algorithms::naive::entities_along_ray(
+                    &self.entities,
+                    &self.extents,
+                    ray,
+                    max_distance,
+                    thickness,
+                )
+            })
+    }
+
+    /// Returns up to `k` entities closest to the sample point, ordered by ascending distance.
+    pub fn k_nearest(&self, sample_point: Vec3, k: usize, max_distance: f32) -> Vec<Entity> {
+        self.algorithm
+            .k_nearest(sample_point, k, max_distance)
+            .unwrap_or_else(|| {
+                algorithms::naive::k_nearest(
+                    &self.entities,
+                    &self.extents,
+                    sample_point,
+                    k,
+                    max_distance,
+                )
+            })
+    }
+
+    /// Returns the entity nearest to the sample point for which `predicate` returns true.
+    pub fn nearest_matching(
+        &self,
+        sample_point: Vec3,
+        mut predicate: impl FnMut(Entity) -> bool,
+    ) -> Option<Entity> {
+        match self.algorithm.nearest_iter(sample_point) {
+            Some(mut nearest) => nearest.find(|entity| predicate(*entity)),
+            None => algorithms::naive::nearest_matching(
+                &self.entities,
+                &self.extents,
+                sample_point,
+                predicate,
+            ),
+        }
+    }
+
+    /// Returns every pair of entities whose volumes overlap, e.g. for broad-phase collision
+    /// detection.
+    pub fn overlapping_pairs(&self) -> Vec<(Entity, Entity)> {
+        self.algorithm
+            .overlapping_pairs()
+            .unwrap_or_else(|| algorithms::naive::overlapping_pairs(&self.entities, &self.extents))
+    }
+
     /// Prepares the configured algorithm for lookup.
     pub fn prepare_algorithm(&mut self) {
-        self.algorithm.prepare(&self.entities);
+        self.rebuild_indices();
+        self.rebuild_required = false;
+        self.algorithm
+            .prepare_with_extents(&self.entities, &self.extents);
+    }
+
+    /// Notifies the algorithm that a new frame has started.
+    ///
+    /// If the algorithm requests it, it is rebuilt by the next call to `apply_changes`.
+    pub fn begin_frame(&mut self) {
+        if !self.algorithm.begin_frame() {
+            self.rebuild_required = true;
+        }
+    }
+
+    /// Adds an entity to the prepared lookup, or moves it if it's already in the lookup.
+    ///
+    /// The algorithm is updated incrementally if it supports it, otherwise it is rebuilt by the
+    /// next call to `apply_changes`.
+    pub fn insert_or_update(&mut self, entity: Entity, position: Vec3, extent: SpatialExtent) {
+        self.prepare_incremental_update();
+
+        match self.indices.get(&entity) {
+            Some(&index) => {
+                self.entities[index].1 = position;
+                self.extents[index] = extent;
+
+                if !self.rebuild_required && !self.algorithm.update(entity, position, extent) {
+                    self.rebuild_required = true;
+                }
+            }
+            None => {
+                self.indices.insert(entity, self.entities.len());
+                self.entities.push((entity, position));
+                self.extents.push(extent);
+
+                if !self.rebuild_required && !self.algorithm.insert(entity, position, extent) {
+                    self.rebuild_required = true;
+                }
+            }
+        }
+    }
+
+    /// Removes an entity from the prepared lookup, if it's in the lookup.
+    ///
+    /// The algorithm is updated incrementally if it supports it, otherwise it is rebuilt by the
+    /// next call to `apply_changes`.
+    pub fn remove(&mut self, entity: Entity) {
+        self.prepare_incremental_update();
+
+        let Some(index) = self.indices.remove(&entity) else {
+            return;
+        };
+
+        self.entities.swap_remove(index);
+        self.extents.swap_remove(index);
+        if let Some((moved_entity, _position)) = self.entities.get(index) {
+            self.indices.insert(*moved_entity, index);
+        }
+
+        if !self.rebuild_required && !self.algorithm.remove(entity) {
+            self.rebuild_required = true;
+        }
+    }
+
+    /// Rebuilds the algorithm if it didn't support some of the incremental updates since it was
+    /// last prepared.
+    pub fn apply_changes(&mut self) {
+        if self.rebuild_required {
+            self.prepare_algorithm();
+        }
+    }
+
+    /// Makes sure `extents` and `indices` match `entities` before an incremental update.
+    fn prepare_incremental_update(&mut self) {
+        if self.extents.len() != self.entities.len() {
+            self.extents
+                .resize(self.entities.len(), SpatialExtent::default());
+        }
+
+        if self.indices.len() != self.entities.len() {
+            self.rebuild_indices();
+        }
+    }
+
+    /// Recalculates the index of each entity in `entities`.
+    fn rebuild_indices(&mut self) {
+        self.indices.clear();
+        self.indices.extend(
+            self.entities
+                .iter()
+                .enumerate()
+                .map(|(index, (entity, _position))| (*entity, index)),
+        );
     }
 }
 
-/// Prepares the configured spatial lookup algorithm.
+/// Query data for the mesh `Aabb` of an entity, which is only used with the `mesh_aabb` feature.
+#[cfg(feature = "mesh_aabb")]
+type MeshAabb = Option<&'static bevy::render::primitives::Aabb>;
+#[cfg(not(feature = "mesh_aabb"))]
+type MeshAabb = ();
+
+/// Query data for calculating the position and volume of an entity.
+type EntityVolumeData = (
+    Entity,
+    &'static GlobalTransform,
+    Option<&'static SpatialExtent>,
+    MeshAabb,
+);
+
+/// Query filter for entities whose position or volume has changed.
+#[cfg(feature = "mesh_aabb")]
+type VolumeChanged = Or<(
+    Changed<GlobalTransform>,
+    Changed<SpatialExtent>,
+    Changed<bevy::render::primitives::Aabb>,
+)>;
+#[cfg(not(feature = "mesh_aabb"))]
+type VolumeChanged = Or<(Changed<GlobalTransform>, Changed<SpatialExtent>)>;
+
+/// Calculates the position and volume to index an entity with.
+///
+/// With the `mesh_aabb` feature, entities without a `SpatialExtent` use their mesh `Aabb` as their
+/// volume, and are indexed at the center of the transformed box.
+fn entity_volume(
+    transform: &GlobalTransform,
+    extent: Option<&SpatialExtent>,
+    _mesh_aabb: QueryItem<MeshAabb>,
+) -> (Vec3, SpatialExtent) {
+    #[cfg(feature = "mesh_aabb")]
+    if extent.is_none()
+        && let Some(aabb) = _mesh_aabb
+    {
+        return spatial_extent::from_mesh_aabb(aabb, transform);
+    }
+
+    (transform.translation(), extent.copied().unwrap_or_default())
+}
+
+/// Prepares the spatial lookup algorithm of the index `I` with all entities matching the filter
+/// `F`.
+///
+/// The lookup is built from scratch when the `SpatialLookupState<I>` resource is added. After
+/// that, only entities which were added, moved or removed are passed to the algorithm, which is
+/// rebuilt if it doesn't support incremental updates. Entities which start or stop matching the
+/// filter without moving are handled by `add_tracked_entities`, `remove_untracked_entities` or
+/// `resync_spatial_lookup`, which have to run before this system.
 ///
 /// Any systems using `SpatialQuery<_>` *MUST* be scheduled after this system
-pub fn prepare_spatial_lookup(
-    all_entities: Query<(Entity, &GlobalTransform)>,
-    mut lookup_state: ResMut<SpatialLookupState>,
+pub fn prepare_spatial_lookup<I: Send + Sync + 'static, F: QueryFilter>(
+    all_entities: Query<EntityVolumeData, F>,
+    changed_entities: Query<EntityVolumeData, (F, VolumeChanged)>,
+    mut removed_transforms: RemovedComponents<GlobalTransform>,
+    mut removed_extents: RemovedComponents<SpatialExtent>,
+    #[cfg(feature = "mesh_aabb")] mut removed_mesh_aabbs: RemovedComponents<
+        bevy::render::primitives::Aabb,
+    >,
+    mut lookup_state: ResMut<SpatialLookupState<I>>,
+) {
+    if lookup_state.is_added() {
+        lookup_state.entities.clear();
+        lookup_state.extents.clear();
+
+        for (entity, transform, extent, mesh_aabb) in &all_entities {
+            let (position, extent) = entity_volume(transform, extent, mesh_aabb);
+
+            lookup_state.entities.push((entity, position));
+            lookup_state.extents.push(extent);
+        }
+
+        lookup_state.prepare_algorithm();
+        return;
+    }
+
+    lookup_state.begin_frame();
+
+    for entity in removed_transforms.read() {
+        lookup_state.remove(entity);
+    }
+
+    // entities which lost their volume aren't matched by `VolumeChanged`
+    #[cfg(feature = "mesh_aabb")]
+    let removed_volumes = removed_extents.read().chain(removed_mesh_aabbs.read());
+    #[cfg(not(feature = "mesh_aabb"))]
+    let removed_volumes = removed_extents.read();
+    for entity in removed_volumes {
+        if let Ok((entity, transform, extent, mesh_aabb)) = all_entities.get(entity) {
+            let (position, extent) = entity_volume(transform, extent, mesh_aabb);
+            lookup_state.insert_or_update(entity, position, extent);
+        }
+    }
+
+    for (entity, transform, extent, mesh_aabb) in &changed_entities {
+        let (position, extent) = entity_volume(transform, extent, mesh_aabb);
+        lookup_state.insert_or_update(entity, position, extent);
+    }
+
+    lookup_state.apply_changes();
+}
+
+/// Adds entities to the index `I` when they gain the component `C` and match the filter `F`.
+///
+/// `prepare_spatial_lookup` only notices entities which are spawned, moved or change their
+/// volume. The plugins use this system to add stationary entities which gain their
+/// `SpatialTracked` marker or the component of a `SpatialIndexPlugin`, and it can be added for
+/// any other components used in the filter.
+pub fn add_tracked_entities<I: Send + Sync + 'static, C: Component, F: QueryFilter>(
+    added_entities: Query<EntityVolumeData, (F, Added<C>)>,
+    mut lookup_state: ResMut<SpatialLookupState<I>>,
 ) {
-    lookup_state.entities.clear();
+    // a new lookup is built from scratch by `prepare_spatial_lookup`
+    if lookup_state.is_added() {
+        return;
+    }
 
-    for (entity, transform) in &all_entities {
-        lookup_state
+    for (entity, transform, extent, mesh_aabb) in &added_entities {
+        let (position, extent) = entity_volume(transform, extent, mesh_aabb);
+        lookup_state.insert_or_update(entity, position, extent);
+    }
+}
+
+/// Removes entities from the index `I` when they lose the component `C` and no longer match the
+/// filter `F`.
+///
+/// `prepare_spatial_lookup` only notices entities which are despawned or lose their
+/// `GlobalTransform`. The plugins use this system to remove entities which lose their
+/// `SpatialTracked` marker or the component of a `SpatialIndexPlugin`, and it can be added for
+/// any other components used in the filter.
+pub fn remove_untracked_entities<I: Send + Sync + 'static, C: Component, F: QueryFilter>(
+    mut removed: RemovedComponents<C>,
+    tracked_entities: Query<(), F>,
+    mut lookup_state: ResMut<SpatialLookupState<I>>,
+) {
+    for entity in removed.read() {
+        if !tracked_entities.contains(entity) {
+            lookup_state.remove(entity);
+        }
+    }
+}
+
+/// Adds every entity matching the filter `F` which isn't in the index `I` yet, and removes every
+/// indexed entity which no longer matches it.
+///
+/// The plugins only notice entities starting or stopping to match the filter through their own
+/// marker component, see `add_tracked_entities` and `remove_untracked_entities`. For other
+/// filters, e.g. `Without<Dead>`, this system can be added to re-sync the index every frame:
+/// ```
+/// # use bevy::prelude::*;
+/// # use bevy_mod_spatial_query::prelude::*;
+/// # use bevy_mod_spatial_query::{PrepareSpatialLookup, resync_spatial_lookup};
+/// #
+/// #[derive(Component)]
+/// struct Dead;
+///
+/// # let mut app = App::new();
+/// #
+/// app.add_plugins(FilteredSpatialQueriesPlugin::<Without<Dead>>::default())
+///     .add_systems(
+///         First,
+///         resync_spatial_lookup::<(), Without<Dead>>.before(PrepareSpatialLookup),
+///     );
+/// ```
+///
+/// Unlike the rest of the incremental update, this visits every matching and every indexed
+/// entity, so it is only worth it if the filter can't be tracked otherwise.
+pub fn resync_spatial_lookup<I: Send + Sync + 'static, F: QueryFilter>(
+    all_entities: Query<EntityVolumeData, F>,
+    mut lookup_state: ResMut<SpatialLookupState<I>>,
+) {
+    // a new lookup is built from scratch by `prepare_spatial_lookup`
+    if lookup_state.is_added() {
+        return;
+    }
+
+    lookup_state.prepare_incremental_update();
+    let mut matching_entities = 0;
+    for (entity, transform, extent, mesh_aabb) in &all_entities {
+        matching_entities += 1;
+
+        if !lookup_state.indices.contains_key(&entity) {
+            let (position, extent) = entity_volume(transform, extent, mesh_aabb);
+            lookup_state.insert_or_update(entity, position, extent);
+        }
+    }
+
+    // every matching entity is indexed now, so any other indexed entity no longer matches
+    if matching_entities < lookup_state.entities.len() {
+        let unmatched_entities: Vec<Entity> = lookup_state
             .entities
-            .push((entity, transform.translation()));
+            .iter()
+            .map(|(entity, _position)| *entity)
+            .filter(|entity| !all_entities.contains(*entity))
+            .collect();
+
+        for entity in unmatched_entities {
+            lookup_state.remove(entity);
+        }
     }
+}
 
-    lookup_state.prepare_algorithm();
+pub fn draw_spatial_lookup_gizmos(lookup_state: Res<SpatialLookupState>, gizmos: Gizmos) {
+    draw_spatial_index_gizmos(lookup_state, gizmos);
 }
 
-pub fn draw_spatial_lookup_gizmos(lookup_state: Res<SpatialLookupState>, mut gizmos: Gizmos) {
+/// Draws the debug gizmos of the spatial index `I`.
+pub fn draw_spatial_index_gizmos<I: Send + Sync + 'static>(
+    lookup_state: Res<SpatialLookupState<I>>,
+    mut gizmos: Gizmos,
+) {
     lookup_state.algorithm.debug_gizmos(&mut gizmos);
 }
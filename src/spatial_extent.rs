@@ -0,0 +1,284 @@
+//! Volumes of entities considered by spatial queries.
+use crate::spatial_shape::SpatialShape;
+use bevy::math::FloatPow;
+#[cfg(feature = "mesh_aabb")]
+use bevy::math::Mat3A;
+use bevy::prelude::*;
+
+/// Gives an entity a volume for spatial queries.
+///
+/// By default, entities are treated as points at their `GlobalTransform` translation, which means
+/// that a large entity whose origin is outside a query is never found. Entities with this
+/// component are found by every query which overlaps their volume.
+///
+/// The volume is centered on the translation of the entity and is not rotated or scaled
+/// with it.
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub enum SpatialExtent {
+    /// Sphere with the given radius.
+    Sphere(f32),
+    /// Axis-aligned box with the given half-extents.
+    Cuboid(Vec3),
+}
+
+impl Default for SpatialExtent {
+    /// A sphere with zero radius, i.e. a point.
+    fn default() -> Self {
+        SpatialExtent::Sphere(0.)
+    }
+}
+
+impl SpatialExtent {
+    /// Returns the half-extents of the axis-aligned box bounding this volume.
+    pub fn half_size(&self) -> Vec3 {
+        match self {
+            SpatialExtent::Sphere(radius) => Vec3::splat(*radius),
+            SpatialExtent::Cuboid(half_size) => *half_size,
+        }
+    }
+
+    /// Returns the radius of the sphere bounding this volume.
+    pub fn bounding_radius(&self) -> f32 {
+        match self {
+            SpatialExtent::Sphere(radius) => *radius,
+            SpatialExtent::Cuboid(half_size) => half_size.length(),
+        }
+    }
+
+    /// Returns the squared distance from the point to the closest point of this volume centered
+    /// at `position`.
+    ///
+    /// Points inside the volume have a distance of zero.
+    #[inline]
+    pub fn distance_squared_to_point(&self, position: Vec3, point: Vec3) -> f32 {
+        match self {
+            SpatialExtent::Sphere(radius) => (position.distance(point) - radius).max(0.).squared(),
+            SpatialExtent::Cuboid(half_size) => ((point - position).abs() - *half_size)
+                .max(Vec3::ZERO)
+                .length_squared(),
+        }
+    }
+
+    /// Returns true if this volume centered at `position` overlaps the axis-aligned box spanned by
+    /// `min` and `max`.
+    #[inline]
+    pub fn intersects_aabb(&self, position: Vec3, min: Vec3, max: Vec3) -> bool {
+        match self {
+            SpatialExtent::Sphere(radius) => {
+                position.clamp(min, max).distance_squared(position) <= radius.squared()
+            }
+            SpatialExtent::Cuboid(half_size) => {
+                (position - *half_size).cmple(max).all() && (position + *half_size).cmpge(min).all()
+            }
+        }
+    }
+
+    /// Returns true if this volume centered at `position` overlaps the other volume centered at
+    /// `other_position`.
+    #[inline]
+    pub fn intersects_extent(
+        &self,
+        position: Vec3,
+        other: &SpatialExtent,
+        other_position: Vec3,
+    ) -> bool {
+        match (self, other) {
+            (SpatialExtent::Sphere(radius), SpatialExtent::Sphere(other_radius)) => {
+                position.distance_squared(other_position) <= (radius + other_radius).squared()
+            }
+            (_, SpatialExtent::Cuboid(half_size)) => self.intersects_aabb(
+                position,
+                other_position - *half_size,
+                other_position + *half_size,
+            ),
+            (SpatialExtent::Cuboid(half_size), _) => {
+                other.intersects_aabb(other_position, position - *half_size, position + *half_size)
+            }
+        }
+    }
+
+    /// Returns true if this volume centered at `position` overlaps the given shape.
+    ///
+    /// See `SpatialShape` for which shapes test boxes exactly.
+    #[inline]
+    pub fn intersects_shape(
+        &self,
+        position: Vec3,
+        shape: &dyn SpatialShape,
+        isometry: Isometry3d,
+    ) -> bool {
+        match self {
+            SpatialExtent::Sphere(radius) => shape.intersects_sphere(isometry, position, *radius),
+            SpatialExtent::Cuboid(half_size) => {
+                shape.intersects_cuboid(isometry, position, *half_size)
+            }
+        }
+    }
+
+    /// Returns the distance along the ray to the closest approach of `position`, if this volume
+    /// centered at `position` is within `thickness` of the ray.
+    ///
+    /// Only the part of the ray between its origin and `max_distance` is considered.
+    #[inline]
+    pub fn distance_along_ray(
+        &self,
+        position: Vec3,
+        ray: Ray3d,
+        max_distance: f32,
+        thickness: f32,
+    ) -> Option<f32> {
+        let distance_along_ray = (position - ray.origin)
+            .dot(*ray.direction)
+            .clamp(0.0, max_distance);
+
+        let hit = match self {
+            SpatialExtent::Sphere(radius) => {
+                ray.get_point(distance_along_ray).distance_squared(position)
+                    <= (thickness + radius).squared()
+            }
+            SpatialExtent::Cuboid(half_size) => {
+                segment_distance_squared_to_cuboid(ray, max_distance, position, *half_size)
+                    <= thickness.squared()
+            }
+        };
+
+        hit.then_some(distance_along_ray)
+    }
+}
+
+/// Returns the squared distance from the part of the ray between its origin and `max_distance`
+/// to the axis-aligned box centered at `position`.
+///
+/// Along the ray, the squared distance to the box is a convex function which is quadratic
+/// between the points where the ray crosses the planes of the faces of the box, so its minimum
+/// is found by minimizing each of these pieces.
+fn segment_distance_squared_to_cuboid(
+    ray: Ray3d,
+    max_distance: f32,
+    position: Vec3,
+    half_size: Vec3,
+) -> f32 {
+    let origin = ray.origin - position;
+    let direction = *ray.direction;
+
+    let mut breakpoints = [0.; 8];
+    let mut count = 0;
+    for axis in 0..3 {
+        if direction[axis] != 0. {
+            for face in [-half_size[axis], half_size[axis]] {
+                let distance = (face - origin[axis]) / direction[axis];
+                if distance > 0. && distance < max_distance {
+                    breakpoints[count] = distance;
+                    count += 1;
+                }
+            }
+        }
+    }
+    breakpoints[count] = 0.;
+    breakpoints[count + 1] = max_distance;
+    let breakpoints = &mut breakpoints[..count + 2];
+    breakpoints.sort_unstable_by(f32::total_cmp);
+
+    let cuboid = SpatialExtent::Cuboid(half_size);
+    let mut min_distance_squared = f32::INFINITY;
+    for piece in breakpoints.windows(2) {
+        let (start, end) = (piece[0], piece[1]);
+        let middle = if end.is_finite() {
+            (start + end) / 2.
+        } else {
+            start + 1.
+        };
+
+        // in each piece, every axis is either within the box or beyond one of its faces
+        let point = origin + direction * middle;
+        let offset = point.signum() * half_size;
+        let outside = point.abs().cmpgt(half_size);
+        let (mut numerator, mut denominator) = (0., 0.);
+        for axis in (0..3).filter(|axis| outside.test(*axis)) {
+            numerator -= (origin[axis] - offset[axis]) * direction[axis];
+            denominator += direction[axis].squared();
+        }
+
+        let closest = if denominator > 0. {
+            (numerator / denominator).clamp(start, end)
+        } else {
+            start
+        };
+        min_distance_squared = min_distance_squared
+            .min(cuboid.distance_squared_to_point(Vec3::ZERO, origin + direction * closest));
+    }
+
+    min_distance_squared
+}
+
+/// Calculates the world-space position and volume of an entity from its mesh `Aabb`.
+///
+/// The returned position is the center of the transformed box, which is not necessarily the
+/// translation of the entity.
+#[cfg(feature = "mesh_aabb")]
+pub(crate) fn from_mesh_aabb(
+    aabb: &bevy::render::primitives::Aabb,
+    transform: &GlobalTransform,
+) -> (Vec3, SpatialExtent) {
+    let affine = transform.affine();
+    let center = affine.transform_point3a(aabb.center);
+    // the rotated and scaled box is bounded by the absolute values of the transform matrix
+    let matrix = affine.matrix3;
+    let half_size = Mat3A::from_cols(
+        matrix.x_axis.abs(),
+        matrix.y_axis.abs(),
+        matrix.z_axis.abs(),
+    ) * aabb.half_extents;
+
+    (center.into(), SpatialExtent::Cuboid(half_size.into()))
+}
+
+/// Pairs each entity with its extent, treating all entities as points if `extents` is empty.
+pub(crate) fn with_extents<'a>(
+    entities: &'a [(Entity, Vec3)],
+    extents: &'a [SpatialExtent],
+) -> impl Iterator<Item = (Entity, Vec3, SpatialExtent)> + 'a {
+    debug_assert!(extents.is_empty() || extents.len() == entities.len());
+
+    entities
+        .iter()
+        .zip(
+            extents
+                .iter()
+                .copied()
+                .chain(std::iter::repeat_with(default)),
+        )
+        .map(|((entity, position), extent)| (*entity, *position, extent))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SpatialExtent;
+    use bevy::prelude::*;
+
+    #[test]
+    fn test_distance_along_ray_to_cuboid_edge() {
+        let ray = Ray3d::new(Vec3::new(-5., 0., 0.), Dir3::X);
+        // the closest edge of the box runs along (x, 1, 1), sqrt(2) away from the ray, while the
+        // box grown by the thickness would already contain the ray
+        let cuboid = SpatialExtent::Cuboid(Vec3::ONE);
+        let position = Vec3::new(2., 2., 2.);
+
+        assert_eq!(cuboid.distance_along_ray(position, ray, 10., 1.4), None);
+        assert_eq!(cuboid.distance_along_ray(position, ray, 10., 1.5), Some(7.));
+    }
+
+    #[test]
+    fn test_distance_along_ray_to_cuboid_beyond_end() {
+        let ray = Ray3d::new(Vec3::new(-5., 0., 0.), Dir3::X);
+        // the ray ends at (5, 0, 0), 6 away from the closest face of the box
+        let cuboid = SpatialExtent::Cuboid(Vec3::ONE);
+        let position = Vec3::new(12., 0.5, 0.);
+
+        assert_eq!(cuboid.distance_along_ray(position, ray, 10., 5.9), None);
+        assert_eq!(
+            cuboid.distance_along_ray(position, ray, 10., 6.1),
+            Some(10.)
+        );
+    }
+}
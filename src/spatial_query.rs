@@ -1,24 +1,643 @@
 use crate::SpatialLookupState;
-use crate::spatial_query_iterator::SpatialQueryIterator;
-use bevy::ecs::query::{QueryData, QueryFilter};
+use crate::spatial_query_iterator::{
+    PooledEntityIter, SpatialQueryDistanceIterator, SpatialQueryIterator,
+};
+use crate::spatial_shape::SpatialShape;
+use bevy::ecs::entity::EntityHashSet;
+use bevy::ecs::query::{QueryData, QueryEntityError, QueryFilter, ROQueryItem};
 use bevy::ecs::system::SystemParam;
-use bevy::math::Vec3;
-use bevy::prelude::{Query, Res};
+use bevy::math::{FloatOrd, Isometry3d, Ray3d, Vec3};
+use bevy::prelude::{Entity, Local, Query, Res};
+#[cfg(feature = "bevy_render")]
+use bevy::{
+    prelude::{Camera, GlobalTransform},
+    render::primitives::Frustum,
+};
+use std::ops::ControlFlow;
+use std::sync::Mutex;
 
+/// Like `Query<D, F>`, but with methods for finding entities by their position.
+///
+/// Entities are looked up from the spatial index `I`, which defaults to the untyped index of
+/// `SpatialQueriesPlugin`. See `SpatialIndexPlugin` for using multiple indices.
 #[derive(SystemParam)]
-pub struct SpatialQuery<'w, 's, D: QueryData + 'static, F: QueryFilter + 'static = ()> {
-    lookup: Res<'w, SpatialLookupState>,
+pub struct SpatialQuery<
+    'w,
+    's,
+    D: QueryData + 'static,
+    F: QueryFilter + 'static = (),
+    I: Send + Sync + 'static = (),
+> {
+    lookup: Res<'w, SpatialLookupState<I>>,
     query: Query<'w, 's, D, F>,
+    /// Entities found by the latest lookup, reused between queries to avoid allocating.
+    found_entities: Local<'s, Vec<Entity>>,
+    /// Entities found by the latest lookup along with their distances and positions, reused
+    /// between queries to avoid allocating.
+    found_with_distance: Local<'s, Vec<(Entity, f32, Vec3)>>,
+    /// Entities already seen while removing duplicates from the found entities.
+    seen_entities: Local<'s, EntityHashSet>,
+    /// Buffers for the entities found by `iter_in_radius`, which are returned to the pool when
+    /// the iterator is dropped, so that nested lookups can reuse them too.
+    radius_buffers: Local<'s, Mutex<Vec<Vec<Entity>>>>,
 }
 
-impl<'w, 's, D: QueryData + 'static, F: QueryFilter + 'static> SpatialQuery<'w, 's, D, F> {
+impl<'w, 's, D: QueryData + 'static, F: QueryFilter + 'static, I: Send + Sync + 'static>
+    SpatialQuery<'w, 's, D, F, I>
+{
+    /// Returns an iterator over the query items of the given entities, which are stored in the
+    /// reused buffer.
+    fn iter_found<'q>(
+        &'q mut self,
+        entities: impl IntoIterator<Item = Entity>,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        self.found_entities.clear();
+        self.found_entities.extend(entities);
+
+        self.iter_found_entities()
+    }
+
+    /// Returns an iterator over the query items of the entities in the reused buffer.
+    ///
+    /// A custom `SpatialLookupAlgorithm` may return the same entity more than once, which would
+    /// result in aliasing mutable query items, so duplicates are removed first. The order of the
+    /// entities is kept, which matters for ordered queries like `k_nearest`.
+    fn iter_found_entities<'q>(&'q mut self) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        let seen_entities = &mut *self.seen_entities;
+        seen_entities.clear();
+        self.found_entities
+            .retain(|entity| seen_entities.insert(*entity));
+
+        // SAFETY: duplicates were removed above
+        unsafe { SpatialQueryIterator::with_unique_entities(&self.found_entities, &mut self.query) }
+    }
+
     pub fn in_radius<'q>(
         &'q mut self,
         sample_point: Vec3,
         radius: f32,
     ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
-        let entities_in_range = self.lookup.entities_in_radius(sample_point, radius);
+        self.found_entities.clear();
+        self.lookup
+            .entities_in_radius_into(sample_point, radius, &mut self.found_entities);
+
+        self.iter_found_entities()
+    }
+
+    /// Returns the query items of all entities in radius of the sample point, along with the
+    /// distance from the sample point to the position of each entity and the position itself.
+    ///
+    /// The positions are the ones the lookup already has, so this saves looking up the transform
+    /// of each entity just to measure the distance:
+    /// ```
+    /// # use bevy::prelude::*;
+    /// # use bevy_mod_spatial_query::prelude::*;
+    /// #
+    /// # #[derive(Component)]
+    /// # struct Health(f32);
+    /// #
+    /// fn explode(mut targets: SpatialQuery<&mut Health>) {
+    ///     for (mut health, distance, _position) in targets.in_radius_with_distance(Vec3::ZERO, 5.) {
+    ///         health.0 -= 100. * (1. - distance / 5.);
+    ///     }
+    /// }
+    /// ```
+    ///
+    /// The distance is measured to the position of the entity, so for entities with a
+    /// `SpatialExtent` it may be larger than `radius`. The items are in no particular order, see
+    /// `in_radius_sorted_by_distance`.
+    pub fn in_radius_with_distance<'q>(
+        &'q mut self,
+        sample_point: Vec3,
+        radius: f32,
+    ) -> SpatialQueryDistanceIterator<'w, 's, 'q, D, F> {
+        self.find_with_distance(sample_point, radius);
+
+        self.iter_found_with_distance()
+    }
+
+    /// Like `in_radius_with_distance`, but the items are in ascending order of distance.
+    pub fn in_radius_sorted_by_distance<'q>(
+        &'q mut self,
+        sample_point: Vec3,
+        radius: f32,
+    ) -> SpatialQueryDistanceIterator<'w, 's, 'q, D, F> {
+        self.find_with_distance(sample_point, radius);
+        self.found_with_distance
+            .sort_unstable_by_key(|(_entity, distance, _position)| FloatOrd(*distance));
+
+        self.iter_found_with_distance()
+    }
+
+    /// Fills the reused buffer with the entities in radius of the sample point, along with their
+    /// distances and positions.
+    fn find_with_distance(&mut self, sample_point: Vec3, radius: f32) {
+        let found_with_distance = &mut *self.found_with_distance;
+        found_with_distance.clear();
+
+        let _ = self
+            .lookup
+            .visit_in_radius(sample_point, radius, |entity, position| {
+                found_with_distance.push((entity, position.distance(sample_point), position));
+                ControlFlow::Continue(())
+            });
+    }
+
+    /// Returns an iterator over the query items of the entities in the reused buffer, along with
+    /// their distances and positions, after removing duplicates like `iter_found_entities`.
+    fn iter_found_with_distance<'q>(
+        &'q mut self,
+    ) -> SpatialQueryDistanceIterator<'w, 's, 'q, D, F> {
+        let seen_entities = &mut *self.seen_entities;
+        seen_entities.clear();
+        self.found_with_distance
+            .retain(|(entity, _distance, _position)| seen_entities.insert(*entity));
+
+        // SAFETY: duplicates were removed above
+        unsafe {
+            SpatialQueryDistanceIterator::with_unique_entities(
+                &self.found_with_distance,
+                &mut self.query,
+            )
+        }
+    }
+
+    /// Returns the query items of all entities in radius of the sample point, or an error if the
+    /// lookup found the same entity more than once.
+    ///
+    /// Like `Query::get_many_mut`, every entity is checked for being unique before any mutable
+    /// item is returned, instead of silently removing duplicates like `in_radius`. This is mostly
+    /// useful for testing a custom `SpatialLookupAlgorithm`. Entities which don't match the query
+    /// are skipped.
+    pub fn in_radius_many_mut(
+        &mut self,
+        sample_point: Vec3,
+        radius: f32,
+    ) -> Result<Vec<D::Item<'_>>, QueryEntityError<'_>> {
+        self.found_entities.clear();
+        self.lookup
+            .entities_in_radius_into(sample_point, radius, &mut self.found_entities);
+
+        self.seen_entities.clear();
+        for entity in self.found_entities.iter() {
+            if !self.seen_entities.insert(*entity) {
+                return Err(QueryEntityError::AliasedMutability(*entity));
+            }
+        }
+
+        let query = &self.query;
+        Ok(self
+            .found_entities
+            .iter()
+            // SAFETY: `self` is mutably borrowed for the lifetime of the items, and the entities
+            // were checked to be unique above
+            .filter_map(|entity| unsafe { query.get_unchecked(*entity) }.ok())
+            .collect())
+    }
+
+    /// Returns the read-only query items of all entities in radius of the sample point.
+    ///
+    /// Like `Query::iter`, this only needs `&self`, so lookups can be nested, e.g. to look at the
+    /// neighbours of every unit:
+    /// ```
+    /// # use bevy::prelude::*;
+    /// # use bevy_mod_spatial_query::prelude::*;
+    /// #
+    /// # #[derive(Component)]
+    /// # struct Unit;
+    /// #
+    /// fn count_neighbours(units: SpatialQuery<&GlobalTransform, With<Unit>>) {
+    ///     for transform in units.iter_in_radius(Vec3::ZERO, 100.) {
+    ///         let neighbours = units.iter_in_radius(transform.translation(), 5.).count();
+    ///         // Do something with the number of neighbours..
+    ///     }
+    /// }
+    /// #
+    /// # let mut app = App::new();
+    /// # app.add_plugins((MinimalPlugins, SpatialQueriesPlugin))
+    /// #     .add_systems(Update, count_neighbours);
+    /// # app.world_mut().spawn((Unit, GlobalTransform::default()));
+    /// # app.update();
+    /// ```
+    ///
+    /// The found entities are stored in buffers which are reused by later calls, so like
+    /// `in_radius`, this doesn't allocate once the buffers are large enough.
+    pub fn iter_in_radius(
+        &self,
+        sample_point: Vec3,
+        radius: f32,
+    ) -> impl Iterator<Item = ROQueryItem<'_, D>> + '_ {
+        PooledEntityIter::new(&self.radius_buffers, |found| {
+            self.lookup
+                .entities_in_radius_into(sample_point, radius, found);
+        })
+        .filter_map(move |entity| self.query.get(entity).ok())
+    }
+
+    /// Returns all entities inside the axis-aligned box spanned by `min` and `max`.
+    ///
+    /// Useful for box selection and box-shaped trigger volumes.
+    pub fn in_aabb<'q>(
+        &'q mut self,
+        min: Vec3,
+        max: Vec3,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        let entities_in_aabb = self.lookup.entities_in_aabb(min, max);
+
+        self.iter_found(entities_in_aabb)
+    }
+
+    /// Returns all entities inside the given shape, placed in the world by the isometry.
+    ///
+    /// Any of the bounded `bevy_math` primitives can be used as the shape, e.g. an oriented box:
+    /// ```
+    /// # use bevy::prelude::*;
+    /// # use bevy_mod_spatial_query::prelude::*;
+    /// #
+    /// # #[derive(Component)]
+    /// # struct Unit;
+    /// #
+    /// fn select_units_in_box(mut units: SpatialQuery<&mut Unit>) {
+    ///     let isometry = Isometry3d::new(Vec3::ZERO, Quat::from_rotation_y(0.5));
+    ///
+    ///     for unit in units.in_shape(&Cuboid::new(10., 2., 4.), isometry) {
+    ///         // Do something with the units..
+    ///     }
+    /// }
+    /// ```
+    pub fn in_shape<'q>(
+        &'q mut self,
+        shape: &impl SpatialShape,
+        isometry: impl Into<Isometry3d>,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        let entities_in_shape = self.lookup.entities_in_shape(shape, isometry.into());
+
+        self.iter_found(entities_in_shape)
+    }
+
+    /// Returns all entities inside the given frustum.
+    ///
+    /// The `Frustum` component of a camera can be used directly to find every entity the camera
+    /// can see, without going through the renderer.
+    #[cfg(feature = "bevy_render")]
+    pub fn in_frustum<'q>(
+        &'q mut self,
+        frustum: &Frustum,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        self.in_shape(frustum, Isometry3d::IDENTITY)
+    }
+
+    /// Returns all entities inside the view frustum of the given camera.
+    ///
+    /// If the projection of the camera has no far plane, the frustum extends infinitely.
+    #[cfg(feature = "bevy_render")]
+    pub fn in_camera_view<'q>(
+        &'q mut self,
+        camera: &Camera,
+        camera_transform: &GlobalTransform,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        let clip_from_world = camera.clip_from_view() * camera_transform.compute_matrix().inverse();
+        let mut frustum = Frustum::from_clip_from_world(&clip_from_world);
+
+        // Perspective projections with an infinite far plane result in a degenerate far
+        // half-space. Replace it with a copy of the near half-space, which is always satisfied
+        // by points that are already inside the frustum.
+        if !frustum.half_spaces[5].normal_d().is_finite() {
+            frustum.half_spaces[5] = frustum.half_spaces[4];
+        }
+
+        self.in_frustum(&frustum)
+    }
+
+    /// Returns all entities within `thickness` of the ray, in ascending order of distance along
+    /// the ray.
+    ///
+    /// Only the part of the ray between its origin and `max_distance` is considered. This can be
+    /// used for cheap hitscan and line-of-sight checks, e.g. the first item is the closest hit.
+    pub fn along_ray<'q>(
+        &'q mut self,
+        ray: Ray3d,
+        max_distance: f32,
+        thickness: f32,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        let entities_along_ray = self
+            .lookup
+            .entities_along_ray(ray, max_distance, thickness)
+            .into_iter()
+            .map(|(entity, _distance)| entity);
+
+        self.iter_found(entities_along_ray)
+    }
+
+    /// Returns up to `k` entities nearest to the sample point, in ascending order of distance.
+    ///
+    /// Only entities within `max_distance` of the sample point are considered. The `k` nearest
+    /// entities are looked up before the query filters are applied, so fewer than `k` items may
+    /// be returned if some of them do not match the query.
+    pub fn k_nearest<'q>(
+        &'q mut self,
+        sample_point: Vec3,
+        k: usize,
+        max_distance: f32,
+    ) -> SpatialQueryIterator<'w, 's, 'q, D, F> {
+        let nearest_entities = self.lookup.k_nearest(sample_point, k, max_distance);
+
+        self.iter_found(nearest_entities)
+    }
+
+    /// Returns the entity nearest to the sample point that matches this query, along with its
+    /// query item.
+    ///
+    /// Unlike `k_nearest`, the query filters are taken into account during the search, so the
+    /// search keeps going outwards until a matching entity is found.
+    pub fn nearest(&mut self, sample_point: Vec3) -> Option<(Entity, D::Item<'_>)> {
+        let entity = self
+            .lookup
+            .nearest_matching(sample_point, |entity| self.query.contains(entity))?;
+
+        self.query.get_mut(entity).ok().map(|item| (entity, item))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::algorithms::Naive;
+    use crate::prelude::*;
+    use crate::{PrepareSpatialLookup, resync_spatial_lookup};
+    use bevy::ecs::query::QueryEntityError;
+    use bevy::ecs::system::RunSystemOnce;
+    use bevy::prelude::*;
+
+    /// Broken algorithm which returns every entity in radius twice
+    #[derive(Default)]
+    struct DuplicatingLookup(Naive);
+
+    impl SpatialLookupAlgorithm for DuplicatingLookup {
+        fn prepare(&mut self, entities: &[(Entity, Vec3)]) {
+            self.0.prepare(entities);
+        }
+
+        fn entities_in_radius(&self, sample_point: Vec3, radius: f32) -> Vec<Entity> {
+            let found = self.0.entities_in_radius(sample_point, radius);
+
+            found.iter().chain(found.iter()).copied().collect()
+        }
+    }
+
+    fn app_with_duplicating_lookup() -> App {
+        let mut app = App::new();
+        app.insert_resource(SpatialLookupState::with_algorithm(
+            DuplicatingLookup::default(),
+        ))
+        .add_plugins(SpatialQueriesPlugin);
+
+        for x in 0..10 {
+            app.world_mut()
+                .spawn(GlobalTransform::from_xyz(x as f32, 0., 0.));
+        }
+        app.update();
+
+        app
+    }
+
+    #[test]
+    fn test_in_radius_removes_duplicates() {
+        let mut app = app_with_duplicating_lookup();
+
+        let found = app
+            .world_mut()
+            .run_system_once(|mut query: SpatialQuery<Entity>| {
+                query.in_radius(Vec3::ZERO, 4.5).collect::<Vec<_>>()
+            })
+            .unwrap();
+
+        assert_eq!(found.len(), 5);
+    }
+
+    #[test]
+    fn test_in_radius_many_mut_detects_duplicates() {
+        let mut app = app_with_duplicating_lookup();
+
+        let result = app
+            .world_mut()
+            .run_system_once(|mut query: SpatialQuery<&mut GlobalTransform>| {
+                query
+                    .in_radius_many_mut(Vec3::ZERO, 4.5)
+                    .map(|items| items.len())
+                    .map_err(|error| matches!(error, QueryEntityError::AliasedMutability(_)))
+            })
+            .unwrap();
+
+        assert_eq!(result, Err(true));
+    }
+
+    /// Algorithm which only finds every other entity in radius, to tell its results apart from
+    /// those of `Naive`
+    #[derive(Default)]
+    struct SkippingLookup(Naive);
+
+    impl SpatialLookupAlgorithm for SkippingLookup {
+        fn prepare(&mut self, entities: &[(Entity, Vec3)]) {
+            self.0.prepare(entities);
+        }
+
+        fn entities_in_radius(&self, sample_point: Vec3, radius: f32) -> Vec<Entity> {
+            let mut found = self.0.entities_in_radius(sample_point, radius);
+            found.sort();
+
+            found.into_iter().step_by(2).collect()
+        }
+    }
+
+    #[test]
+    fn test_radius_queries_use_algorithm_without_visitor() {
+        let mut app = App::new();
+        app.insert_resource(SpatialLookupState::with_algorithm(SkippingLookup::default()))
+            .add_plugins(SpatialQueriesPlugin);
+        for x in 0..10 {
+            app.world_mut()
+                .spawn(GlobalTransform::from_xyz(x as f32, 0., 0.));
+        }
+
+        // the lookup is rebuilt when it's added and whenever an entity is spawned, because the
+        // algorithm doesn't support incremental updates
+        for frame in 0..3 {
+            if frame == 2 {
+                app.world_mut()
+                    .spawn(GlobalTransform::from_xyz(100., 0., 0.));
+            }
+            app.update();
+
+            let (found, found_with_distance, found_read_only) = app
+                .world_mut()
+                .run_system_once(|mut query: SpatialQuery<Entity>| {
+                    let mut found = query.in_radius(Vec3::ZERO, 4.5).collect::<Vec<_>>();
+                    let mut found_with_distance = query
+                        .in_radius_sorted_by_distance(Vec3::ZERO, 4.5)
+                        .map(|(entity, distance, position)| {
+                            assert_eq!(distance, position.length());
+                            entity
+                        })
+                        .collect::<Vec<_>>();
+                    let mut found_read_only =
+                        query.iter_in_radius(Vec3::ZERO, 4.5).collect::<Vec<_>>();
+                    let found_nested = query
+                        .iter_in_radius(Vec3::ZERO, 4.5)
+                        .map(|_entity| query.iter_in_radius(Vec3::ZERO, 4.5).count())
+                        .collect::<Vec<_>>();
+                    found.sort();
+                    found_with_distance.sort();
+                    found_read_only.sort();
+                    assert_eq!(found_nested, vec![3; 3]);
+
+                    (found, found_with_distance, found_read_only)
+                })
+                .unwrap();
+
+            assert_eq!(found.len(), 3);
+            assert_eq!(found_with_distance, found);
+            assert_eq!(found_read_only, found);
+        }
+    }
+
+    #[test]
+    fn test_in_radius_sorted_by_distance() {
+        let mut app = App::new();
+        app.add_plugins(SpatialQueriesPlugin);
+        for x in [3., -1., 4., 1., -5., 9.] {
+            app.world_mut().spawn(GlobalTransform::from_xyz(x, 0., 0.));
+        }
+        app.update();
+
+        let found = app
+            .world_mut()
+            .run_system_once(|mut query: SpatialQuery<&GlobalTransform>| {
+                query
+                    .in_radius_sorted_by_distance(Vec3::ZERO, 4.5)
+                    .map(|(transform, distance, position)| {
+                        assert_eq!(transform.translation(), position);
+                        distance
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .unwrap();
+
+        assert_eq!(found, vec![1., 1., 3., 4.]);
+    }
+
+    #[test]
+    fn test_in_radius_many_mut() {
+        let mut app = App::new();
+        app.add_plugins(SpatialQueriesPlugin);
+        for x in 0..10 {
+            app.world_mut()
+                .spawn(GlobalTransform::from_xyz(x as f32, 0., 0.));
+        }
+        app.update();
+
+        let found = app
+            .world_mut()
+            .run_system_once(|mut query: SpatialQuery<&mut GlobalTransform>| {
+                query
+                    .in_radius_many_mut(Vec3::ZERO, 4.5)
+                    .map(|items| items.len())
+                    .ok()
+            })
+            .unwrap();
+
+        assert_eq!(found, Some(5));
+    }
+
+    #[derive(Component)]
+    struct Enemy;
+
+    #[derive(Component)]
+    struct Dead;
+
+    /// Helper function to count the entities of the index `I` within a radius of the origin
+    fn count_in_radius<I: Send + Sync + 'static>(app: &mut App) -> usize {
+        app.world_mut()
+            .run_system_once(|mut query: SpatialQuery<Entity, (), I>| {
+                query.in_radius(Vec3::ZERO, 1.).count()
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_index_finds_stationary_entity_gaining_marker() {
+        let mut app = App::new();
+        app.add_plugins(SpatialIndexPlugin::<Enemy>::default());
+        let entity = app.world_mut().spawn(GlobalTransform::default()).id();
+        app.update();
+
+        assert_eq!(count_in_radius::<Enemy>(&mut app), 0);
+
+        app.world_mut().entity_mut(entity).insert(Enemy);
+        app.update();
+
+        assert_eq!(count_in_radius::<Enemy>(&mut app), 1);
+    }
+
+    #[test]
+    fn test_filtered_finds_stationary_entity_gaining_marker() {
+        let mut app = App::new();
+        app.add_plugins(FilteredSpatialQueriesPlugin::<With<SpatialTracked>>::default());
+        let entity = app.world_mut().spawn(GlobalTransform::default()).id();
+        app.update();
+
+        assert_eq!(count_in_radius::<()>(&mut app), 0);
+
+        app.world_mut().entity_mut(entity).insert(SpatialTracked);
+        app.update();
+
+        assert_eq!(count_in_radius::<()>(&mut app), 1);
+    }
+
+    #[test]
+    fn test_filtered_removes_stationary_entity_leaving_filter() {
+        let mut app = App::new();
+        app.add_plugins(FilteredSpatialQueriesPlugin::<Without<Dead>>::default())
+            .add_systems(
+                First,
+                resync_spatial_lookup::<(), Without<Dead>>.before(PrepareSpatialLookup),
+            );
+        let entity = app.world_mut().spawn(GlobalTransform::default()).id();
+        app.update();
+
+        assert_eq!(count_in_radius::<()>(&mut app), 1);
+
+        app.world_mut().entity_mut(entity).insert(Dead);
+        app.update();
+
+        assert_eq!(count_in_radius::<()>(&mut app), 0);
+
+        app.world_mut().entity_mut(entity).remove::<Dead>();
+        app.update();
+
+        assert_eq!(count_in_radius::<()>(&mut app), 1);
+    }
+
+    #[cfg(feature = "mesh_aabb")]
+    #[test]
+    fn test_removing_mesh_aabb_resets_volume() {
+        use bevy::render::primitives::Aabb;
+
+        let mut app = App::new();
+        app.add_plugins(SpatialQueriesPlugin);
+        let entity = app
+            .world_mut()
+            .spawn((
+                GlobalTransform::from_xyz(3., 0., 0.),
+                Aabb::from_min_max(Vec3::splat(-3.), Vec3::splat(3.)),
+            ))
+            .id();
+        app.update();
+
+        assert_eq!(count_in_radius::<()>(&mut app), 1);
+
+        app.world_mut().entity_mut(entity).remove::<Aabb>();
+        app.update();
 
-        SpatialQueryIterator::with_entities(entities_in_range, &mut self.query)
+        assert_eq!(count_in_radius::<()>(&mut app), 0);
     }
 }
@@ -1,16 +1,29 @@
 use bevy::ecs::query::{QueryData, QueryFilter};
-use bevy::prelude::{Entity, Query};
+use bevy::prelude::{Entity, Query, Vec3};
+use std::slice;
+use std::sync::{Mutex, PoisonError};
 
 pub struct SpatialQueryIterator<'w, 's, 'q, D: QueryData + 'static, F: QueryFilter + 'static> {
-    entities: Vec<Entity>,
+    entities: slice::Iter<'q, Entity>,
     query: &'q mut Query<'w, 's, D, F>,
 }
 
 impl<'w, 's, 'q, D: QueryData + 'static, F: QueryFilter + 'static>
     SpatialQueryIterator<'w, 's, 'q, D, F>
 {
-    pub(crate) fn with_entities(entities: Vec<Entity>, query: &'q mut Query<'w, 's, D, F>) -> Self {
-        SpatialQueryIterator { entities, query }
+    /// Creates an iterator over the query items of the given entities.
+    ///
+    /// # Safety
+    ///
+    /// `entities` must not contain duplicates, otherwise mutable query items would alias.
+    pub(crate) unsafe fn with_unique_entities(
+        entities: &'q [Entity],
+        query: &'q mut Query<'w, 's, D, F>,
+    ) -> Self {
+        SpatialQueryIterator {
+            entities: entities.iter(),
+            query,
+        }
     }
 }
 
@@ -23,7 +36,9 @@ where
     type Item = D::Item<'q>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        while let Some(entity) = self.entities.pop() {
+        for &entity in self.entities.by_ref() {
+            // SAFETY: the query is mutably borrowed for 'q and every entity is only visited once,
+            // so the returned items never alias
             match unsafe { self.query.get_unchecked(entity) } {
                 Ok(data) => {
                     return Some(unsafe { std::mem::transmute::<D::Item<'_>, D::Item<'q>>(data) });
@@ -39,3 +54,121 @@ where
         (0, Some(self.entities.len()))
     }
 }
+
+/// Like `SpatialQueryIterator`, but also yields the distance from the sample point to the position
+/// of each entity, and the position itself.
+pub struct SpatialQueryDistanceIterator<
+    'w,
+    's,
+    'q,
+    D: QueryData + 'static,
+    F: QueryFilter + 'static,
+> {
+    entities: slice::Iter<'q, (Entity, f32, Vec3)>,
+    query: &'q mut Query<'w, 's, D, F>,
+}
+
+impl<'w, 's, 'q, D: QueryData + 'static, F: QueryFilter + 'static>
+    SpatialQueryDistanceIterator<'w, 's, 'q, D, F>
+{
+    /// Creates an iterator over the query items of the given entities, along with their distances
+    /// and positions.
+    ///
+    /// # Safety
+    ///
+    /// `entities` must not contain the same entity twice, otherwise mutable query items would
+    /// alias.
+    pub(crate) unsafe fn with_unique_entities(
+        entities: &'q [(Entity, f32, Vec3)],
+        query: &'q mut Query<'w, 's, D, F>,
+    ) -> Self {
+        SpatialQueryDistanceIterator {
+            entities: entities.iter(),
+            query,
+        }
+    }
+}
+
+impl<'w, 's, 'q, D: QueryData + 'static, F: QueryFilter + 'static> Iterator
+    for SpatialQueryDistanceIterator<'w, 's, 'q, D, F>
+where
+    'w: 'q,
+    's: 'q,
+{
+    type Item = (D::Item<'q>, f32, Vec3);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for &(entity, distance, position) in self.entities.by_ref() {
+            // SAFETY: the query is mutably borrowed for 'q and every entity is only visited once,
+            // so the returned items never alias
+            match unsafe { self.query.get_unchecked(entity) } {
+                Ok(data) => {
+                    let data = unsafe { std::mem::transmute::<D::Item<'_>, D::Item<'q>>(data) };
+                    return Some((data, distance, position));
+                }
+                Err(_) => continue,
+            }
+        }
+
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.entities.len()))
+    }
+}
+
+/// Iterator over entities stored in a buffer borrowed from a pool, which is returned to the pool
+/// when the iterator is dropped, so that the buffer can be reused without allocating.
+pub(crate) struct PooledEntityIter<'a> {
+    entities: Vec<Entity>,
+    next: usize,
+    pool: &'a Mutex<Vec<Vec<Entity>>>,
+}
+
+impl<'a> PooledEntityIter<'a> {
+    /// Takes an empty buffer from the pool, and fills it with `find`.
+    pub(crate) fn new(
+        pool: &'a Mutex<Vec<Vec<Entity>>>,
+        find: impl FnOnce(&mut Vec<Entity>),
+    ) -> Self {
+        let mut entities = pool
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pop()
+            .unwrap_or_default();
+        entities.clear();
+        find(&mut entities);
+
+        PooledEntityIter {
+            entities,
+            next: 0,
+            pool,
+        }
+    }
+}
+
+impl Iterator for PooledEntityIter<'_> {
+    type Item = Entity;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entity = self.entities.get(self.next).copied();
+        self.next += 1;
+
+        entity
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.entities.len().saturating_sub(self.next);
+        (remaining, Some(remaining))
+    }
+}
+
+impl Drop for PooledEntityIter<'_> {
+    fn drop(&mut self) {
+        self.pool
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(std::mem::take(&mut self.entities));
+    }
+}
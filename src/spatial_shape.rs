@@ -0,0 +1,387 @@
+//! Shapes usable with `SpatialQuery::in_shape`.
+use bevy::math::bounding::{Aabb3d, Bounded3d, IntersectsVolume};
+use bevy::math::{Mat3A, Vec3A};
+use bevy::prelude::*;
+
+/// Trait for shapes which can be used to query entities with `SpatialQuery::in_shape`.
+///
+/// A shape is always queried together with an `Isometry3d`, which places the shape in the world.
+/// Lookup algorithms use the bounding volume to skip over parts of the world that can't contain
+/// any matching entities, and the intersection tests to filter the remaining entities. Entities
+/// with a `SpatialExtent` are filtered with `intersects_sphere` or `intersects_cuboid`.
+///
+/// `intersects_sphere` *MUST* be exact, since it also decides which points are inside the shape.
+/// `intersects_cuboid` *MUST NOT* miss any box which overlaps the shape, but it may report boxes
+/// close to the shape as overlapping if an exact test is too expensive. Queries with such a shape
+/// can therefore return entities with a `SpatialExtent::Cuboid` which are just outside of it.
+///
+/// This trait is implemented for the bounded 3D primitives from `bevy_math`. The 2D primitives
+/// (`Circle`, `Rectangle`) lie on the XY plane of the isometry and extend infinitely along its
+/// Z axis, which makes them useful for 2D games where the Z coordinate is only used for layering.
+/// Of these, `Sphere` and `Cuboid` test boxes exactly.
+pub trait SpatialShape {
+    /// Returns the axis-aligned bounding box of the shape, placed in the world by the isometry.
+    fn bounding_aabb(&self, isometry: Isometry3d) -> Aabb3d;
+
+    /// Returns true if the point is inside the shape placed in the world by the isometry.
+    ///
+    /// Points on the surface of the shape are considered to be inside it. The default
+    /// implementation calls `intersects_sphere` with a radius of zero.
+    fn contains_point(&self, isometry: Isometry3d, point: Vec3) -> bool {
+        self.intersects_sphere(isometry, point, 0.)
+    }
+
+    /// Returns false if the shape placed in the world by the isometry can't intersect the given
+    /// axis-aligned box.
+    ///
+    /// Lookup algorithms use this to cull parts of the world, so false positives are allowed but
+    /// false negatives are not. The default implementation tests the box against `bounding_aabb`.
+    fn intersects_aabb(&self, isometry: Isometry3d, aabb: &Aabb3d) -> bool {
+        self.bounding_aabb(isometry).intersects(aabb)
+    }
+
+    /// Returns true if the shape placed in the world by the isometry overlaps the given sphere.
+    ///
+    /// This is used to find entities with a `SpatialExtent::Sphere`, and points are spheres with a
+    /// radius of zero.
+    fn intersects_sphere(&self, isometry: Isometry3d, center: Vec3, radius: f32) -> bool;
+
+    /// Returns true if the shape placed in the world by the isometry may overlap the axis-aligned
+    /// box with the given center and half-size.
+    ///
+    /// This is used to find entities with a `SpatialExtent::Cuboid`. The default implementation
+    /// tests both the bounding sphere of the box and the box itself with `intersects_aabb`, which
+    /// may report an overlap close to the corners of the box where there is none.
+    fn intersects_cuboid(&self, isometry: Isometry3d, center: Vec3, half_size: Vec3) -> bool {
+        self.intersects_sphere(isometry, center, half_size.length())
+            && self.intersects_aabb(isometry, &Aabb3d::new(center, half_size))
+    }
+}
+
+impl SpatialShape for Sphere {
+    fn bounding_aabb(&self, isometry: Isometry3d) -> Aabb3d {
+        self.aabb_3d(isometry)
+    }
+
+    fn intersects_sphere(&self, isometry: Isometry3d, center: Vec3, radius: f32) -> bool {
+        isometry.inverse_transform_point(center).length() <= self.radius + radius
+    }
+
+    fn intersects_cuboid(&self, isometry: Isometry3d, center: Vec3, half_size: Vec3) -> bool {
+        // the sphere is unaffected by the rotation, so only the distance to the box matters
+        ((Vec3::from(isometry.translation) - center).abs() - half_size)
+            .max(Vec3::ZERO)
+            .length()
+            <= self.radius
+    }
+}
+
+impl SpatialShape for Cuboid {
+    fn bounding_aabb(&self, isometry: Isometry3d) -> Aabb3d {
+        self.aabb_3d(isometry)
+    }
+
+    fn contains_point(&self, isometry: Isometry3d, point: Vec3) -> bool {
+        let local_point = isometry.inverse_transform_point(point);
+
+        local_point.abs().cmple(self.half_size.into()).all()
+    }
+
+    fn intersects_sphere(&self, isometry: Isometry3d, center: Vec3, radius: f32) -> bool {
+        let local_center = isometry.inverse_transform_point(center);
+
+        (local_center.abs() - Vec3A::from(self.half_size))
+            .max(Vec3A::ZERO)
+            .length()
+            <= radius
+    }
+
+    fn intersects_cuboid(&self, isometry: Isometry3d, center: Vec3, half_size: Vec3) -> bool {
+        // separating axis test between the rotated cuboid and the axis-aligned box
+        let rotation = Mat3A::from_quat(isometry.rotation);
+        let cuboid_axes = [rotation.x_axis, rotation.y_axis, rotation.z_axis];
+        let cuboid_half_size = Vec3A::from(self.half_size);
+        let box_half_size = Vec3A::from(half_size);
+        let offset = Vec3A::from(center) - isometry.translation;
+
+        let separated_along = |axis: Vec3A| {
+            // parallel edges produce degenerate axes, which are covered by the face axes
+            if axis.length_squared() < 1e-12 {
+                return false;
+            }
+
+            let box_extent = box_half_size.dot(axis.abs());
+            let cuboid_extent = Vec3A::new(
+                cuboid_axes[0].dot(axis),
+                cuboid_axes[1].dot(axis),
+                cuboid_axes[2].dot(axis),
+            )
+            .abs()
+            .dot(cuboid_half_size);
+
+            offset.dot(axis).abs() > box_extent + cuboid_extent
+        };
+
+        let box_axes = [Vec3A::X, Vec3A::Y, Vec3A::Z];
+        let separated = box_axes
+            .iter()
+            .chain(&cuboid_axes)
+            .any(|axis| separated_along(*axis))
+            || box_axes.iter().any(|box_axis| {
+                cuboid_axes
+                    .iter()
+                    .any(|cuboid_axis| separated_along(box_axis.cross(*cuboid_axis)))
+            });
+
+        !separated
+    }
+}
+
+impl SpatialShape for Capsule3d {
+    fn bounding_aabb(&self, isometry: Isometry3d) -> Aabb3d {
+        self.aabb_3d(isometry)
+    }
+
+    fn intersects_sphere(&self, isometry: Isometry3d, center: Vec3, radius: f32) -> bool {
+        let local_center = isometry.inverse_transform_point(center);
+        let closest_on_segment =
+            Vec3A::Y * local_center.y.clamp(-self.half_length, self.half_length);
+
+        local_center.distance(closest_on_segment) <= self.radius + radius
+    }
+}
+
+impl SpatialShape for Cylinder {
+    fn bounding_aabb(&self, isometry: Isometry3d) -> Aabb3d {
+        self.aabb_3d(isometry)
+    }
+
+    fn intersects_sphere(&self, isometry: Isometry3d, center: Vec3, radius: f32) -> bool {
+        let local_center = isometry.inverse_transform_point(center);
+        let outside = Vec2::new(
+            Vec2::new(local_center.x, local_center.z).length() - self.radius,
+            local_center.y.abs() - self.half_height,
+        );
+
+        outside.max(Vec2::ZERO).length() <= radius
+    }
+}
+
+impl SpatialShape for Cone {
+    fn bounding_aabb(&self, isometry: Isometry3d) -> Aabb3d {
+        self.aabb_3d(isometry)
+    }
+
+    fn intersects_sphere(&self, isometry: Isometry3d, center: Vec3, radius: f32) -> bool {
+        // The cone is rotationally symmetric around the Y axis, so the problem can be reduced to
+        // the distance between a point and the triangular cross-section of the cone. The tip of
+        // the cone points towards +Y, and the cone is centered halfway between the tip and the
+        // center of the base.
+        let local_center = isometry.inverse_transform_point(center);
+        let point = Vec2::new(
+            Vec2::new(local_center.x, local_center.z).length(),
+            local_center.y,
+        );
+        let half_height = self.height * 0.5;
+
+        let tip = Vec2::new(0., half_height);
+        let base_center = Vec2::new(0., -half_height);
+        let base_edge = Vec2::new(self.radius, -half_height);
+
+        let inside = point.y.abs() <= half_height
+            && point.x <= self.radius * (half_height - point.y) / self.height;
+
+        inside
+            || distance_to_segment(point, tip, base_edge) <= radius
+            || distance_to_segment(point, base_center, base_edge) <= radius
+    }
+}
+
+impl SpatialShape for Circle {
+    fn bounding_aabb(&self, isometry: Isometry3d) -> Aabb3d {
+        extruded_aabb(Vec2::splat(self.radius), isometry)
+    }
+
+    fn intersects_sphere(&self, isometry: Isometry3d, center: Vec3, radius: f32) -> bool {
+        let local_center = isometry.inverse_transform_point(center);
+
+        local_center.truncate().length() <= self.radius + radius
+    }
+}
+
+impl SpatialShape for Rectangle {
+    fn bounding_aabb(&self, isometry: Isometry3d) -> Aabb3d {
+        extruded_aabb(self.half_size, isometry)
+    }
+
+    fn contains_point(&self, isometry: Isometry3d, point: Vec3) -> bool {
+        let local_point = isometry.inverse_transform_point(point);
+
+        local_point.truncate().abs().cmple(self.half_size).all()
+    }
+
+    fn intersects_sphere(&self, isometry: Isometry3d, center: Vec3, radius: f32) -> bool {
+        let local_center = isometry.inverse_transform_point(center);
+
+        (local_center.truncate().abs() - self.half_size)
+            .max(Vec2::ZERO)
+            .length()
+            <= radius
+    }
+}
+
+/// Returns the distance between a point and a line segment.
+fn distance_to_segment(point: Vec2, start: Vec2, end: Vec2) -> f32 {
+    let segment = end - start;
+    let t = ((point - start).dot(segment) / segment.length_squared()).clamp(0., 1.);
+
+    point.distance(start + segment * t)
+}
+
+/// Calculates the bounding box of a 2D shape with the given half-size, extruded infinitely along
+/// the Z axis of the isometry.
+fn extruded_aabb(half_size: Vec2, isometry: Isometry3d) -> Aabb3d {
+    // f32::MAX instead of infinity, because a rotation matrix may have zeroes in it and
+    // infinity * 0 would result in NaN. Any non-zero contribution still results in a box that is
+    // practically unbounded along that axis.
+    let local_half_size = Vec3A::new(half_size.x, half_size.y, f32::MAX);
+    let rotation = Mat3A::from_quat(isometry.rotation);
+    let half_size = Mat3A::from_cols(
+        rotation.x_axis.abs(),
+        rotation.y_axis.abs(),
+        rotation.z_axis.abs(),
+    ) * local_half_size;
+
+    Aabb3d {
+        min: isometry.translation - half_size,
+        max: isometry.translation + half_size,
+    }
+}
+
+/// Frustums are already in world space, so they are usually queried with `Isometry3d::IDENTITY`.
+///
+/// Works for both perspective and orthographic projections.
+#[cfg(feature = "bevy_render")]
+impl SpatialShape for bevy::render::primitives::Frustum {
+    fn bounding_aabb(&self, _isometry: Isometry3d) -> Aabb3d {
+        // The far plane may be missing, in which case the frustum is unbounded. Culling is done
+        // against the half-spaces in `intersects_aabb` instead.
+        Aabb3d {
+            min: Vec3A::NEG_INFINITY,
+            max: Vec3A::INFINITY,
+        }
+    }
+
+    fn intersects_sphere(&self, isometry: Isometry3d, center: Vec3, radius: f32) -> bool {
+        let local_center = Vec3::from(isometry.inverse_transform_point(center)).extend(1.0);
+
+        self.half_spaces
+            .iter()
+            .all(|half_space| half_space.normal_d().dot(local_center) + radius >= 0.0)
+    }
+
+    fn intersects_aabb(&self, isometry: Isometry3d, aabb: &Aabb3d) -> bool {
+        for half_space in &self.half_spaces {
+            let normal = isometry.rotation * half_space.normal();
+            let d = half_space.d() - normal.dot(isometry.translation);
+
+            // the corner of the box furthest along the normal of the half-space
+            let furthest_corner = Vec3A::select(normal.cmpge(Vec3A::ZERO), aabb.max, aabb.min);
+
+            if normal.dot(furthest_corner) + d < 0.0 {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SpatialShape;
+    use bevy::prelude::*;
+    use turborand::SeededCore;
+    use turborand::prelude::*;
+
+    /// Box which only overlaps the bounding sphere and bounding box of a unit shape at the origin,
+    /// but not a unit sphere
+    const CORNER_CENTER: Vec3 = Vec3::new(1.8, 1.8, 0.);
+    const CORNER_HALF_SIZE: Vec3 = Vec3::ONE;
+
+    /// Helper function to check that `intersects_cuboid` never misses a box which contains a point
+    /// of the shape
+    fn assert_no_missed_cuboids(shape: &impl SpatialShape) {
+        let rng = Rng::with_seed(4242);
+        let isometry = Isometry3d::new(Vec3::new(0.5, -0.5, 1.), Quat::from_rotation_y(0.7));
+
+        for _ in 0..1_000 {
+            let center = Vec3::new(rng.f32(), rng.f32(), rng.f32()) * 8. - 4.;
+            let half_size = Vec3::new(rng.f32(), rng.f32(), rng.f32()) * 2.;
+
+            let samples = 6;
+            let contains_sample = (0..=samples).any(|x| {
+                (0..=samples).any(|y| {
+                    (0..=samples).any(|z| {
+                        let t = Vec3::new(x as f32, y as f32, z as f32) / samples as f32;
+                        let point = center - half_size + t * half_size * 2.;
+                        shape.contains_point(isometry, point)
+                    })
+                })
+            });
+
+            if contains_sample {
+                assert!(shape.intersects_cuboid(isometry, center, half_size));
+            }
+        }
+    }
+
+    #[test]
+    fn test_intersects_cuboid_does_not_miss_boxes() {
+        assert_no_missed_cuboids(&Sphere::new(1.5));
+        assert_no_missed_cuboids(&Cuboid::new(3., 1., 2.));
+        assert_no_missed_cuboids(&Capsule3d::new(0.5, 2.));
+        assert_no_missed_cuboids(&Cylinder::new(1., 2.));
+        assert_no_missed_cuboids(&Cone::new(1.5, 2.));
+        assert_no_missed_cuboids(&Circle::new(1.5));
+        assert_no_missed_cuboids(&Rectangle::new(3., 1.));
+    }
+
+    #[test]
+    fn test_sphere_intersects_cuboid_exactly() {
+        let sphere = Sphere::new(1.);
+
+        assert!(!sphere.intersects_cuboid(Isometry3d::IDENTITY, CORNER_CENTER, CORNER_HALF_SIZE));
+        assert!(sphere.intersects_cuboid(
+            Isometry3d::IDENTITY,
+            CORNER_CENTER * 0.8,
+            CORNER_HALF_SIZE
+        ));
+    }
+
+    #[test]
+    fn test_cuboid_intersects_cuboid_exactly() {
+        // rotated by 45 degrees, an edge of the cuboid faces the corner of the box, which the
+        // bounding box of the cuboid would reach
+        let cuboid = Cuboid::new(2., 2., 2.);
+        let isometry =
+            Isometry3d::from_rotation(Quat::from_rotation_z(std::f32::consts::FRAC_PI_4));
+        let center = Vec3::new(1.8, 1.8, 0.);
+
+        assert!(!cuboid.intersects_cuboid(isometry, center, Vec3::splat(0.9)));
+        assert!(cuboid.intersects_cuboid(isometry, center, Vec3::splat(1.2)));
+        assert!(cuboid.intersects_cuboid(Isometry3d::IDENTITY, center, Vec3::splat(0.9)));
+    }
+
+    #[test]
+    fn test_default_intersects_cuboid_is_approximate() {
+        // the default implementation only tests the bounding sphere and bounding box of the box,
+        // which both reach the cylinder even though the box itself doesn't
+        let cylinder = Cylinder::new(1., 4.);
+        let isometry =
+            Isometry3d::from_rotation(Quat::from_rotation_x(std::f32::consts::FRAC_PI_2));
+
+        assert!(cylinder.intersects_cuboid(isometry, CORNER_CENTER, CORNER_HALF_SIZE));
+        assert!(!cylinder.intersects_cuboid(isometry, CORNER_CENTER * 2., CORNER_HALF_SIZE));
+    }
+}